@@ -0,0 +1,45 @@
+//! Heavy-overwrite workload: many small writes landing on top of each
+//! other, so every write splits and replaces existing spans.
+//!
+//! Run with `cargo bench --bench overwrite`.  Add `--no-default-features`
+//! to see how much slower the fallback checksum (without crc64fast) is:
+//! replay checks every record, so it shows there most.
+use std::time::Instant;
+use tempfile::tempdir;
+
+use syncless::{open, open_readonly, WriteOpenMode};
+
+const STORE_SIZE: u64 = 64 * 1024;
+const WRITES: usize = 200_000;
+
+// Deterministic, so runs are comparable.
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn main() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+    let data = [0xAAu8; 256];
+    let mut rng = 0x2545_F491_4F6C_DD1D;
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    let start = Instant::now();
+    for _ in 0..WRITES {
+        let off = xorshift(&mut rng) % STORE_SIZE;
+        let len = 1 + (xorshift(&mut rng) % data.len() as u64) as usize;
+        store.write(off, &data[..len]).unwrap();
+    }
+    let elapsed = start.elapsed();
+    println!("write:  {} writes in {:?} ({:?}/write)",
+             WRITES, elapsed, elapsed / WRITES as u32);
+    drop(store);
+
+    let start = Instant::now();
+    let store = open_readonly(&path).unwrap();
+    let elapsed = start.elapsed();
+    println!("replay: {} bytes in {:?}", store.size(), elapsed);
+}
@@ -0,0 +1,44 @@
+//! Big reads of a range with no holes: reading should cost about as
+//! much as copying the data out of the page cache, and no more.
+//!
+//! Run with `cargo bench --bench read`.  Opening checks every record, so
+//! add `--no-default-features` to see how much slower the fallback
+//! checksum (without crc64fast) is.
+use std::time::Instant;
+use tempfile::tempdir;
+
+use syncless::{open, open_readonly, WriteOpenMode};
+
+const STORE_SIZE: usize = 64 * 1024 * 1024;
+const WRITE_SIZE: usize = 1024 * 1024;
+const READS: u32 = 50;
+
+fn main() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+    let data: Vec<u8> = (0..STORE_SIZE).map(|i| i as u8).collect();
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    for (i, chunk) in data.chunks(WRITE_SIZE).enumerate() {
+        store.write((i * WRITE_SIZE) as u64, chunk).unwrap();
+    }
+    drop(store);
+
+    // Readonly, so everything is checked on open and not while reading.
+    let start = Instant::now();
+    let mut store = open_readonly(&path).unwrap();
+    let elapsed = start.elapsed();
+    println!("open: {}MiB in {:?} ({:.0}MiB/s)",
+             STORE_SIZE >> 20, elapsed, (STORE_SIZE >> 20) as f64 / elapsed.as_secs_f64());
+    let mut buf = vec![0u8; STORE_SIZE];
+    store.read(0, &mut buf).unwrap();
+    assert!(buf == data);
+
+    let start = Instant::now();
+    for _ in 0..READS {
+        store.read(0, &mut buf).unwrap();
+    }
+    let elapsed = start.elapsed();
+    println!("read: {} x {}MiB in {:?} ({:?}/read)",
+             READS, STORE_SIZE >> 20, elapsed, elapsed / READS);
+}
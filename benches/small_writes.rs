@@ -0,0 +1,29 @@
+//! Many tiny appends: the per-record overhead of the write path, with
+//! next to no data to move.
+//!
+//! Run with `cargo bench --bench small_writes`.
+use std::time::Instant;
+use tempfile::tempdir;
+
+use syncless::{open, WriteOpenMode};
+
+const WRITES: u64 = 1_000_000;
+const RECORD_LEN: usize = 16;
+
+fn main() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+    let data = [0x5Au8; RECORD_LEN];
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    // In a transaction, so we time writes and not compaction.
+    let mut txn = store.transaction();
+    let start = Instant::now();
+    for i in 0..WRITES {
+        txn.write(i * RECORD_LEN as u64, &data).unwrap();
+    }
+    let elapsed = start.elapsed();
+    txn.commit().unwrap();
+    println!("write: {} x {} bytes in {:?} ({:?}/write)",
+             WRITES, RECORD_LEN, elapsed, elapsed / WRITES as u32);
+}
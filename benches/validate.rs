@@ -0,0 +1,34 @@
+//! Validating many freshly-written records at once, as close() and
+//! into_readonly() do.
+//!
+//! Run with `cargo bench --bench validate`, and again with
+//! `--features rayon` to see the speedup from checking them in parallel.
+use std::time::Instant;
+use tempfile::tempdir;
+
+use syncless::{open, WriteOpenMode};
+
+// Small enough that the file stays under 1MB: compaction would validate
+// everything as we go.
+const RECORDS: u64 = 20_000;
+const RECORD_LEN: usize = 16;
+
+fn main() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+    let data = [0x55u8; RECORD_LEN];
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    // Backwards, as each write checks the record before it (in case they
+    // overlap).
+    for i in (0..RECORDS).rev() {
+        store.write(i * RECORD_LEN as u64, &data).unwrap();
+    }
+    assert!(!store.is_fully_validated());
+
+    let start = Instant::now();
+    store.validate_all().unwrap();
+    let elapsed = start.elapsed();
+    println!("validate: {} records in {:?} ({:?}/record)",
+             RECORDS, elapsed, elapsed / RECORDS as u32);
+}
@@ -0,0 +1,51 @@
+//! Arbitrary bytes after a valid header: opening must never panic, and
+//! must give the same view as the records replay used, on their own.
+//!
+//! Run with `cargo fuzz run replay` (needs nightly and cargo-fuzz).
+#![no_main]
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use syncless::{open, open_readonly, ReadOnly, SparseManifest, Store, WriteOpenMode, HEADER_SIZE};
+
+/// The file of an empty store: just a header.
+fn header() -> &'static [u8] {
+    static HEADER: OnceLock<Vec<u8>> = OnceLock::new();
+    HEADER.get_or_init(|| {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("empty");
+        open(&path, WriteOpenMode::MustNotExist).unwrap().close().unwrap();
+        std::fs::read(&path).unwrap()
+    })
+}
+
+/// The logical contents, holes and all (this is no bigger than the file).
+fn view(store: &mut Store<ReadOnly>) -> (SparseManifest, Vec<u8>) {
+    let mut data = Vec::new();
+    let manifest = store.export_sparse(&mut data).unwrap();
+    (manifest, data)
+}
+
+fuzz_target!(|input: &[u8]| {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("store");
+    let mut file = header().to_vec();
+    file.extend_from_slice(input);
+    std::fs::write(&path, &file).unwrap();
+
+    let mut store = open_readonly(&path).unwrap();
+    let used = store.file_len();
+    let offsets = store.record_offsets().unwrap();
+    assert!(used <= file.len() as u64);
+    assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+    assert!(offsets.iter().all(|&off| off >= HEADER_SIZE as u64 && off < used));
+    let whole = view(&mut store);
+
+    // Replay stops at the first bad record, so what it used is a prefix
+    // of good records, which must say the same thing.
+    std::fs::write(&path, &file[..used as usize]).unwrap();
+    let mut prefix = open_readonly(&path).unwrap();
+    assert_eq!(prefix.fsck().unwrap(), None);
+    assert_eq!(prefix.file_len(), used);
+    assert_eq!(view(&mut prefix), whole);
+});
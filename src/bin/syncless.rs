@@ -0,0 +1,88 @@
+//! Inspect syncless store files (with the `cli` feature).
+//!
+//! ```text
+//! syncless info FILE     header version, file and data sizes, span count
+//! syncless verify FILE   check every record, report the first bad one
+//! syncless dump FILE     write the contents to stdout
+//! syncless layout FILE   where each span of data lives in the file
+//! ```
+//!
+//! Files are opened readonly.  Exit status is 0 on success, 1 if verify
+//! found a problem, and 2 on usage or other errors.
+use std::io::Write;
+use std::process::ExitCode;
+
+use syncless::{open_readonly, Error, ReadOnly, Store};
+
+const USAGE: &str = "usage: syncless info|verify|dump|layout FILE";
+
+fn info(store: &mut Store<ReadOnly>) -> Result<ExitCode, Error> {
+    let mut spans = 0;
+    store.for_each_span(|_, _, _| { spans += 1; Ok(()) })?;
+    println!("version: {}", store.file_version());
+    println!("generation: {}", store.generation());
+    println!("file size: {}", store.file_len());
+    println!("data size: {}", store.size());
+    println!("spans: {}", spans);
+    Ok(ExitCode::SUCCESS)
+}
+
+fn verify(store: &mut Store<ReadOnly>) -> Result<ExitCode, Error> {
+    match store.fsck()? {
+        None => {
+            println!("ok");
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(offset) => {
+            println!("bad record at file offset {}", offset);
+            Ok(ExitCode::from(1))
+        }
+    }
+}
+
+fn dump(store: &mut Store<ReadOnly>) -> Result<ExitCode, Error> {
+    const CHUNK: u64 = 64 * 1024;
+    let mut buf = vec![0u8; CHUNK as usize];
+    let mut out = std::io::stdout().lock();
+    let mut pos = 0;
+
+    while pos < store.size() {
+        let n = (store.size() - pos).min(CHUNK) as usize;
+        store.read(pos, &mut buf[..n])?;
+        out.write_all(&buf[..n])?;
+        pos += n as u64;
+    }
+    out.flush()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn layout(store: &mut Store<ReadOnly>) -> Result<ExitCode, Error> {
+    print!("{}", store.describe_layout());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [cmd, path] = args.as_slice() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    };
+    let cmd = match cmd.as_str() {
+        "info" => info,
+        "verify" => verify,
+        "dump" => dump,
+        "layout" => layout,
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match open_readonly(path).and_then(|mut store| cmd(&mut store)) {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("syncless: {}: {:?}", path, err);
+            ExitCode::from(2)
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! The record checksum: CRC-64/XZ.
+//!
+//! With the (default) `crc64fast` feature we use that crate's SIMD
+//! implementation.  Without it, a simple table-driven one computes the
+//! same checksum (more slowly), so files are the same either way.
+
+#[cfg(feature = "crc64fast")]
+pub(crate) use crc64fast::Digest;
+#[cfg(not(feature = "crc64fast"))]
+pub(crate) use self::Crc64 as Digest;
+
+// Reflected form of the ECMA-182 polynomial.
+#[cfg(any(test, not(feature = "crc64fast")))]
+const POLY: u64 = 0xC96C_5795_D787_0F42;
+
+#[cfg(any(test, not(feature = "crc64fast")))]
+static TABLE: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-64/XZ, a byte at a time.
+#[cfg(any(test, not(feature = "crc64fast")))]
+pub(crate) struct Crc64 {
+    state: u64,
+}
+
+#[cfg(any(test, not(feature = "crc64fast")))]
+impl Crc64 {
+    pub(crate) fn new() -> Crc64 {
+        Crc64 { state: !0 }
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.state = TABLE[((self.state ^ b as u64) & 0xFF) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    pub(crate) fn sum64(&self) -> u64 {
+        !self.state
+    }
+}
+
+#[test]
+fn fallback_matches() {
+    // The standard check value.
+    let mut d = Crc64::new();
+    d.write(b"123456789");
+    assert_eq!(d.sum64(), 0x995D_C9BB_DF19_39FA);
+
+    // In pieces, and against whichever Digest we're using.
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i * 7 + i / 13) as u8).collect();
+    let mut a = Crc64::new();
+    let mut b = Digest::new();
+    for chunk in data.chunks(333) {
+        a.write(chunk);
+        b.write(chunk);
+    }
+    assert_eq!(a.sum64(), b.sum64());
+}
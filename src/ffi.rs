@@ -0,0 +1,252 @@
+//! A C interface to syncless (with the `ffi` feature).
+//!
+//! Build a library for C with (say)
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib`, and declare:
+//!
+//! ```c
+//! struct syncless_store;
+//!
+//! int syncless_open(const char *path, int mode, struct syncless_store **store);
+//! int syncless_open_readonly(const char *path, struct syncless_store **store);
+//! int syncless_read(struct syncless_store *store, uint64_t offset, uint8_t *buf, size_t len);
+//! int syncless_write(struct syncless_store *store, uint64_t offset, const uint8_t *buf, size_t len);
+//! uint64_t syncless_size(const struct syncless_store *store);
+//! int syncless_close(struct syncless_store *store);
+//! ```
+//!
+//! Every function returning `int` returns `SYNCLESS_OK` (0) on success,
+//! or one of the negative `SYNCLESS_ERR_*` codes below, which mostly
+//! correspond to [`Error`] variants.  There's no more detail than the
+//! code: in particular, `errno` may have been changed since the error.
+//!
+//! Memory: the open functions allocate the store and set `*store` to it
+//! (only on success).  The caller owns it, and must pass it to
+//! `syncless_close` exactly once, which frees it (even if it returns an
+//! error), after which it must not be used.  syncless never keeps
+//! pointers to `path` or buffers after a call returns, so those remain
+//! the caller's.  A store must not be used by two threads at once.
+use std::ffi::{c_char, c_int, CStr};
+use std::path::PathBuf;
+use crate::{open, open_readonly, Error, ReadOnly, Store, Writable, WriteOpenMode};
+
+/// Success.
+pub const SYNCLESS_OK: c_int = 0;
+/// [`Error::Io`].  The code is all the caller gets: `errno` isn't kept.
+pub const SYNCLESS_ERR_IO: c_int = -1;
+/// [`Error::NotSyncless`].
+pub const SYNCLESS_ERR_NOT_SYNCLESS: c_int = -2;
+/// [`Error::UnsupportedVersion`].
+pub const SYNCLESS_ERR_UNSUPPORTED_VERSION: c_int = -3;
+/// [`Error::CorruptRecord`].
+pub const SYNCLESS_ERR_CORRUPT_RECORD: c_int = -4;
+/// [`Error::Hole`].
+pub const SYNCLESS_ERR_HOLE: c_int = -5;
+/// [`Error::SavepointLost`].
+pub const SYNCLESS_ERR_SAVEPOINT_LOST: c_int = -6;
+/// [`Error::OutOfRange`].
+pub const SYNCLESS_ERR_OUT_OF_RANGE: c_int = -7;
+/// A NULL pointer, bad mode, or (off Unix) a path which isn't UTF-8.
+pub const SYNCLESS_ERR_INVALID_ARGUMENT: c_int = -8;
+/// `syncless_write` on a store opened with `syncless_open_readonly`.
+pub const SYNCLESS_ERR_READONLY: c_int = -9;
+/// [`Error::Sealed`].
+pub const SYNCLESS_ERR_SEALED: c_int = -10;
+/// [`Error::Cancelled`].
+pub const SYNCLESS_ERR_CANCELLED: c_int = -11;
+
+/// `mode` for `syncless_open`: [`WriteOpenMode::MustExist`].
+pub const SYNCLESS_MUST_EXIST: c_int = 0;
+/// `mode` for `syncless_open`: [`WriteOpenMode::MustNotExist`].
+pub const SYNCLESS_MUST_NOT_EXIST: c_int = 1;
+/// `mode` for `syncless_open`: [`WriteOpenMode::MayExist`].
+pub const SYNCLESS_MAY_EXIST: c_int = 2;
+
+/// An open store, as an opaque handle for C.
+pub enum SynclessStore {
+    /// From `syncless_open`.
+    Writable(Store<Writable>),
+    /// From `syncless_open_readonly`.
+    ReadOnly(Store<ReadOnly>),
+}
+
+fn error_code(err: Error) -> c_int {
+    match err {
+        Error::Io(_) => SYNCLESS_ERR_IO,
+        Error::NotSyncless => SYNCLESS_ERR_NOT_SYNCLESS,
+        Error::UnsupportedVersion => SYNCLESS_ERR_UNSUPPORTED_VERSION,
+        Error::CorruptRecord { .. } => SYNCLESS_ERR_CORRUPT_RECORD,
+        Error::Hole { .. } => SYNCLESS_ERR_HOLE,
+        Error::SavepointLost => SYNCLESS_ERR_SAVEPOINT_LOST,
+        Error::OutOfRange => SYNCLESS_ERR_OUT_OF_RANGE,
+        Error::Sealed => SYNCLESS_ERR_SEALED,
+        Error::Cancelled => SYNCLESS_ERR_CANCELLED,
+    }
+}
+
+fn result_code(res: Result<(), Error>) -> c_int {
+    match res {
+        Ok(()) => SYNCLESS_OK,
+        Err(err) => error_code(err),
+    }
+}
+
+// SAFETY: caller promises path is NULL or a valid C string.
+unsafe fn to_path(path: *const c_char) -> Option<PathBuf> {
+    if path.is_null() {
+        return None;
+    }
+    let bytes = unsafe { CStr::from_ptr(path) }.to_bytes();
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Some(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+    }
+    #[cfg(not(unix))]
+    {
+        std::str::from_utf8(bytes).ok().map(PathBuf::from)
+    }
+}
+
+// SAFETY: caller promises out is NULL or valid to write.
+unsafe fn give(store: SynclessStore, out: *mut *mut SynclessStore) -> c_int {
+    unsafe { *out = Box::into_raw(Box::new(store)) };
+    SYNCLESS_OK
+}
+
+/// Opens a store for reading and writing, as [`open`]: `mode` is one of
+/// the `SYNCLESS_MUST_EXIST`, `SYNCLESS_MUST_NOT_EXIST` or
+/// `SYNCLESS_MAY_EXIST` constants.  On success, sets `*store`.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string, and `store` valid to write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn syncless_open(path: *const c_char,
+                                       mode: c_int,
+                                       store: *mut *mut SynclessStore) -> c_int {
+    let mode = match mode {
+        SYNCLESS_MUST_EXIST => WriteOpenMode::MustExist,
+        SYNCLESS_MUST_NOT_EXIST => WriteOpenMode::MustNotExist,
+        SYNCLESS_MAY_EXIST => WriteOpenMode::MayExist,
+        _ => return SYNCLESS_ERR_INVALID_ARGUMENT,
+    };
+    let Some(path) = (unsafe { to_path(path) }) else {
+        return SYNCLESS_ERR_INVALID_ARGUMENT;
+    };
+    if store.is_null() {
+        return SYNCLESS_ERR_INVALID_ARGUMENT;
+    }
+    match open(path, mode) {
+        Ok(s) => unsafe { give(SynclessStore::Writable(s), store) },
+        Err(err) => error_code(err),
+    }
+}
+
+/// Opens an existing store readonly, as [`open_readonly`].  On success,
+/// sets `*store`.
+///
+/// # Safety
+///
+/// `path` must be a NUL-terminated string, and `store` valid to write.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn syncless_open_readonly(path: *const c_char,
+                                                store: *mut *mut SynclessStore) -> c_int {
+    let Some(path) = (unsafe { to_path(path) }) else {
+        return SYNCLESS_ERR_INVALID_ARGUMENT;
+    };
+    if store.is_null() {
+        return SYNCLESS_ERR_INVALID_ARGUMENT;
+    }
+    match open_readonly(path) {
+        Ok(s) => unsafe { give(SynclessStore::ReadOnly(s), store) },
+        Err(err) => error_code(err),
+    }
+}
+
+/// Reads `len` bytes at `offset` into `buf`, as [`Store::read`] (so holes
+/// and anything past the end read as the hole fill byte, usually zero).
+///
+/// # Safety
+///
+/// `store` must be from a syncless open function and not yet closed, and
+/// `buf` valid to write `len` bytes (it may be NULL if `len` is 0).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn syncless_read(store: *mut SynclessStore,
+                                       offset: u64,
+                                       buf: *mut u8,
+                                       len: usize) -> c_int {
+    let Some(store) = (unsafe { store.as_mut() }) else {
+        return SYNCLESS_ERR_INVALID_ARGUMENT;
+    };
+    let buf: &mut [u8] = if len == 0 {
+        &mut []
+    } else if buf.is_null() {
+        return SYNCLESS_ERR_INVALID_ARGUMENT;
+    } else {
+        unsafe { std::slice::from_raw_parts_mut(buf, len) }
+    };
+    result_code(match store {
+        SynclessStore::Writable(s) => s.read(offset, buf),
+        SynclessStore::ReadOnly(s) => s.read(offset, buf),
+    })
+}
+
+/// Writes `len` bytes from `buf` at `offset`, as [`Store::write`].
+///
+/// # Safety
+///
+/// `store` must be from a syncless open function and not yet closed, and
+/// `buf` valid to read `len` bytes (it may be NULL if `len` is 0).
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn syncless_write(store: *mut SynclessStore,
+                                        offset: u64,
+                                        buf: *const u8,
+                                        len: usize) -> c_int {
+    let Some(store) = (unsafe { store.as_mut() }) else {
+        return SYNCLESS_ERR_INVALID_ARGUMENT;
+    };
+    let buf: &[u8] = if len == 0 {
+        &[]
+    } else if buf.is_null() {
+        return SYNCLESS_ERR_INVALID_ARGUMENT;
+    } else {
+        unsafe { std::slice::from_raw_parts(buf, len) }
+    };
+    match store {
+        SynclessStore::Writable(s) => result_code(s.write(offset, buf)),
+        SynclessStore::ReadOnly(_) => SYNCLESS_ERR_READONLY,
+    }
+}
+
+/// Returns the logical size of the store, as [`Store::size`] (0 if
+/// `store` is NULL).
+///
+/// # Safety
+///
+/// `store` must be from a syncless open function and not yet closed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn syncless_size(store: *const SynclessStore) -> u64 {
+    match unsafe { store.as_ref() } {
+        Some(SynclessStore::Writable(s)) => s.size(),
+        Some(SynclessStore::ReadOnly(s)) => s.size(),
+        None => 0,
+    }
+}
+
+/// Closes and frees the store, returning any error from closing it (as
+/// [`Store::close`]).  Closing NULL does nothing.
+///
+/// # Safety
+///
+/// `store` must be NULL, or from a syncless open function and not yet
+/// closed.  It must not be used again.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn syncless_close(store: *mut SynclessStore) -> c_int {
+    if store.is_null() {
+        return SYNCLESS_OK;
+    }
+    match *unsafe { Box::from_raw(store) } {
+        SynclessStore::Writable(s) => result_code(s.close()),
+        SynclessStore::ReadOnly(_) => SYNCLESS_OK,
+    }
+}
@@ -1,49 +1,204 @@
 //! Parsing and validating magic + version.  This header is created once at offset 0.
 //! Magic food (8 bytes):                "Syncless"
 //! Version (4 bytes, Little Endian):    Majorver (8 bits) Formatver (8 bits) Minorver (16 bits);
+//! Generation (8 bytes, Little Endian): Bumped on every writable open.
+//! Hole fill (1 byte):                  What holes read as.
+//! Id (16 bytes):                       Random, set at creation.
+//! Created (8 bytes, Little Endian):    Seconds since 1970 at creation.
+//! Extensions (128 bytes):              Tagged fields, see below.
+//! Flags (1 byte):                      Bit 0: sealed, no more writes.
+//! Reserved (82 bytes):                 Zero.
+//!
+//! Majorver 0 (syncless 0.1) files have only the magic and version.  From
+//! Majorver 1 the header is always 256 bytes, so a later minor version
+//! can put new fields in the reserved bytes (and bump the minor version)
+//! without moving the records.
+//!
+//! Extensions are a series of [tag: 1 byte][length: 1 byte][value: length],
+//! ending at a zero tag (or the end), so new kinds of metadata need no new
+//! version.  We keep fields whose tags we don't know, but don't use them.
+//! Tags 0x80 and above are for applications.
 //!
 //! Majorver: if not compatible, fail open.
-//! Formatver: if not compatible, only allow read-only open.
-//! Minorver:  ignore, informational only.
-use std::fs::File;
-use std::io::{Read, Write};
+//! Formatver: if not compatible, only allow read-only open.  Formatver 1
+//!            means records' checksums only cover a sample of their data
+//!            (see record.rs).
+//! Minorver:  fields added in the reserved bytes: older versions of
+//!            syncless ignore them.
+use std::io::SeekFrom;
 use crate::Error;
+use crate::record::RecordFormat;
+use crate::storage::Storage;
 
 const MAGIC: &[u8; 8] = b"Syncless";
+const VERSION_SIZE: usize = MAGIC.len() + 4;
+const GENERATION_OFFSET: u64 = VERSION_SIZE as u64;
+/// The whole header (in the current version).
+pub(crate) const HEADER_SIZE: usize = 256;
+const HOLE_FILL_OFFSET: usize = GENERATION_OFFSET as usize + 8;
+const ID_OFFSET: usize = HOLE_FILL_OFFSET + 1;
+const CREATED_OFFSET: usize = ID_OFFSET + 16;
+const EXT_OFFSET: usize = CREATED_OFFSET + 8;
+/// Where the flags are.
+const FLAGS_OFFSET: usize = EXT_OFFSET + EXT_SIZE;
+/// The part of it with fields in.
+const FIELDS_SIZE: usize = FLAGS_OFFSET + 1;
+/// Flags bit: the store must not be opened for writing.
+const FLAG_SEALED: u8 = 1;
+const _: () = assert!(FIELDS_SIZE <= HEADER_SIZE);
+/// Space for extensions.
+pub(crate) const EXT_SIZE: usize = 128;
+/// Smallest tag applications can use.
+pub(crate) const FIRST_APP_TAG: u8 = 0x80;
 
+#[derive(Clone, Copy, Debug)]
 pub(crate) struct HeaderVer {
     major: u8,
     format: u8,
-    _minor: u16,
+    minor: u16,
 }
 
 impl HeaderVer {
-    const CURRENT_MAJOR: u8 = 0;
+    const CURRENT_MAJOR: u8 = 1;
     const CURRENT_FORMAT: u8 = 0;
+    const SAMPLED_FORMAT: u8 = 1;
     const CURRENT_MINOR: u16 = 0;
 
+    /// What we write.
+    pub(crate) const CURRENT: HeaderVer = HeaderVer {
+        major: Self::CURRENT_MAJOR,
+        format: Self::CURRENT_FORMAT,
+        minor: Self::CURRENT_MINOR,
+    };
+
+    // CURRENT_* may be zero today, but won't stay that way.
+    #[allow(clippy::absurd_extreme_comparisons)]
     pub(crate) fn is_read_compatible(&self) -> bool {
         self.major <= Self::CURRENT_MAJOR
     }
+    #[allow(clippy::absurd_extreme_comparisons)]
     pub(crate) fn is_write_compatible(&self) -> bool {
-        self.is_read_compatible() && self.format <= Self::CURRENT_FORMAT
+        self.is_read_compatible() && self.format <= Self::SAMPLED_FORMAT
     }
-}
 
-pub(crate) fn read_header(file: &mut File, file_offset: &mut u64) -> Result<HeaderVer, Error> {
-    let mut magic_and_header = [0u8; 8 + 4];
+    /// What we write, for a file with records in this format.
+    pub(crate) fn for_format(format: RecordFormat) -> HeaderVer {
+        HeaderVer {
+            format: if format == RecordFormat::Sampled { Self::SAMPLED_FORMAT } else { Self::CURRENT_FORMAT },
+            ..Self::CURRENT
+        }
+    }
 
-    match file.read_exact(&mut magic_and_header) {
-        Ok(()) => {
-            *file_offset += magic_and_header.len() as u64;
+    pub(crate) fn record_format(&self) -> RecordFormat {
+        if self.major == 0 {
+            RecordFormat::Legacy
+        } else if self.format == Self::SAMPLED_FORMAT {
+            RecordFormat::Sampled
+        } else {
+            RecordFormat::Marked
         }
-        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => {
-            return Err(Error::NotSyncless);
+    }
+}
+
+impl std::fmt::Display for HeaderVer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.format, self.minor)
+    }
+}
+
+/// Header fields after the generation (Majorver 0 files get these defaults).
+#[derive(Clone, Debug, Default)]
+pub(crate) struct HeaderFields {
+    /// What holes read as.
+    pub hole_fill: u8,
+    /// Identifies the store.
+    pub id: [u8; 16],
+    /// When the store was created (seconds since the epoch), or 0 if we
+    /// don't know.
+    pub created_at: u64,
+    /// Extensions, as (tag, value).
+    pub ext: Vec<(u8, Vec<u8>)>,
+    /// No more writes (see Store::seal).
+    pub sealed: bool,
+}
+
+impl HeaderFields {
+    /// Fields for a store we're creating.
+    pub(crate) fn new_store(hole_fill: u8, ext: Vec<(u8, Vec<u8>)>) -> HeaderFields {
+        let created_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        HeaderFields { hole_fill, id: new_id(), created_at, ext, sealed: false }
+    }
+
+    /// The value of extension `tag`, if any.
+    pub(crate) fn ext_value(&self, tag: u8) -> Option<&[u8]> {
+        self.ext.iter().find(|(t, _)| *t == tag).map(|(_, v)| v.as_slice())
+    }
+}
+
+/// Do these extensions fit in the header?
+pub(crate) fn ext_fits(ext: &[(u8, Vec<u8>)]) -> bool {
+    ext.iter().map(|(_, v)| 2 + v.len()).sum::<usize>() <= EXT_SIZE
+}
+
+fn parse_ext(mut bytes: &[u8]) -> Vec<(u8, Vec<u8>)> {
+    let mut ext = Vec::new();
+    while let [tag, len, rest @ ..] = bytes {
+        let len = *len as usize;
+        // A zero tag ends it, and if it's nonsense we keep what we have.
+        if *tag == 0 || len > rest.len() {
+            break;
         }
-        Err(error) => {
-            return Err(Error::Io(error));
+        ext.push((*tag, rest[..len].to_vec()));
+        bytes = &rest[len..];
+    }
+    ext
+}
+
+/// 16 random bytes.  std's RandomState is seeded from the OS, and we mix
+/// in the time so two ids from the same seed still differ.
+fn new_id() -> [u8; 16] {
+    use std::hash::{BuildHasher, Hasher, RandomState};
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let mut id = [0u8; 16];
+    for (i, half) in id.chunks_mut(8).enumerate() {
+        let mut h = RandomState::new().build_hasher();
+        h.write_u128(now);
+        h.write_usize(i);
+        half.copy_from_slice(&h.finish().to_le_bytes());
+    }
+    id
+}
+
+pub(crate) struct Header {
+    pub ver: HeaderVer,
+    /// None for Majorver 0 files, which don't have one.
+    pub generation: Option<u64>,
+    pub fields: HeaderFields,
+}
+
+// Read a header field, which must be there.
+fn read_field<S: Storage>(file: &mut S, buf: &mut [u8], file_offset: &mut u64) -> Result<(), Error> {
+    match file.read_exact(buf) {
+        Ok(()) => {
+            *file_offset += buf.len() as u64;
+            Ok(())
         }
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => Err(Error::NotSyncless),
+        Err(error) => Err(Error::Io(error)),
     }
+}
+
+pub(crate) fn read_header<S: Storage>(file: &mut S, file_offset: &mut u64) -> Result<Header, Error> {
+    let mut magic_and_header = [0u8; VERSION_SIZE];
+
+    read_field(file, &mut magic_and_header, file_offset)?;
     if &magic_and_header[..8] != MAGIC {
         return Err(Error::NotSyncless);
     }
@@ -51,22 +206,68 @@ pub(crate) fn read_header(file: &mut File, file_offset: &mut u64) -> Result<Head
     let hver = HeaderVer {
         major: magic_and_header[8],
         format: magic_and_header[9],
-        _minor: u16::from_le_bytes([
+        minor: u16::from_le_bytes([
             magic_and_header[10],
             magic_and_header[11],
         ]),
     };
-    Ok(hver)
+
+    // We don't know what a future major version looks like, so stop here.
+    if hver.major == 0 || !hver.is_read_compatible() {
+        return Ok(Header { ver: hver, generation: None, fields: HeaderFields::default() });
+    }
+
+    // The rest of it: anything a later minor version put in the reserved
+    // bytes isn't for us.
+    let mut rest = [0u8; HEADER_SIZE - VERSION_SIZE];
+    read_field(file, &mut rest, file_offset)?;
+    let field = |offset: usize, len: usize| &rest[offset - VERSION_SIZE..offset - VERSION_SIZE + len];
+
+    let fields = HeaderFields {
+        hole_fill: field(HOLE_FILL_OFFSET, 1)[0],
+        id: field(ID_OFFSET, 16).try_into().unwrap(),
+        created_at: u64::from_le_bytes(field(CREATED_OFFSET, 8).try_into().unwrap()),
+        ext: parse_ext(field(EXT_OFFSET, EXT_SIZE)),
+        sealed: field(FLAGS_OFFSET, 1)[0] & FLAG_SEALED != 0,
+    };
+    let generation = u64::from_le_bytes(field(GENERATION_OFFSET as usize, 8).try_into().unwrap());
+    Ok(Header { ver: hver, generation: Some(generation), fields })
 }
 
-pub(crate) fn write_header(file: &mut File) -> Result<u64, Error> {
-    let mut magic_and_header = [0u8; 8 + 4];
+pub(crate) fn write_header<S: Storage>(file: &mut S,
+                                       generation: u64,
+                                       format: RecordFormat,
+                                       fields: &HeaderFields) -> Result<u64, Error> {
+    let mut magic_and_header = [0u8; HEADER_SIZE];
+    let ver = HeaderVer::for_format(format);
 
     magic_and_header[..8].copy_from_slice(MAGIC);
-    magic_and_header[8] = HeaderVer::CURRENT_MAJOR;
-    magic_and_header[9] = HeaderVer::CURRENT_FORMAT;
-    magic_and_header[10..12].copy_from_slice(&HeaderVer::CURRENT_MINOR.to_le_bytes());
+    magic_and_header[8] = ver.major;
+    magic_and_header[9] = ver.format;
+    magic_and_header[10..12].copy_from_slice(&ver.minor.to_le_bytes());
+    magic_and_header[GENERATION_OFFSET as usize..HOLE_FILL_OFFSET].copy_from_slice(&generation.to_le_bytes());
+    magic_and_header[HOLE_FILL_OFFSET] = fields.hole_fill;
+    magic_and_header[ID_OFFSET..CREATED_OFFSET].copy_from_slice(&fields.id);
+    magic_and_header[CREATED_OFFSET..EXT_OFFSET].copy_from_slice(&fields.created_at.to_le_bytes());
+    debug_assert!(ext_fits(&fields.ext));
+    let mut pos = EXT_OFFSET;
+    for (tag, value) in &fields.ext {
+        magic_and_header[pos] = *tag;
+        magic_and_header[pos + 1] = value.len() as u8;
+        magic_and_header[pos + 2..pos + 2 + value.len()].copy_from_slice(value);
+        pos += 2 + value.len();
+    }
+    if fields.sealed {
+        magic_and_header[FLAGS_OFFSET] = FLAG_SEALED;
+    }
 
     file.write_all(&magic_and_header)?;
     Ok(magic_and_header.len() as u64)
 }
+
+/// Overwrite the generation in place (the header must have one!).
+pub(crate) fn write_generation<S: Storage>(file: &mut S, generation: u64) -> Result<(), Error> {
+    file.seek(SeekFrom::Start(GENERATION_OFFSET))?;
+    file.write_all(&generation.to_le_bytes())?;
+    Ok(())
+}
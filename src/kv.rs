@@ -0,0 +1,190 @@
+//! A simple key-value store on top of a syncless [`Store`].
+//!
+//! Each put or delete appends an entry at the end of the logical store:
+//! [key_len + 1: le32] (0 ends the log)
+//! [val_len: le32] (u32::MAX for a delete, u32::MAX - 1 for padding)
+//! [key...: key_len]
+//! [value...: val_len]
+//!
+//! On open we scan the entries to build an in-memory index.  Each entry is
+//! a single write, so (as long as it's smaller than a record) it is atomic
+//! and ordered like any other write.  Old values are never reclaimed:
+//! this is for small, slowly-changing data like bookmarks.
+//!
+//! A larger entry can be torn, leaving part of it at the end of the store.
+//! Before appending after one, we write a padding entry whose "key" covers
+//! the leftover bytes, so they're skipped rather than parsed next time.
+use std::collections::HashMap;
+use std::path::Path;
+use crate::{open, Error, Store, Writable, WriteOpenMode};
+
+const ENTRY_HDR_SIZE: u64 = 4 + 4;
+const TOMBSTONE: u32 = u32::MAX;
+const PADDING: u32 = u32::MAX - 1;
+
+/// A string-keyed store of byte values, persisted in a syncless store.
+pub struct KvStore {
+    store: Store<Writable>,
+    /// Key -> (logical offset, length) of its current value.
+    index: HashMap<String, (u64, u64)>,
+    /// End of the last complete entry: where the next one goes.
+    end: u64,
+}
+
+impl KvStore {
+    /// Opens (or creates) a syncless store at `path` and loads its index.
+    ///
+    /// # Errors
+    ///
+    /// As for [`open`], and an [`Error::Io`] of kind `InvalidData` if
+    /// the store doesn't contain key-value entries.
+    pub fn open<P: AsRef<Path>>(path: P, mode: WriteOpenMode) -> Result<KvStore, Error> {
+        KvStore::from_store(open(path, mode)?)
+    }
+
+    /// Loads the index from an already-open store.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`Error::Io`] of kind `InvalidData` if the store doesn't
+    /// contain key-value entries, and an error on underlying I/O error.
+    pub fn from_store(mut store: Store<Writable>) -> Result<KvStore, Error> {
+        let mut index = HashMap::new();
+        let mut end = 0;
+        let size = store.size();
+
+        while end + ENTRY_HDR_SIZE <= size {
+            let mut hdr = [0u8; ENTRY_HDR_SIZE as usize];
+            store.read(end, &mut hdr)?;
+            let key_len = u32::from_le_bytes(hdr[..4].try_into().unwrap()) as u64;
+            let val_len = u32::from_le_bytes(hdr[4..].try_into().unwrap());
+            // Zeroes (e.g. a hole) aren't an entry.
+            if key_len == 0 {
+                break;
+            }
+            let key_len = key_len - 1;
+            let val_bytes = if val_len == TOMBSTONE || val_len == PADDING { 0 } else { val_len as u64 };
+
+            // A write larger than one record may have been torn: ignore it,
+            // and pad over it before the next entry.
+            let entry_len = ENTRY_HDR_SIZE + key_len + val_bytes;
+            if end + entry_len > size {
+                break;
+            }
+            if val_len == PADDING {
+                end += entry_len;
+                continue;
+            }
+
+            let mut key = vec![0u8; key_len as usize];
+            store.read(end + ENTRY_HDR_SIZE, &mut key)?;
+            let key = String::from_utf8(key)
+                .map_err(|e| Error::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?;
+            if val_len == TOMBSTONE {
+                index.remove(&key);
+            } else {
+                index.insert(key, (end + ENTRY_HDR_SIZE + key_len, val_bytes));
+            }
+            end += entry_len;
+        }
+
+        Ok(KvStore { store, index, end })
+    }
+
+    /// Covers whatever follows the last complete entry (a torn entry, or
+    /// zeroes) with padding entries, so it can't be mistaken for entries.
+    fn pad_tail(&mut self) -> Result<(), Error> {
+        let size = self.store.size();
+        while self.end < size {
+            // The padding header itself may go past the old end.
+            let skip = (size - self.end).saturating_sub(ENTRY_HDR_SIZE).min(PADDING as u64 - 1);
+            let mut hdr = [0u8; ENTRY_HDR_SIZE as usize];
+            hdr[..4].copy_from_slice(&(skip as u32 + 1).to_le_bytes());
+            hdr[4..].copy_from_slice(&PADDING.to_le_bytes());
+            self.store.write(self.end, &hdr)?;
+            self.end += ENTRY_HDR_SIZE + skip;
+        }
+        Ok(())
+    }
+
+    fn append_entry(&mut self, key: &str, val_len: u32, value: &[u8]) -> Result<u64, Error> {
+        self.pad_tail()?;
+        let mut entry = Vec::with_capacity(ENTRY_HDR_SIZE as usize + key.len() + value.len());
+        entry.extend_from_slice(&(key.len() as u32 + 1).to_le_bytes());
+        entry.extend_from_slice(&val_len.to_le_bytes());
+        entry.extend_from_slice(key.as_bytes());
+        entry.extend_from_slice(value);
+
+        let off = self.end;
+        self.store.write(off, &entry)?;
+        self.end += entry.len() as u64;
+        Ok(off)
+    }
+
+    /// Sets `key` to `value`.
+    ///
+    /// Like any syncless write, this is atomic and ordered, but not durable.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::OutOfRange`] if `key` or `value` is too long for
+    /// an entry's 32-bit lengths (about 4GiB), and an error on underlying
+    /// I/O problems (probably out of disk space).
+    pub fn put(&mut self, key: &str, value: &[u8]) -> Result<(), Error> {
+        if key.len() >= u32::MAX as usize || value.len() >= PADDING as usize {
+            return Err(Error::OutOfRange);
+        }
+        let off = self.append_entry(key, value.len() as u32, value)?;
+        self.index.insert(key.to_owned(),
+                          (off + ENTRY_HDR_SIZE + key.len() as u64, value.len() as u64));
+        Ok(())
+    }
+
+    /// Gets the current value of `key`, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error on underlying I/O error.
+    pub fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let Some(&(off, len)) = self.index.get(key) else {
+            return Ok(None);
+        };
+        let mut value = vec![0u8; len as usize];
+        self.store.read(off, &mut value)?;
+        Ok(Some(value))
+    }
+
+    /// Removes `key`, returning true if it was present.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error on underlying I/O problems (probably out of disk space).
+    pub fn delete(&mut self, key: &str) -> Result<bool, Error> {
+        if !self.index.contains_key(key) {
+            return Ok(false);
+        }
+        self.append_entry(key, TOMBSTONE, &[])?;
+        self.index.remove(key);
+        Ok(true)
+    }
+
+    /// Iterates over the keys currently present (in no particular order).
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.index.keys().map(String::as_str)
+    }
+
+    /// Returns the underlying store.
+    pub fn into_store(self) -> Store<Writable> {
+        self.store
+    }
+
+    /// Closes the underlying store, returning any error (see
+    /// [`Store::close`]).
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::close`].
+    pub fn close(self) -> Result<(), Error> {
+        self.store.close()
+    }
+}
@@ -1,18 +1,104 @@
 //! Each write appends an ondisk record has a header, and a tailer.
+//! [sync: 4 bytes] (not in Majorver 0 files)
 //! [logical_offset: le64]
-//! [length: le24]
+//! [length: le24, or le64 if sync is LONG_MAGIC]
 //! [data...: length]
-//! [hash: le64] (covers offset, length, and data)
-use std::io::{Seek, SeekFrom, Read, Write};
-use std::fs::File;
-use crc64fast;
+//! [hash: le64] (covers sync, offset, length, and data)
+//!
+//! An empty record is a checkpoint marker, with the checkpoint id in place
+//! of the logical offset.
+//!
+//! Except in files with sampled checksums (Formatver 1), where the hash
+//! only covers the first and last SAMPLE_SIZE bytes of the data (all of it,
+//! if it's no longer than two of those).
+//!
+//! The sync marker lets us find the next record if one is damaged (see
+//! find_sync), instead of giving up on everything after it.  It also says
+//! how big the length field is, so a file can mix both kinds of record.
+use std::io::{self, IoSlice, SeekFrom};
 use std::ops::Bound::*;
 use std::collections::BTreeMap;
 use crate::Error;
+use crate::checksum;
 use crate::store::Span;
+use crate::storage::Storage;
 
 pub(crate) const MAX_RECORD_SIZE: usize = 1 << 24;
-const RECORD_HDR_SIZE: usize = 8 + 3;
+const OFFSET_SIZE: usize = 8;
+const SHORT_LEN_SIZE: usize = 3;
+const LONG_LEN_SIZE: usize = 8;
+const CSUM_SIZE: usize = 8;
+/// How much of each end of the data a sampled checksum covers.
+const SAMPLE_SIZE: usize = 4096;
+/// Record lengths above this are checked against the file size before we
+/// allocate anything for them.
+const MAX_UNCHECKED_LEN: u64 = 64 * 1024;
+// Length must fit in 24 bits.
+const _: () = assert!(MAX_RECORD_SIZE - 1 <= 0x00FF_FFFF);
+
+// Arbitrary, but not ASCII and not zeros.
+const SYNC_MAGIC: [u8; 4] = [0xF5, 0x9C, 0x5A, 0x17];
+// Same, for records with a 64-bit length.
+const LONG_MAGIC: [u8; 4] = [0xF5, 0x9C, 0x5A, 0x18];
+const MAX_HDR_SIZE: usize = LONG_MAGIC.len() + OFFSET_SIZE + LONG_LEN_SIZE;
+/// Header and checksum around a record's data (in the current format).
+pub(crate) const RECORD_OVERHEAD: usize = SYNC_MAGIC.len() + OFFSET_SIZE + SHORT_LEN_SIZE + CSUM_SIZE;
+pub(crate) const LONG_RECORD_OVERHEAD: usize = MAX_HDR_SIZE + CSUM_SIZE;
+
+/// How records are laid out in this file (depends on header version).
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum RecordFormat {
+    /// No sync marker (Majorver 0).
+    Legacy,
+    /// Each record starts with SYNC_MAGIC (or LONG_MAGIC).
+    Marked,
+    /// Marked, but checksums only cover a sample of the data.
+    Sampled,
+}
+
+impl RecordFormat {
+    /// What we write into new files.
+    pub const CURRENT: RecordFormat = RecordFormat::Marked;
+
+    /// Largest data we can put in a single record.
+    pub fn max_record_len(self) -> usize {
+        match self {
+            RecordFormat::Legacy => MAX_RECORD_SIZE - 1,
+            RecordFormat::Marked | RecordFormat::Sampled => usize::MAX,
+        }
+    }
+
+    /// Does a record of this length need a 64-bit length field?
+    fn is_long(self, len: usize) -> bool {
+        self != RecordFormat::Legacy && len >= MAX_RECORD_SIZE
+    }
+
+    fn marker(self, len: usize) -> &'static [u8] {
+        match self {
+            RecordFormat::Legacy => &[],
+            _ if self.is_long(len) => &LONG_MAGIC,
+            RecordFormat::Marked | RecordFormat::Sampled => &SYNC_MAGIC,
+        }
+    }
+
+    /// Which parts of a record's data its checksum covers, as (start, len).
+    fn covered(self, len: usize) -> [(usize, usize); 2] {
+        if self == RecordFormat::Sampled && len > 2 * SAMPLE_SIZE {
+            [(0, SAMPLE_SIZE), (len - SAMPLE_SIZE, SAMPLE_SIZE)]
+        } else {
+            [(0, len), (len, 0)]
+        }
+    }
+
+    fn marker_size(self) -> usize {
+        self.marker(0).len()
+    }
+
+    fn hdr_size(self, len: usize) -> usize {
+        let len_size = if self.is_long(len) { LONG_LEN_SIZE } else { SHORT_LEN_SIZE };
+        self.marker(len).len() + OFFSET_SIZE + len_size
+    }
+}
 
 pub(crate) struct RecordHeader {
     pub logical_offset: u64,
@@ -24,9 +110,14 @@ pub(crate) struct Record {
     pub file_data_offset: u64,
 }
 
-// No zero-length spans, no overlapping.
+// No zero-length spans, no overlapping.  This walks every span, so don't
+// even do the walk in release builds.
 fn debug_check_spans(spans: &BTreeMap<u64, Span>)
 {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+
     let mut prev_end = None;
 
     for (&off, span) in spans {
@@ -40,135 +131,416 @@ fn debug_check_spans(spans: &BTreeMap<u64, Span>)
     }
 }
 
-// Read bytes, but seek back if it fails.  Return false if couldn't read all.
-fn read_bytes_fail_back(file: &mut File,
-                        buf: &mut [u8],
-                        total_read: &mut u64) -> Result<bool, Error>
+/// A length from the file (or the caller) as a usize, if it fits.
+pub(crate) fn to_usize(n: u64) -> Result<usize, Error> {
+    usize::try_from(n).map_err(|_| Error::OutOfRange)
+}
+
+// Read bytes, but seek back to `start` if it fails.  Return false if
+// couldn't read all.
+fn read_bytes_fail_back<S: Storage>(file: &mut S,
+                                    buf: &mut [u8],
+                                    start: u64,
+                                    total_read: &mut u64) -> Result<bool, Error>
 {
-    let length = file.read(buf)?;
+    // One read can stop short of the end of the file (at ~2GiB on Linux).
+    let mut length = 0;
+    while length < buf.len() {
+        match file.read(&mut buf[length..]) {
+            Ok(0) => break,
+            Ok(n) => length += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
     *total_read += length as u64;
     if length == buf.len() {
         return Ok(true);
     }
-    // Short read, stop at this point.
-    if length != 0 {
-        file.seek_relative(-(*total_read as i64))?;
+    // Short read, stop at this point.  (An absolute seek, as a relative
+    // one can't go back more than i64::MAX.)
+    file.seek(SeekFrom::Start(start))?;
+    Ok(false)
+}
+
+/// Parts of a file, as (offset, length): header, covered data (at most
+/// two parts) and checksum.  Unused ones are empty.
+type Pieces = [(u64, usize); 4];
+
+/// Records up to this long are read back into a buffer on the stack.
+const STACK_CHECK_SIZE: usize = 4096;
+
+/// Longer ones are checksummed this much at a time.
+const CHECK_CHUNK: usize = 64 * 1024;
+
+// Where the parts of the record around this data which the checksum
+// covers are (and the checksum itself), and their total length.
+fn record_pieces(format: RecordFormat, hdr_size: usize, data_offset: u64, data_length: usize)
+                 -> Result<(Pieces, usize), Error>
+{
+    let csum_offset = data_offset.checked_add(data_length as u64).ok_or(Error::OutOfRange)?;
+    let covered = format.covered(data_length)
+        .map(|(start, len)| (data_offset + start as u64, len));
+
+    // Join them up where they're adjacent (everything, usually).
+    let mut pieces: Pieces = [(0, 0); 4];
+    pieces[0] = (data_offset - hdr_size as u64, hdr_size);
+    let mut n = 1;
+    for (off, len) in covered.into_iter().chain([(csum_offset, CSUM_SIZE)]) {
+        let last = &mut pieces[n - 1];
+        if last.0 + last.1 as u64 == off {
+            last.1 = last.1.checked_add(len).ok_or(Error::OutOfRange)?;
+        } else {
+            pieces[n] = (off, len);
+            n += 1;
+        }
+    }
+    let total = pieces.iter()
+        .try_fold(0usize, |n, &(_, len)| n.checked_add(len))
+        .ok_or(Error::OutOfRange)?;
+    Ok((pieces, total))
+}
+
+// Read the pieces of a record with read(offset, buf), a chunk at a
+// time, and check it.  Small records (most of them) don't need an
+// allocation for this.
+fn check_pieces<F>(format: RecordFormat, data_offset: u64, data_length: usize, mut read: F)
+                   -> Result<bool, Error>
+    where F: FnMut(u64, &mut [u8]) -> io::Result<()>
+{
+    let hdr_size = format.hdr_size(data_length);
+    match check_with_header(format, hdr_size, data_offset, data_length, &mut read)? {
+        // A file mixing encodings can have a small record with a long
+        // header: the marker says which it is.
+        None if format != RecordFormat::Legacy
+            && hdr_size < MAX_HDR_SIZE
+            && data_offset >= MAX_HDR_SIZE as u64 => {
+            Ok(check_with_header(format, MAX_HDR_SIZE, data_offset, data_length, &mut read)?
+               .unwrap_or(false))
+        }
+        ok => Ok(ok.unwrap_or(false)),
+    }
+}
+
+// check_pieces, assuming a header of hdr_size: None if it doesn't start
+// with the marker for that size.
+fn check_with_header<F>(format: RecordFormat, hdr_size: usize, data_offset: u64, data_length: usize,
+                        read: &mut F) -> Result<Option<bool>, Error>
+    where F: FnMut(u64, &mut [u8]) -> io::Result<()>
+{
+    let marker: &[u8] = match format {
+        RecordFormat::Legacy => &[],
+        _ if hdr_size == MAX_HDR_SIZE => &LONG_MAGIC,
+        RecordFormat::Marked | RecordFormat::Sampled => &SYNC_MAGIC,
+    };
+    let (pieces, total) = record_pieces(format, hdr_size, data_offset, data_length)?;
+    let mut small = [0u8; STACK_CHECK_SIZE];
+    let mut large = Vec::new();
+    let chunk = if total <= small.len() {
+        &mut small[..]
+    } else {
+        large.resize(CHECK_CHUNK, 0);
+        &mut large[..]
+    };
+
+    // Everything but the checksum (the last bytes) goes into the digest.
+    let csum_start = total - CSUM_SIZE;
+    let mut csum = [0u8; CSUM_SIZE];
+    let mut d = checksum::Digest::new();
+    let mut pos = 0;
+    for &(mut off, mut left) in pieces.iter().filter(|&&(_, len)| len != 0) {
+        while left > 0 {
+            let n = left.min(chunk.len());
+            read(off, &mut chunk[..n])?;
+            // The first chunk holds the whole header.
+            if pos == 0 && !chunk.starts_with(marker) {
+                return Ok(None);
+            }
+            let hashed = csum_start.saturating_sub(pos).min(n);
+            d.write(&chunk[..hashed]);
+            if hashed < n {
+                let at = pos + hashed - csum_start;
+                csum[at..at + n - hashed].copy_from_slice(&chunk[hashed..n]);
+            }
+            pos += n;
+            off += n as u64;
+            left -= n;
+        }
     }
-    return Ok(false);
+    Ok(Some(d.sum64() == u64::from_le_bytes(csum)))
 }
 
-pub(crate) fn validate(file: &mut File,
-                       data_offset: u64,
-                       data_length: usize) -> Result<bool, Error>
+pub(crate) fn validate<S: Storage>(file: &mut S,
+                                   format: RecordFormat,
+                                   data_offset: u64,
+                                   data_length: usize) -> Result<bool, Error>
 {
-    let mut bytes = vec![0u8; RECORD_HDR_SIZE + data_length + 8];
+    check_pieces(format, data_offset, data_length, |off, buf| {
+        file.seek(SeekFrom::Start(off))?;
+        file.read_exact(buf)
+    })
+}
 
-    file.seek(SeekFrom::Start(data_offset - RECORD_HDR_SIZE as u64))?;
-    file.read_exact(&mut bytes)?;
+/// validate, but with a positioned read, so many threads can do it at once.
+#[cfg(feature = "rayon")]
+pub(crate) fn validate_at(file: &std::fs::File,
+                          format: RecordFormat,
+                          data_offset: u64,
+                          data_length: usize) -> Result<bool, Error>
+{
+    check_pieces(format, data_offset, data_length, |off, buf| crate::sys::read_exact_at(file, off, buf))
+}
 
-    let mut d = crc64fast::Digest::new();
-    d.write(&bytes[..RECORD_HDR_SIZE + data_length]);
+/// Why read_next_record didn't return a record.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum LogEnd {
+    /// Nothing more in the file.
+    Eof,
+    /// Only part of a record (e.g. we crashed while writing it).
+    Truncated,
+    /// Doesn't start with a sync marker.
+    BadMarker,
+    /// Length is longer than the rest of the file.
+    TooLong,
+    /// The data would end past the largest logical offset.
+    BadOffset,
+    /// The whole record is there, but the hash doesn't match.
+    BadChecksum,
+}
 
-    let csum_start = bytes.len() - 8;
-    Ok(d.sum64() == u64::from_le_bytes(bytes[csum_start..csum_start + 8].try_into().unwrap()))
+impl LogEnd {
+    fn short(total_read: u64) -> LogEnd {
+        if total_read == 0 { LogEnd::Eof } else { LogEnd::Truncated }
+    }
 }
 
-pub(crate) fn read_next_record(file: &mut File, file_offset: &mut u64) -> Result<Option<Record>, Error>
+/// Read the record at `file_offset`, and move past it.  Unless `check`,
+/// we trust the header and don't read the data or checksum at all.
+pub(crate) fn read_next_record<S: Storage>(file: &mut S,
+                                           format: RecordFormat,
+                                           file_offset: &mut u64,
+                                           check: bool) -> Result<Result<Record, LogEnd>, Error>
 {
-    let mut hdrbytes = [0u8; RECORD_HDR_SIZE];
+    let mut hdrbuf = [0u8; MAX_HDR_SIZE];
     let mut total_read: u64 = 0;
+    // Where to go back to if this isn't a record.
+    let start = *file_offset;
 
-    if !read_bytes_fail_back(file, &mut hdrbytes, &mut total_read)? {
-        return Ok(None);
+    // The marker (if any) tells us how big the length field is.
+    let marker_size = format.marker_size();
+    if !read_bytes_fail_back(file, &mut hdrbuf[..marker_size], start, &mut total_read)? {
+        return Ok(Err(LogEnd::short(total_read)));
     }
+    let len_size = match &hdrbuf[..marker_size] {
+        m if m.is_empty() || m == SYNC_MAGIC => SHORT_LEN_SIZE,
+        m if m == LONG_MAGIC => LONG_LEN_SIZE,
+        _ => {
+            file.seek(SeekFrom::Start(start))?;
+            return Ok(Err(LogEnd::BadMarker));
+        }
+    };
+
+    let hdr_size = marker_size + OFFSET_SIZE + len_size;
+    if !read_bytes_fail_back(file, &mut hdrbuf[marker_size..hdr_size], start, &mut total_read)? {
+        return Ok(Err(LogEnd::short(total_read)));
+    }
+    let hdrbytes = &hdrbuf[..hdr_size];
+    let fields = &hdrbytes[marker_size..];
 
-    let len24 = (hdrbytes[8] as u32) | ((hdrbytes[9] as u32) << 8) | ((hdrbytes[10] as u32) << 16);
+    let mut lenbytes = [0u8; 8];
+    lenbytes[..len_size].copy_from_slice(&fields[OFFSET_SIZE..]);
     let rhdr = RecordHeader {
-        logical_offset: u64::from_le_bytes(hdrbytes[..8].try_into().unwrap()),
-        length: len24 as u64,
+        logical_offset: u64::from_le_bytes(fields[..OFFSET_SIZE].try_into().unwrap()),
+        length: u64::from_le_bytes(lenbytes),
     };
 
+    if rhdr.logical_offset.checked_add(rhdr.length).is_none() {
+        file.seek(SeekFrom::Start(start))?;
+        return Ok(Err(LogEnd::BadOffset));
+    }
+
+    // Don't trust a (possibly corrupt) length enough to allocate it!
+    if rhdr.length > MAX_UNCHECKED_LEN {
+        let pos = file.stream_position()?;
+        let end = file.seek(SeekFrom::End(0))?;
+        file.seek(SeekFrom::Start(pos))?;
+        if rhdr.length.saturating_add(8) > end - pos {
+            file.seek(SeekFrom::Start(start))?;
+            return Ok(Err(LogEnd::TooLong));
+        }
+    }
+
     let rec = Record {
         hdr: rhdr,
         file_data_offset: *file_offset + hdrbytes.len() as u64,
     };
 
-    let mut data = vec![0u8; rec.hdr.length as usize];
-    if !read_bytes_fail_back(file, &mut data, &mut total_read)? {
-        return Ok(None);
+    if !check {
+        // We only know it's all there if the file is long enough.
+        let file_end = file.seek(SeekFrom::End(0))?;
+        let end = rec.file_data_offset
+            .checked_add(rec.hdr.length)
+            .and_then(|n| n.checked_add(CSUM_SIZE as u64));
+        return match end {
+            Some(end) if end <= file_end => {
+                file.seek(SeekFrom::Start(end))?;
+                *file_offset = end;
+                Ok(Ok(rec))
+            }
+            _ => {
+                file.seek(SeekFrom::Start(start))?;
+                Ok(Err(LogEnd::Truncated))
+            }
+        };
     }
 
+    // Calculate and check hash: my laptop does this at 38Gbytes/sec,
+    // vs siphash13 at 6Gbytes/sec.
+    let mut d = checksum::Digest::new();
+    d.write(hdrbytes);
+
+    // Only read the data the hash covers (usually all of it), a chunk
+    // at a time.
+    let mut data = Vec::new();
+    for (offset, mut len) in format.covered(to_usize(rec.hdr.length)?) {
+        if len == 0 {
+            continue;
+        }
+        file.seek(SeekFrom::Start(rec.file_data_offset + offset as u64))?;
+        data.resize(len.min(CHECK_CHUNK), 0);
+        while len > 0 {
+            let n = len.min(data.len());
+            if !read_bytes_fail_back(file, &mut data[..n], start, &mut total_read)? {
+                return Ok(Err(LogEnd::Truncated));
+            }
+            d.write(&data[..n]);
+            len -= n;
+        }
+    }
+
+    let end = rec.file_data_offset + rec.hdr.length;
+    file.seek(SeekFrom::Start(end))?;
     let mut tlrbytes = [0u8; 8];
-    if !read_bytes_fail_back(file, &mut tlrbytes, &mut total_read)? {
-        return Ok(None);
+    if !read_bytes_fail_back(file, &mut tlrbytes, start, &mut total_read)? {
+        return Ok(Err(LogEnd::Truncated));
     }
 
-    // Calculate and check hash: my laptop does this at 38Gbytes/sec,
-    // vs siphash13 at 6Gbytes/sec.
-    let mut d = crc64fast::Digest::new();
-    d.write(&hdrbytes);
-    d.write(&data);
-    
-    if d.sum64() != u64::from_le_bytes(tlrbytes.try_into().unwrap()) {
-        file.seek_relative(-(total_read as i64))?;
-        return Ok(None);
+    if d.sum64() != u64::from_le_bytes(tlrbytes) {
+        file.seek(SeekFrom::Start(start))?;
+        return Ok(Err(LogEnd::BadChecksum));
     }
 
-    *file_offset += total_read;
-    return Ok(Some(rec));
+    *file_offset = end + CSUM_SIZE as u64;
+    Ok(Ok(rec))
+}
+
+/// Find the next sync marker at or after `from`, which is (probably!)
+/// the start of a record.  Only useful for RecordFormat::Marked.
+pub(crate) fn find_sync<S: Storage>(file: &mut S, from: u64) -> Result<Option<u64>, Error>
+{
+    const CHUNK: usize = 64 * 1024;
+    let mut buf = vec![0u8; CHUNK];
+    let mut pos = from;
+
+    loop {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut n = 0;
+        while n < buf.len() {
+            let r = file.read(&mut buf[n..])?;
+            if r == 0 {
+                break;
+            }
+            n += r;
+        }
+
+        if let Some(i) = buf[..n].windows(SYNC_MAGIC.len()).position(|w| w == SYNC_MAGIC || w == LONG_MAGIC) {
+            return Ok(Some(pos + i as u64));
+        }
+        if n < buf.len() {
+            return Ok(None);
+        }
+        // Marker might straddle the chunk boundary.
+        pos += (n - (SYNC_MAGIC.len() - 1)) as u64;
+    }
 }
 
-/// Appends a record to the end of the store (must be < 16MB!)
+/// Appends a record to the end of the store (must be <= format.max_record_len()!)
 /// 
 /// The file cursor must be positioned at the end of the valid log.
 /// Atomicity is provided by the trailer checksum; durability is not guaranteed.
-pub(crate) fn write_record(file: &mut File,
-                           logical_offset: u64,
-                           data: &[u8],
-                           file_size: &mut u64)
-                           -> Result<u64, Error>
+pub(crate) fn write_record<S: Storage>(file: &mut S,
+                                       format: RecordFormat,
+                                       logical_offset: u64,
+                                       data: &[u8],
+                                       file_size: &mut u64)
+                                       -> Result<u64, Error>
 {
-    let offhdr = logical_offset.to_le_bytes();
     let len = data.len();
+    let marker = format.marker(len);
+    let offhdr = logical_offset.to_le_bytes();
 
-    debug_assert!(len < MAX_RECORD_SIZE);
-    debug_assert!(MAX_RECORD_SIZE - 1 <= 0x00FF_FFFF);
-    let lenhdr = [(len & 0xFF) as u8,
-                  ((len >> 8) & 0xFF) as u8,
-                  ((len >> 16) & 0xFF) as u8];
+    debug_assert!(len <= format.max_record_len());
+    let lenbytes = (len as u64).to_le_bytes();
+    let lenhdr = if format.is_long(len) {
+        &lenbytes[..]
+    } else {
+        &lenbytes[..SHORT_LEN_SIZE]
+    };
 
-    file.write_all(&offhdr)?;
-    file.write_all(&lenhdr)?;
-    let data_off = *file_size + offhdr.len() as u64 + lenhdr.len() as u64;
-    file.write_all(data)?;
+    let data_off = *file_size + (marker.len() + offhdr.len() + lenhdr.len()) as u64;
 
-    let mut d = crc64fast::Digest::new();
+    let mut d = checksum::Digest::new();
+    d.write(marker);
     d.write(&offhdr);
-    d.write(&lenhdr);
-    d.write(data);
+    d.write(lenhdr);
+    for (start, len) in format.covered(len) {
+        d.write(&data[start..start + len]);
+    }
     let tlr = u64::to_le_bytes(d.sum64());
-    file.write_all(&tlr)?;
+
+    write_all_vectored(file, &mut [IoSlice::new(marker),
+                                   IoSlice::new(&offhdr),
+                                   IoSlice::new(lenhdr),
+                                   IoSlice::new(data),
+                                   IoSlice::new(&tlr)])?;
     *file_size = data_off + data.len() as u64 + tlr.len() as u64;
 
     Ok(data_off)
 }
 
-/// If a span overlaps logical_offset, split it in two.
-fn split_span(spans: &mut BTreeMap<u64, Span>, logical_offset: u64)
+// Like write_all, but a whole record is usually one writev.  Where the
+// OS (or Storage) doesn't do vectored writes, this writes one at a time.
+fn write_all_vectored<S: Storage>(file: &mut S,
+                                  mut bufs: &mut [IoSlice<'_>]) -> Result<(), Error>
 {
-    if let Some((&offset, span)) = spans.range((Included(0), Excluded(logical_offset))).next_back() {
-        if offset + span.len > logical_offset {
-            let before_len = logical_offset - offset;
-            // We cannot validate spans after splitting, since they no longer correspond to
-            // the record on disk.  So caller must have done this!
-            assert!(span.validated);
-            let newspan = Span { len: span.len - before_len,
-                                 file_data_offset: span.file_data_offset + before_len,
-                                 validated: span.validated };
-            spans.insert(logical_offset, newspan);
-            spans.get_mut(&offset).unwrap().len = before_len;
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match file.write_vectored(bufs) {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero).into()),
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
         }
     }
+    Ok(())
+}
+
+/// If a span overlaps logical_offset, split it in two.
+fn split_span(spans: &mut BTreeMap<u64, Span>, logical_offset: u64, records_kept: bool)
+{
+    if let Some((&offset, span)) = spans.range((Included(0), Excluded(logical_offset))).next_back()
+        && offset + span.len > logical_offset {
+        let before_len = logical_offset - offset;
+        // We cannot validate spans after splitting, since they no longer correspond to
+        // the record on disk.  So caller must have done this, or be keeping
+        // track of the records to validate those instead!
+        assert!(span.validated || records_kept);
+        let newspan = Span { len: span.len - before_len,
+                             file_data_offset: span.file_data_offset + before_len,
+                             validated: span.validated };
+        spans.insert(logical_offset, newspan);
+        spans.get_mut(&offset).unwrap().len = before_len;
+    }
 }
 
 /// Insert a record into our in-memory span map.
@@ -176,27 +548,31 @@ pub(crate) fn add_record(spans: &mut BTreeMap<u64, Span>,
                          logical_offset: u64,
                          len: u64,
                          file_data_offset: u64,
-                         validated: bool)
+                         validated: bool,
+                         records_kept: bool)
 {
-    // Do we partially overlap some spans?  Split if so.
-    split_span(spans, logical_offset);
-    split_span(spans, logical_offset + len);
+    // Appending past everything (the usual case)?  Nothing to split or replace.
+    let past_end = spans.last_key_value()
+        .is_none_or(|(&offset, span)| offset + span.len <= logical_offset);
+    if !past_end {
+        // Do we partially overlap some spans?  Split if so.
+        split_span(spans, logical_offset, records_kept);
+        split_span(spans, logical_offset + len, records_kept);
 
-    // Collect overlaps (can't delete during iteration).
-    let overlaps: Vec<u64> = spans
-        .range((Included(logical_offset), Excluded(logical_offset + len)))
-        .map(|(&k, _)| k)
-        .collect();
-
-    // Delete all.
-    for k in overlaps {
-        spans.remove(&k);
+        // Delete the ones we cover.
+        while let Some((&k, _)) = spans.range((Included(logical_offset), Excluded(logical_offset + len))).next() {
+            spans.remove(&k);
+        }
     }
 
-    // Insert new span.
-    spans.insert(logical_offset, Span { len: len,
-                                        file_data_offset: file_data_offset,
-                                        validated: validated,
+    // Insert new span.  We don't try to merge it with its neighbours:
+    // each record's header and checksum sit between its data and the
+    // next record's, so a new record is never file-contiguous with an
+    // existing span.  (Unvalidated spans must also stay one per record,
+    // for validate_spans.)  Compaction is what bounds the map.
+    spans.insert(logical_offset, Span { len,
+                                        file_data_offset,
+                                        validated,
     });
     debug_check_spans(spans);
 }
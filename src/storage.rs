@@ -0,0 +1,368 @@
+//! What records are kept in: a File, except in tests which want
+//! something nastier.
+//!
+//! header.rs and record.rs only touch the file through this, but it's
+//! still std::io underneath (as is Error::Io), so syncless needs std:
+//! a no_std build would need our own I/O trait and error type, and a
+//! Store which isn't built around a File and a Path.
+//!
+//! That Store is also what a browser backend (IndexedDB, or a buffer
+//! saved to localStorage) would need: it could implement Storage, but
+//! wasm32-unknown-unknown has no File to hand to Store, and we'd want
+//! bindings (wasm-bindgen, web-sys) we don't depend on.
+use std::fs::File;
+use std::io::{self, Read, Seek, Write};
+
+/// Somewhere to read and write records.
+pub(crate) trait Storage: Read + Write + Seek {
+    fn sync_data(&mut self) -> io::Result<()>;
+    fn sync_all(&mut self) -> io::Result<()>;
+}
+
+impl Storage for File {
+    fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+/// Wraps Storage to remember where the cursor is, so seeking to where
+/// we already are (e.g. for back-to-back appends) costs nothing.
+pub(crate) struct Tracked<S> {
+    inner: S,
+    /// None if we don't know (e.g. after an error).
+    pos: Option<u64>,
+    /// Someone else may move the cursor (see Store::try_clone), so never
+    /// skip a seek.
+    shared: bool,
+}
+
+impl<S: Storage> Tracked<S> {
+    pub fn new(inner: S) -> Tracked<S> {
+        Tracked { inner, pos: None, shared: false }
+    }
+
+    /// We moved the cursor behind its back.
+    #[cfg(feature = "rayon")]
+    pub fn forget_position(&mut self) {
+        self.pos = None;
+    }
+
+    /// Stop remembering where the cursor is.
+    pub fn set_shared(&mut self) {
+        self.shared = true;
+        self.pos = None;
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn advance(&mut self, r: io::Result<usize>) -> io::Result<usize> {
+        match r {
+            Ok(n) => self.pos = self.pos.map(|p| p + n as u64),
+            Err(_) => self.pos = None,
+        }
+        r
+    }
+}
+
+impl<S: Storage> Read for Tracked<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let r = self.inner.read(buf);
+        self.advance(r)
+    }
+}
+
+impl<S: Storage> Write for Tracked<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let r = self.inner.write(buf);
+        self.advance(r)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        let r = self.inner.write_vectored(bufs);
+        self.advance(r)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: Storage> Seek for Tracked<S> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match (pos, self.pos) {
+            (io::SeekFrom::Start(off), Some(cur)) if off == cur => return Ok(cur),
+            (io::SeekFrom::Current(0), Some(cur)) => return Ok(cur),
+            _ => {}
+        }
+        let r = self.inner.seek(pos);
+        self.pos = r.as_ref().ok().copied().filter(|_| !self.shared);
+        r
+    }
+}
+
+impl<S: Storage> Storage for Tracked<S> {
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.inner.sync_data()
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.inner.sync_all()
+    }
+}
+
+/// In-memory storage which counts seeks and writes.
+#[cfg(test)]
+pub(crate) struct CountingStorage {
+    data: io::Cursor<Vec<u8>>,
+    pub seeks: u32,
+    pub writes: u32,
+}
+
+#[cfg(test)]
+impl CountingStorage {
+    pub fn new() -> CountingStorage {
+        CountingStorage { data: io::Cursor::new(Vec::new()), seeks: 0, writes: 0 }
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut Vec<u8> {
+        self.data.get_mut()
+    }
+}
+
+#[cfg(test)]
+impl Read for CountingStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl Write for CountingStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes += 1;
+        self.data.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.writes += 1;
+        self.data.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Seek for CountingStorage {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.seeks += 1;
+        self.data.seek(pos)
+    }
+}
+
+#[cfg(test)]
+impl Storage for CountingStorage {
+    fn sync_data(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// In-memory storage which acts like ZFS on a bad day: anything
+/// written since the last sync reads back as zeroes.
+#[cfg(test)]
+pub(crate) struct FaultyStorage {
+    data: io::Cursor<Vec<u8>>,
+    /// [start, end) ranges written since the last sync.
+    unsynced: Vec<(u64, u64)>,
+    /// How many times we've been synced.
+    pub syncs: u32,
+}
+
+#[cfg(test)]
+impl FaultyStorage {
+    pub fn new() -> FaultyStorage {
+        FaultyStorage { data: io::Cursor::new(Vec::new()), unsynced: Vec::new(), syncs: 0 }
+    }
+
+    fn sync(&mut self) {
+        self.unsynced.clear();
+        self.syncs += 1;
+    }
+}
+
+#[cfg(test)]
+impl Read for FaultyStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = self.data.position();
+        let n = self.data.read(buf)?;
+        for &(s, e) in &self.unsynced {
+            let s = s.clamp(start, start + n as u64);
+            let e = e.clamp(start, start + n as u64);
+            buf[(s - start) as usize..(e - start) as usize].fill(0);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+impl Write for FaultyStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = self.data.position();
+        let n = self.data.write(buf)?;
+        self.unsynced.push((start, start + n as u64));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Seek for FaultyStorage {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+#[cfg(test)]
+impl Storage for FaultyStorage {
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.sync();
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.sync();
+        Ok(())
+    }
+}
+
+/// In-memory storage which runs out of space: after `budget` more bytes,
+/// writes fail (part way through, if need be), like ENOSPC or a crash
+/// in the middle of appending a record.
+#[cfg(test)]
+pub(crate) struct TornStorage {
+    data: io::Cursor<Vec<u8>>,
+    /// Bytes we'll still write (None means no limit).
+    pub budget: Option<usize>,
+}
+
+#[cfg(test)]
+impl TornStorage {
+    pub fn new() -> TornStorage {
+        TornStorage { data: io::Cursor::new(Vec::new()), budget: None }
+    }
+}
+
+#[cfg(test)]
+impl Read for TornStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.data.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl Write for TornStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match self.budget {
+            Some(0) if !buf.is_empty() => return Err(io::ErrorKind::StorageFull.into()),
+            Some(budget) => budget.min(buf.len()),
+            None => buf.len(),
+        };
+        let n = self.data.write(&buf[..n])?;
+        if let Some(budget) = &mut self.budget {
+            *budget -= n;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Seek for TornStorage {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+#[cfg(test)]
+impl Storage for TornStorage {
+    fn sync_data(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// In-memory storage which reads at most `max_read` bytes at a time, as
+/// a File does for huge reads (Linux stops a little short of 2GiB).
+#[cfg(test)]
+pub(crate) struct ShortReadStorage {
+    data: io::Cursor<Vec<u8>>,
+    pub max_read: usize,
+}
+
+#[cfg(test)]
+impl ShortReadStorage {
+    pub fn new(max_read: usize) -> ShortReadStorage {
+        ShortReadStorage { data: io::Cursor::new(Vec::new()), max_read }
+    }
+}
+
+#[cfg(test)]
+impl Read for ShortReadStorage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.max_read);
+        self.data.read(&mut buf[..n])
+    }
+}
+
+#[cfg(test)]
+impl Write for ShortReadStorage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Seek for ShortReadStorage {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+#[cfg(test)]
+impl Storage for ShortReadStorage {
+    fn sync_data(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
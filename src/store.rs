@@ -1,59 +1,679 @@
 use std::fs::File;
 use std::path::{Path, PathBuf};
-use std::collections::BTreeMap;
-use std::io::{Read, Seek, SeekFrom};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufRead, Read, Seek, SeekFrom, Write};
 use std::ops::Bound::*;
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use crate::Error;
+use crate::checksum;
 use crate::header;
 use crate::record;
+use crate::record::{LogEnd, RecordFormat};
+use crate::storage::{Storage, Tracked};
+use crate::sys;
 use crate::Store;
-use crate::{ReadOnly, Writable, WriteOpenMode};
+#[cfg(feature = "serde")]
+use crate::StoreSnapshot;
+use crate::{Metrics, ReadCoverage, ReadOnly, ReplayStats, ReplayStop, RetryPolicy, RetrySync, SparseManifest, SyncPolicy, Writable, WriteEvent, WriteOpenMode};
+
+/// Options chosen at open time (and kept across compaction).
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Options {
+    pub sync_policy: SyncPolicy,
+    /// Skip over damaged records rather than stopping replay there.
+    pub resync: bool,
+    /// Writable opens: truncate anything after the last good record.
+    pub repair_tail: bool,
+    /// Recheck records on every read.
+    pub paranoid: bool,
+    /// Don't check records' checksums on open, only when they're used.
+    pub skip_checksum: bool,
+    /// Read across gaps in the file up to this long, rather than seek.
+    pub read_coalesce_gap: u64,
+    /// Readonly opens: replay at most this many records until needed.
+    pub max_replay_records: Option<u64>,
+    /// Check the oldest records written once more than this are unchecked.
+    pub max_unvalidated_spans: Option<usize>,
+    /// Checksum only a sample of each record, in a newly-created file.
+    pub sampled_checksums: bool,
+    /// Create missing parent directories (if we may create the file).
+    pub create_dirs: bool,
+    /// Unix permissions for a newly-created file.
+    pub unix_mode: Option<u32>,
+    /// What holes read as, in a newly-created file.
+    pub hole_fill: u8,
+    /// Header extensions for a newly-created file.
+    pub metadata: Vec<(u8, Vec<u8>)>,
+    /// How often, and how, to sync and recheck a record written this
+    /// session which reads back wrong (default: once, with sync_data).
+    pub retry_policy: RetryPolicy,
+}
+
+impl Options {
+    /// How to write records in a newly-created file.
+    fn new_format(&self) -> RecordFormat {
+        if self.sampled_checksums { RecordFormat::Sampled } else { RecordFormat::CURRENT }
+    }
+
+    /// Could `metadata` go in a new header?
+    fn check_metadata(&self) -> Result<(), Error> {
+        if self.metadata.iter().any(|&(tag, _)| tag < header::FIRST_APP_TAG)
+            || !header::ext_fits(&self.metadata) {
+            return Err(Error::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+/// Options for opening a store, builder-style.
+///
+/// The `open*` functions are shortcuts for common cases of this.
+///
+/// ```no_run
+/// use syncless::{StoreOptions, SyncPolicy, WriteOpenMode};
+///
+/// let store = StoreOptions::new()
+///     .write_mode(WriteOpenMode::MayExist)
+///     .sync_policy(SyncPolicy::EveryN(100))
+///     .create_dirs(true)
+///     .open("data/history.syncless")?;
+/// # Ok::<(), syncless::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct StoreOptions {
+    mode: WriteOpenMode,
+    opts: Options,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions::new()
+    }
+}
+
+impl StoreOptions {
+    /// Default options: as [`open`] with [`WriteOpenMode::MayExist`].
+    pub fn new() -> StoreOptions {
+        StoreOptions { mode: WriteOpenMode::MayExist, opts: Options::default() }
+    }
+
+    /// Whether the file must (or must not) already exist, for
+    /// [`StoreOptions::open`].
+    pub fn write_mode(mut self, mode: WriteOpenMode) -> StoreOptions {
+        self.mode = mode;
+        self
+    }
+
+    /// When to sync after writes (see [`open_with_sync_policy`]).
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> StoreOptions {
+        self.opts.sync_policy = policy;
+        self
+    }
+
+    /// How to retry fresh records which read back wrong (see
+    /// [`open_with_retry_policy`]).
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> StoreOptions {
+        self.opts.retry_policy = policy;
+        self
+    }
+
+    /// Skip over damaged records (see [`open_resync`]).
+    pub fn resync(mut self, resync: bool) -> StoreOptions {
+        self.opts.resync = resync;
+        self
+    }
+
+    /// Opening writable, truncate the file after the last good record
+    /// if replay stopped at something else (say, a write torn by a crash),
+    /// so it isn't scanned again on every open.
+    ///
+    /// Otherwise new records are written over it, but whatever is left
+    /// past them stays.  With [`StoreOptions::resync`], any records
+    /// after the damage are kept, as usual, and only what follows the
+    /// last of them goes.  Readonly opens ignore this.
+    pub fn repair_tail(mut self, repair: bool) -> StoreOptions {
+        self.opts.repair_tail = repair;
+        self
+    }
+
+    /// Recheck records on every read (see [`open_paranoid`]).
+    pub fn paranoid(mut self, paranoid: bool) -> StoreOptions {
+        self.opts.paranoid = paranoid;
+        self
+    }
+
+    /// Don't check the checksum of every record on open: just follow the
+    /// record headers to find the data.
+    ///
+    /// This makes opening a large file much faster, but only use it on
+    /// files you trust (say, local ones you just wrote).  Records are
+    /// checked later, when first read or overwritten, so damage (such as
+    /// a torn last write) which would have been quietly dropped on open
+    /// instead shows up as [`Error::CorruptRecord`] then.  This also means
+    /// [`StoreOptions::resync`] can't skip damage it doesn't see.
+    pub fn skip_checksum_on_open(mut self, skip: bool) -> StoreOptions {
+        self.opts.skip_checksum = skip;
+        self
+    }
+
+    /// Opening readonly, replay at most this many records before
+    /// returning, leaving the rest until something needs them (see
+    /// [`Store::fully_loaded`]).  Writable opens ignore this, as they need
+    /// to find the end of the file to append to it.
+    ///
+    /// This makes opening a huge store quick, but a later record can
+    /// change any part of it, so the first read (or anything else which
+    /// takes `&mut self` and needs the contents) replays the rest.
+    /// Until then, methods which take `&self`, such as [`Store::size`],
+    /// only see the records replayed so far.
+    pub fn max_replay_records(mut self, max: Option<u64>) -> StoreOptions {
+        self.opts.max_replay_records = max;
+        self
+    }
+
+    /// Opening readonly, only read the header: replay the records when
+    /// something first needs them.  This is `max_replay_records(Some(0))`,
+    /// so the same caveats apply.  `false` undoes that, but leaves any
+    /// other limit set with [`StoreOptions::max_replay_records`].
+    ///
+    /// Replay can't be limited to the range a read touches: there's no
+    /// index, and any later record may overwrite that range, so finding
+    /// out what it holds means reading every record header anyway.  Only
+    /// [`StoreOptions::skip_checksum_on_open`] avoids reading all the data
+    /// too.
+    pub fn lazy_replay(self, lazy: bool) -> StoreOptions {
+        if lazy {
+            self.max_replay_records(Some(0))
+        } else if self.opts.max_replay_records == Some(0) {
+            self.max_replay_records(None)
+        } else {
+            self
+        }
+    }
+
+    /// Once more than this many records written since open haven't been
+    /// checked, have each write check the oldest of them, so the work is
+    /// spread over writes rather than all landing on the next big read
+    /// or [`Store::into_readonly`].  None (the default) never does.
+    ///
+    /// Checking a fresh record can mean a sync first (see
+    /// [`RetryPolicy`]), so a small limit makes writes slower and steadier.
+    /// Records already checked (say, by a read) still count until their
+    /// turn comes, but cost almost nothing then.
+    pub fn max_unvalidated_spans(mut self, max: Option<usize>) -> StoreOptions {
+        self.opts.max_unvalidated_spans = max;
+        self
+    }
+
+    /// When creating a store, have each record's checksum cover only its
+    /// header and the first and last 4096 bytes of its data, rather than
+    /// all of it, so opening (and checking fresh records) only reads that
+    /// much of a large record.  Existing stores keep whatever they were
+    /// created with.
+    ///
+    /// **This weakens the guarantees**: damage to the middle of a large
+    /// record goes unnoticed, and if a crash tears a large write (the
+    /// middle not reaching disk, but both ends doing so), the torn record
+    /// is taken as valid and you read stale or zeroed bytes there.  Only
+    /// use it for large records where that's acceptable, or where the
+    /// data has its own checks.  The file can't be opened by versions of
+    /// syncless before this option was added.
+    pub fn sampled_checksums(mut self, sampled: bool) -> StoreOptions {
+        self.opts.sampled_checksums = sampled;
+        self
+    }
+
+    /// When [`Store::read`] needs pieces of the file which are at most
+    /// this many bytes apart, read them (and what's between) at once
+    /// rather than separately.
+    ///
+    /// The default, 0, only joins pieces which are right next to each
+    /// other.  Every record has [`crate::RECORD_OVERHEAD`] bytes between
+    /// its data and the next's, so something larger can save system calls
+    /// on high-latency storage, at the cost of reading bytes we discard.
+    pub fn read_coalesce_gap(mut self, gap: u64) -> StoreOptions {
+        self.opts.read_coalesce_gap = gap;
+        self
+    }
+
+    /// Create missing parent directories (see [`open_creating_dirs`]).
+    pub fn create_dirs(mut self, create_dirs: bool) -> StoreOptions {
+        self.opts.create_dirs = create_dirs;
+        self
+    }
+
+    /// Unix permissions for a new file (see [`open_with_permissions`]).
+    pub fn unix_mode(mut self, unix_mode: u32) -> StoreOptions {
+        self.opts.unix_mode = Some(unix_mode);
+        self
+    }
+
+    /// What holes (and reads past the end) read as in a new store,
+    /// instead of zero.
+    ///
+    /// This is saved in the file, so it's ignored when opening an
+    /// existing store (see [`Store::hole_fill`]).
+    pub fn hole_fill(mut self, hole_fill: u8) -> StoreOptions {
+        self.opts.hole_fill = hole_fill;
+        self
+    }
+
+    /// Saves `value` in the header of a new store, under `tag`, for
+    /// [`Store::metadata`] (replacing any value already set for `tag`).
+    ///
+    /// Like [`StoreOptions::hole_fill`], this is ignored when opening an
+    /// existing store, but opening for writing returns
+    /// [`Error::OutOfRange`] if `tag` is less than 0x80 (those are
+    /// reserved for syncless), or the values set won't fit in the header:
+    /// there's room for 128 bytes, including two for each value.
+    pub fn metadata(mut self, tag: u8, value: &[u8]) -> StoreOptions {
+        self.opts.metadata.retain(|(t, _)| *t != tag);
+        self.opts.metadata.push((tag, value.to_vec()));
+        self
+    }
+
+    /// Opens the store for reading and writing.
+    ///
+    /// # Errors
+    ///
+    /// As for [`open`].
+    pub fn open<P: AsRef<Path>>(&self, path: P) -> Result<Store<Writable>, Error> {
+        Ok(Store {base: open_writable_base::<P>(path, self.mode, self.opts.clone())?,
+                  writable: true,
+                  _mode: PhantomData})
+    }
+
+    /// Opens an existing store readonly: options which only make sense for
+    /// writing (such as the write mode and retry policy) are ignored.
+    ///
+    /// # Errors
+    ///
+    /// As for [`open_readonly`].
+    pub fn open_readonly<P: AsRef<Path>>(&self, path: P) -> Result<Store<ReadOnly>, Error> {
+        open_readonly_base(path, self.opts.clone())
+    }
+
+    /// Creates a new store with no path (see [`open_anonymous`]): options
+    /// about the file (such as the write mode) are ignored.
+    ///
+    /// # Errors
+    ///
+    /// As for [`open_anonymous`].
+    pub fn open_anonymous(&self) -> Result<Store<Writable>, Error> {
+        self.opts.check_metadata()?;
+        let file = sys::anonymous_file()?;
+        let mut base = StoreBase::new(PathBuf::from("(anonymous)"), file, self.opts.clone());
+        base.anonymous = true;
+        base.created = true;
+        base.generation = Some(1);
+        base.file_size = header::write_header(&mut base.file, 1, base.format, &base.fields)?;
+        Ok(Store {base,
+                  writable: true,
+                  _mode: PhantomData})
+    }
+}
 
 /// An open Syncless store.
 pub(crate) struct StoreBase {
     path: PathBuf,
-    file: File,
+    file: Tracked<File>,
     spans: BTreeMap<u64, Span>,
+    /// If opts.paranoid or opts.skip_checksum, every record: file data
+    /// offset -> length.
+    records: BTreeMap<u64, u64>,
     file_size: u64,
+    /// Where the first record starts.
+    header_len: u64,
+    /// Where each record starts, from replay and our writes.
+    record_starts: Vec<u64>,
+    format: RecordFormat,
+    /// Version from the header.
+    ver: header::HeaderVer,
+    /// The rest of the header.
+    fields: header::HeaderFields,
+    /// Did replay skip over damage to find later records?
+    resynced: bool,
+    /// Replay stopped at max_replay_records: there may be more records
+    /// after file_size.
+    replay_limited: bool,
+    /// None if the file predates generation counters.
+    generation: Option<u64>,
+    /// Did this open create the file (or find it empty)?
+    created: bool,
+    /// No path: see open_anonymous.
+    anonymous: bool,
+    /// How many times we've compacted since open.
+    compactions: u64,
+    /// Bumped whenever records are removed from the file (compaction,
+    /// rollback, undo_last), which invalidates savepoints.
+    history: u64,
+    /// How many records the last compaction wrote (which can't be undone).
+    compacted_records: usize,
+    /// Highest checkpoint id replayed or written (see Store::checkpoint).
+    last_checkpoint: Option<u64>,
+    /// A Transaction is open, so don't compact (it couldn't roll back).
+    in_transaction: bool,
+    /// Where to send each record we write, if anywhere.
+    replication: Option<Sender<WriteEvent>>,
+    opts: Options,
+    metrics: Metrics,
+    replay_stats: ReplayStats,
+    /// Logical ranges of records written, oldest first, to check later
+    /// (only kept with max_unvalidated_spans).
+    unvalidated: VecDeque<(u64, u64)>,
+    /// Kept between reads so each one needn't allocate: the runs to
+    /// read, and a buffer for reading several of them at once.
+    read_runs: Vec<(u64, u64, u64)>,
+    read_scratch: Vec<u8>,
+    /// Writes since we last synced, and when that was.
+    writes_since_sync: u32,
+    last_sync: Instant,
 }
 
 impl StoreBase {
+    fn new(path: PathBuf, file: File, opts: Options) -> StoreBase {
+        // For a new file: an existing one's header overrides this.
+        let fields = header::HeaderFields::new_store(opts.hole_fill, opts.metadata.clone());
+        StoreBase {
+            path,
+            file: Tracked::new(file),
+            spans: BTreeMap::new(),
+            records: BTreeMap::new(),
+            file_size: 0,
+            header_len: header::HEADER_SIZE as u64,
+            record_starts: Vec::new(),
+            format: opts.new_format(),
+            ver: header::HeaderVer::for_format(opts.new_format()),
+            fields,
+            resynced: false,
+            replay_limited: false,
+            generation: None,
+            created: false,
+            anonymous: false,
+            compactions: 0,
+            history: 0,
+            compacted_records: 0,
+            last_checkpoint: None,
+            in_transaction: false,
+            replication: None,
+            opts,
+            metrics: Metrics::default(),
+            replay_stats: ReplayStats::default(),
+            unvalidated: VecDeque::new(),
+            read_runs: Vec::new(),
+            read_scratch: Vec::new(),
+            writes_since_sync: 0,
+            last_sync: Instant::now(),
+        }
+    }
+
+    /// Put the run list back for the next read, and the scratch buffer
+    /// too, unless either grew too big to keep around.
+    fn keep_read_buffers(&mut self, runs: Vec<(u64, u64, u64)>) {
+        if runs.capacity() <= MAX_KEPT_READ_RUNS {
+            self.read_runs = runs;
+        }
+        if self.read_scratch.capacity() > MAX_KEPT_READ_SCRATCH {
+            self.read_scratch = Vec::new();
+        }
+    }
+
     pub fn size(&self) -> u64 {
         self.spans
             .last_key_value()
             .map(|(off, span)| off + span.len)
             .unwrap_or(0)
     }
+
+    /// Runs of adjacent spans, as (logical offset, length), in order.
+    fn runs(&self) -> Vec<(u64, u64)> {
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for (&off, span) in &self.spans {
+            match runs.last_mut() {
+                Some((start, len)) if *start + *len == off => *len += span.len,
+                _ => runs.push((off, span.len)),
+            }
+        }
+        runs
+    }
+
+    /// End of the last span starting in [start, last], if any.
+    #[cfg(feature = "streams")]
+    pub(crate) fn last_end_in(&self, start: u64, last: u64) -> Option<u64> {
+        self.spans.range(start..=last).next_back().map(|(off, span)| off + span.len)
+    }
+
+    /// Pieces of spans within [start, end), clipped to it, in order:
+    /// (logical offset, length, file offset).
+    pub(crate) fn spans_in(&self, start: u64, end: u64) -> impl Iterator<Item = (u64, u64, u64)> + '_ {
+        let end = end.max(start);
+
+        // Previous span may overlap the start.
+        let prev = self.spans
+            .range((Included(0), Excluded(start)))
+            .next_back()
+            .filter(|(off, span)| *off + span.len > start);
+
+        prev.into_iter()
+            .chain(self.spans.range((Included(start), Excluded(end))))
+            .map(move |(&off, span)| {
+                let s = off.max(start);
+                let e = (off + span.len).min(end);
+                (s, e - s, span.file_data_offset + (s - off))
+            })
+    }
+
+    fn add_record(&mut self, logical_offset: u64, len: u64, file_data_offset: u64, validated: bool) {
+        // Empty records are checkpoints, with the id in the offset (except
+        // in 0.1 files: it compacted an empty store to an empty record).
+        if len == 0 {
+            if self.format != RecordFormat::Legacy {
+                self.last_checkpoint = self.last_checkpoint.max(Some(logical_offset));
+            }
+            return;
+        }
+        let records_kept = self.keeps_records();
+        record::add_record(&mut self.spans, logical_offset, len, file_data_offset, validated, records_kept);
+        if records_kept {
+            self.records.insert(file_data_offset, len);
+        }
+    }
+
+    /// The parts of the file holding [start, end), as (file offset, len),
+    /// in file order: nearby ones are merged, as this is for hints to the
+    /// OS, which works in pages anyway.
+    fn file_ranges(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        const PAGE: u64 = 4096;
+        let mut pieces: Vec<(u64, u64)> = self.spans_in(start, end).map(|(_, len, file_off)| (file_off, len)).collect();
+        pieces.sort_unstable();
+
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for (file_off, len) in pieces {
+            if let Some(last) = ranges.last_mut()
+                && file_off <= last.0 + last.1 + PAGE {
+                last.1 = max(last.1, file_off + len - last.0);
+            } else {
+                ranges.push((file_off, len));
+            }
+        }
+        ranges
+    }
+
+    /// Forget the spans, and replay the file again.
+    fn replay(&mut self) -> Result<(), Error> {
+        self.spans.clear();
+        self.records.clear();
+        self.record_starts.clear();
+        self.last_checkpoint = None;
+        let mut pos = self.header_len;
+        self.file.seek(SeekFrom::Start(pos))?;
+        loop {
+            let start = pos;
+            let Ok(record) = record::read_next_record(&mut self.file, self.format, &mut pos, true)? else {
+                break;
+            };
+            self.record_starts.push(start);
+            self.add_record(record.hdr.logical_offset, record.hdr.length, record.file_data_offset, true);
+        }
+        self.file_size = pos;
+        Ok(())
+    }
+
+    fn keeps_records(&self) -> bool {
+        self.opts.paranoid || self.opts.skip_checksum
+    }
+
+    /// The whole record holding this (unvalidated) span: spans only get
+    /// split from their records if we're keeping track of them.
+    fn record_of(&self, span: &Span) -> (u64, u64) {
+        self.records
+            .range(..=span.file_data_offset)
+            .next_back()
+            .map(|(&off, &len)| (off, len))
+            .unwrap_or((span.file_data_offset, span.len))
+    }
+
+    /// Replay didn't get a record, because `why`.
+    fn replay_stopped(&mut self, why: LogEnd) {
+        if why != LogEnd::Eof {
+            debug_log!("{}: replay stopped at {}: {:?}", self.path.display(), self.file_size, why);
+        }
+        if why == LogEnd::BadChecksum {
+            self.metrics.checksum_failures += 1;
+        }
+        self.replay_stats.stopped_reason = match why {
+            LogEnd::Eof => ReplayStop::EndOfFile,
+            LogEnd::Truncated | LogEnd::TooLong => ReplayStop::Truncated,
+            LogEnd::BadMarker | LogEnd::BadChecksum | LogEnd::BadOffset => ReplayStop::Corrupt,
+        };
+    }
+
+    /// Replay whatever max_replay_records left.
+    fn replay_rest(&mut self) -> Result<(), Error> {
+        if self.replay_limited {
+            self.file.seek(SeekFrom::Start(self.file_size))?;
+            replay_records(self, None)?;
+        }
+        Ok(())
+    }
+
+    /// Called after each write: sync if the policy says so.
+    fn maybe_sync(&mut self) -> Result<(), Error> {
+        self.writes_since_sync += 1;
+        let due = match self.opts.sync_policy {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(n) => self.writes_since_sync >= n,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if due {
+            self.file.sync_data()?;
+            self.writes_since_sync = 0;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
 }
 
+#[derive(Clone)]
 pub(crate) struct Span {
-    /// How long is the data in this span (in practice, less than MAX_RECORD_SIZE).
+    /// How long is the data in this span.
     pub len: u64,
     /// Where the physical file is the span data (i.e. after header).
     pub file_data_offset: u64,
     /// Did we freshly write this span?  If so, ZFS on Ubuntu (at least) may fart back zeroes
-    /// at us: we need to recheck this and fdatasync if we see this.  Thanks Obama!
+    /// at us: we need to recheck this and fdatasync if we see this (see RetryPolicy).
+    /// Thanks Obama!
     pub validated: bool,
 }
 
 /// Parse header of new file, load up records.
-fn read_newfile(base: &mut StoreBase, compatible: fn(&header::HeaderVer) -> bool) -> Result<(), Error>
+fn read_newfile(base: &mut StoreBase,
+                compatible: fn(&header::HeaderVer) -> bool,
+                limit: Option<u64>) -> Result<(), Error>
 {
-    let hver = header::read_header(&mut base.file, &mut base.file_size)?;
+    let hdr = header::read_header(&mut base.file, &mut base.file_size)?;
 
-    if !compatible(&hver) {
+    if !compatible(&hdr.ver) {
         return Err(Error::UnsupportedVersion);
     }
+    base.header_len = base.file_size;
+    base.generation = hdr.generation;
+    base.format = hdr.ver.record_format();
+    base.ver = hdr.ver;
+    base.fields = hdr.fields;
+    replay_records(base, limit)
+}
+
+/// Replay records from file_size (where the file is positioned), stopping
+/// after `limit` of them.
+fn replay_records(base: &mut StoreBase, limit: Option<u64>) -> Result<(), Error> {
+    let mut replayed = 0;
+    base.replay_limited = false;
+    loop {
+        loop {
+            if limit.is_some_and(|limit| replayed >= limit) {
+                base.replay_limited = base.file_size < base.file.get_ref().metadata()?.len();
+                if base.replay_limited {
+                    base.replay_stats.stopped_reason = ReplayStop::Limit;
+                }
+                return Ok(());
+            }
+            let start = base.file_size;
+            match record::read_next_record(&mut base.file, base.format, &mut base.file_size,
+                                           !base.opts.skip_checksum)? {
+                Ok(record) => {
+                    base.record_starts.push(start);
+                    base.add_record(record.hdr.logical_offset,
+                                    record.hdr.length,
+                                    record.file_data_offset,
+                                    !base.opts.skip_checksum);
+                    replayed += 1;
+                    base.replay_stats.accepted += 1;
+                }
+                Err(why) => {
+                    base.replay_stopped(why);
+                    break;
+                }
+            }
+        }
 
-    while let Some(record) = record::read_next_record(&mut base.file, &mut base.file_size)? {
-        record::add_record(&mut base.spans,
-                           record.hdr.logical_offset,
-                           record.hdr.length,
-                           record.file_data_offset, true);
+        if !base.opts.resync || base.format == RecordFormat::Legacy {
+            return Ok(());
+        }
+
+        // Look for a valid record somewhere after this one.
+        let mut from = base.file_size + 1;
+        let record = loop {
+            let Some(start) = record::find_sync(&mut base.file, from)? else {
+                return Ok(());
+            };
+            base.file.seek(SeekFrom::Start(start))?;
+            let mut end = start;
+            match record::read_next_record(&mut base.file, base.format, &mut end, true)? {
+                Ok(record) => {
+                    base.file_size = end;
+                    base.record_starts.push(start);
+                    break record;
+                }
+                Err(why) => base.replay_stopped(why),
+            }
+            from = start + 1;
+        };
+        base.add_record(record.hdr.logical_offset,
+                        record.hdr.length,
+                        record.file_data_offset, true);
+        replayed += 1;
+        base.replay_stats.accepted += 1;
+        debug_log!("{}: resync found a record at {}", base.path.display(), record.file_data_offset);
+        base.resynced = true;
     }
-    Ok(())
 }
 
 /// Opens an existing syncless store readonly.
@@ -61,6 +681,11 @@ fn read_newfile(base: &mut StoreBase, compatible: fn(&header::HeaderVer) -> bool
 /// On success, the returned [`Store`] represents a logically consistent
 /// view reconstructed from the on-disk log.
 ///
+/// The file is opened for reading only, and a readonly store never writes
+/// to it or syncs it (not even to recheck a bad record, as writable
+/// stores do: see [`RetryPolicy`]), so this works on a read-only
+/// filesystem, or without write permission.
+///
 /// # Errors
 ///
 /// Returns an error if the file cannot be opened (using the
@@ -69,27 +694,72 @@ fn read_newfile(base: &mut StoreBase, compatible: fn(&header::HeaderVer) -> bool
 pub fn open_readonly<P: AsRef<Path>>(
     path: P,
 ) -> Result<Store<ReadOnly>, Error> {
+    StoreOptions::new().open_readonly(path)
+}
+
+/// Opens an existing syncless store readonly, like [`open_readonly`], but
+/// skipping over damaged records instead of stopping at the first one.
+///
+/// This recovers writes which were made after the damage, at the cost of
+/// ordering: you may see a write without one which preceded it.  Only
+/// files with major version 1 or above (see [`Store::file_version`]) have
+/// the markers which make this possible; for older files it is the same
+/// as [`open_readonly`].
+///
+/// # Errors
+///
+/// As for [`open_readonly`].
+pub fn open_readonly_resync<P: AsRef<Path>>(
+    path: P,
+) -> Result<Store<ReadOnly>, Error> {
+    StoreOptions::new().resync(true).open_readonly(path)
+}
+
+/// Opens an existing syncless store readonly, like [`open_readonly`], but
+/// checking every record a read touches, every time.
+///
+/// Normally a record is checked once (when the store is opened, or on
+/// first read after it was written), and trusted after that.  This
+/// catches damage which happens later, but each read costs reading and
+/// hashing every record it touches, in full: a small read from a large
+/// record is expensive.
+///
+/// # Errors
+///
+/// As for [`open_readonly`].
+pub fn open_readonly_paranoid<P: AsRef<Path>>(
+    path: P,
+) -> Result<Store<ReadOnly>, Error> {
+    StoreOptions::new().paranoid(true).open_readonly(path)
+}
+
+fn open_readonly_base<P: AsRef<Path>>(
+    path: P,
+    mut opts: Options,
+) -> Result<Store<ReadOnly>, Error> {
+    // Syncing only helps records we just wrote, and we never sync.
+    opts.retry_policy.retries = 0;
     let path = path.as_ref().to_path_buf();
     let mut oo = std::fs::OpenOptions::new();
     oo.read(true);
 
     let file = oo.open(&path)?;
+    let mut base = StoreBase::new(path, file, opts);
 
-    let mut base = StoreBase {
-        path: path,
-        file: file,
-        spans: BTreeMap::new(),
-        file_size: 0,
-    };
-
-    read_newfile(&mut base, header::HeaderVer::is_read_compatible)?;
+    let limit = base.opts.max_replay_records;
+    read_newfile(&mut base, header::HeaderVer::is_read_compatible, limit)?;
+    // Readonly stores don't append, so don't need to know where the
+    // cursor is, and this way try_clone can share it.
+    base.file.set_shared();
     Ok(Store {base, writable: false, _mode: PhantomData })
 }
 
 pub(crate) fn open_writable_base<P: AsRef<Path>>(
     path: P,
     mode: WriteOpenMode,
+    opts: Options,
 ) -> Result<StoreBase, Error> {
+    opts.check_metadata()?;
     let path = path.as_ref().to_path_buf();
     let mut oo = std::fs::OpenOptions::new();
     oo.read(true);
@@ -97,29 +767,101 @@ pub(crate) fn open_writable_base<P: AsRef<Path>>(
 
     match mode {
         WriteOpenMode::MustExist => { oo.create(false); }
-        WriteOpenMode::MustNotExist => { oo.create_new(true); }
+        WriteOpenMode::MustNotExist => {
+            // A fresh temporary file, linked into place below.
+            oo.create_new(true);
+        }
         WriteOpenMode::MayExist => { oo.create(true); }
     }
 
-    let file = oo.open(&path)?;
+    #[cfg(unix)]
+    if let Some(unix_mode) = opts.unix_mode {
+        use std::os::unix::fs::OpenOptionsExt;
+        oo.mode(unix_mode);
+    }
 
-    let mut base = StoreBase {
-        path: path,
-        file: file,
-        spans: BTreeMap::new(),
-        file_size: 0,
-    };
+    if opts.create_dirs
+        && !matches!(mode, WriteOpenMode::MustExist)
+        && let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    if mode == WriteOpenMode::MustNotExist {
+        return create_new_base(path, &oo, opts);
+    }
+
+    let file = oo.open(&path)?;
+    let mut base = StoreBase::new(path, file, opts);
 
     // Special case: empty file, we write header.
-    if base.file.metadata()?.len() == 0 {
-        base.file_size = header::write_header(&mut base.file)?;
+    if base.file.get_ref().metadata()?.len() == 0 {
+        base.generation = Some(1);
+        base.created = true;
+        base.file_size = header::write_header(&mut base.file, 1, base.format, &base.fields)?;
         base.file.sync_all()?;
     } else {
-        read_newfile(&mut base, header::HeaderVer::is_write_compatible)?;
+        read_newfile(&mut base, header::HeaderVer::is_write_compatible, None)?;
+        if base.fields.sealed {
+            return Err(Error::Sealed);
+        }
+
+        // Rewrite without the damage, otherwise a later open without
+        // resync would stop there (and append over what we recovered!).
+        if base.resynced {
+            base = compact(&mut base)?;
+        }
+        if base.opts.repair_tail && base.file.get_ref().metadata()?.len() > base.file_size {
+            base.file.get_ref().set_len(base.file_size)?;
+            base.file.sync_all()?;
+        }
+        if let Some(generation) = base.generation {
+            // Sync, so this can't be reordered after writes which follow.
+            header::write_generation(&mut base.file, generation + 1)?;
+            base.file.sync_data()?;
+            base.generation = Some(generation + 1);
+        }
     }
     Ok(base)
 }
 
+/// Creates a new store without ever leaving a partial file at `path`.
+///
+/// We write and sync the header in a temporary file, then hard link it
+/// into place (which, unlike rename, fails if `path` exists).
+fn create_new_base(path: PathBuf, oo: &std::fs::OpenOptions, opts: Options)
+                   -> Result<StoreBase, Error> {
+    let (tmp, mut file) = create_temp(&path, oo)?;
+    let fields = header::HeaderFields::new_store(opts.hole_fill, opts.metadata.clone());
+    let linked = header::write_header(&mut file, 1, opts.new_format(), &fields)
+        .and_then(|len| { file.sync_all()?; Ok(len) })
+        .and_then(|len| { std::fs::hard_link(&tmp, &path)?; Ok(len) });
+    // Whether or not that worked, we're done with this name.
+    let _ = std::fs::remove_file(&tmp);
+    let file_size = linked?;
+
+    // Make sure the new name hits disk before anything written to it.
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+
+    // Our file handle is the same file, so keep using it.
+    let mut base = StoreBase::new(path, file, opts);
+    base.generation = Some(1);
+    base.fields = fields;
+    base.created = true;
+    base.file_size = file_size;
+    Ok(base)
+}
+
+/// Creates a file next to `path`, named after it with a random suffix
+/// (so concurrent creates, or unrelated files, never clash), using `oo`,
+/// which must say create_new.
+fn create_temp(path: &Path, oo: &std::fs::OpenOptions) -> Result<(PathBuf, File), Error> {
+    let name = path.file_name().ok_or(std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    Ok(sys::create_randomly_named(&path.with_file_name(name), ".create", oo)?)
+}
 
 /// Opens an existing syncless store for reading and writing.
 ///
@@ -130,32 +872,145 @@ pub(crate) fn open_writable_base<P: AsRef<Path>>(
 ///
 /// Returns an error if the file cannot be opened for writing (using the
 /// underlying OS error), is not a valid syncless store, or is a
-/// future incompatible version.
+/// future incompatible version, and [`Error::Sealed`] if it has been
+/// sealed (see [`Store::seal`]).
 pub fn open<P: AsRef<Path>>(
     path: P,
     mode: WriteOpenMode,
 ) -> Result<Store<Writable>, Error> {
-    Ok(Store {base: open_writable_base::<P>(path, mode)?,
-              writable: true,
-              _mode: PhantomData})
+    StoreOptions::new().write_mode(mode).open(path)
+}
+
+/// Opens a syncless store for reading and writing, like [`open`], but
+/// syncing according to `policy` after writes.
+///
+/// # Errors
+///
+/// As for [`open`].
+pub fn open_with_sync_policy<P: AsRef<Path>>(
+    path: P,
+    mode: WriteOpenMode,
+    policy: SyncPolicy,
+) -> Result<Store<Writable>, Error> {
+    StoreOptions::new().write_mode(mode).sync_policy(policy).open(path)
+}
+
+/// Opens a syncless store for reading and writing, like [`open`], but
+/// skipping over damaged records instead of stopping at the first one.
+///
+/// As with [`open_readonly_resync`], this gives up ordering to recover
+/// later writes.  If any damage was skipped, the file is rewritten
+/// without it (as compaction does).
+///
+/// # Errors
+///
+/// As for [`open`].
+pub fn open_resync<P: AsRef<Path>>(
+    path: P,
+    mode: WriteOpenMode,
+) -> Result<Store<Writable>, Error> {
+    StoreOptions::new().write_mode(mode).resync(true).open(path)
+}
+
+/// Opens a syncless store for reading and writing, like [`open`], but
+/// using `policy` to decide how hard to try before deciding a record we
+/// wrote is corrupt.
+///
+/// # Errors
+///
+/// As for [`open`].
+pub fn open_with_retry_policy<P: AsRef<Path>>(
+    path: P,
+    mode: WriteOpenMode,
+    policy: RetryPolicy,
+) -> Result<Store<Writable>, Error> {
+    StoreOptions::new().write_mode(mode).retry_policy(policy).open(path)
+}
+
+/// Opens a syncless store for reading and writing, like [`open`], but
+/// checking every record a read touches, every time.
+///
+/// See [`open_readonly_paranoid`] for the cost.
+///
+/// # Errors
+///
+/// As for [`open`].
+pub fn open_paranoid<P: AsRef<Path>>(
+    path: P,
+    mode: WriteOpenMode,
+) -> Result<Store<Writable>, Error> {
+    StoreOptions::new().write_mode(mode).paranoid(true).open(path)
+}
+
+/// Opens a syncless store for reading and writing, like [`open`], but
+/// first creating any missing parent directories (unless `mode` is
+/// [`WriteOpenMode::MustExist`]).
+///
+/// # Errors
+///
+/// As for [`open`], and an error if a directory can't be created.
+pub fn open_creating_dirs<P: AsRef<Path>>(
+    path: P,
+    mode: WriteOpenMode,
+) -> Result<Store<Writable>, Error> {
+    StoreOptions::new().write_mode(mode).create_dirs(true).open(path)
+}
+
+/// Opens a syncless store for reading and writing, like [`open`], but if
+/// the file is created, it gets Unix permissions `unix_mode` (e.g. 0o600,
+/// still subject to the umask).  On other platforms this is ignored.
+///
+/// # Errors
+///
+/// As for [`open`].
+pub fn open_with_permissions<P: AsRef<Path>>(
+    path: P,
+    mode: WriteOpenMode,
+    unix_mode: u32,
+) -> Result<Store<Writable>, Error> {
+    StoreOptions::new().write_mode(mode).unix_mode(unix_mode).open(path)
+}
+
+/// Creates a new store with no path, which vanishes when it's dropped.
+///
+/// This is for scratch data which still wants atomic, ordered writes.  On
+/// Linux it lives in memory (via `memfd_create`), elsewhere in an unlinked
+/// temporary file.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be created (using the underlying
+/// OS error).
+pub fn open_anonymous() -> Result<Store<Writable>, Error> {
+    StoreOptions::new().open_anonymous()
 }
 
-fn validate_record_with_retry(
-    file: &mut File,
+fn validate_record_with_retry<S: Storage>(
+    file: &mut S,
+    format: RecordFormat,
+    policy: &RetryPolicy,
     file_data_offset: u64,
     length: u64,
 ) -> Result<(), Error> {
-    if record::validate(file, file_data_offset, length as usize)? {
+    let data_length = record::to_usize(length)?;
+    if record::validate(file, format, file_data_offset, data_length)? {
         return Ok(());
     }
 
-    file.sync_data()?;
+    for attempt in 1..=policy.retries {
+        debug_log!("record at {} invalid, syncing and retrying ({}/{})",
+                   file_data_offset, attempt, policy.retries);
+        match policy.sync {
+            RetrySync::Data => file.sync_data()?,
+            RetrySync::All => file.sync_all()?,
+        }
 
-    if record::validate(file, file_data_offset, length as usize)? {
-        return Ok(());
+        if record::validate(file, format, file_data_offset, data_length)? {
+            return Ok(());
+        }
     }
 
-    Err(Error::CorruptRecord)
+    Err(Error::CorruptRecord { file_offset: file_data_offset, expected_len: length })
 }
 
 impl<M> Store<M>
@@ -168,254 +1023,4339 @@ impl<M> Store<M>
         self.base.size()
     }
 
-    /// Get offset of prior record (or 0)
-    fn prev_offset(&self, offset: u64) -> u64 {
-        self.base.spans
-            .range((Included(0), Excluded(offset)))
-            .next_back()
-            .map(|(&off, _)| off)
-            .unwrap_or(0)
+    /// Returns the logical size of the store in bytes (same as size()).
+    pub fn len(&self) -> u64 {
+        self.size()
     }
 
-    /// Validate any spans in this range not already validated.
-    fn validate_range(&mut self, start: u64, end: u64) -> Result<(), Error> {
-        if !self.writable {
-            return Ok(());
-        }
+    /// Returns true if nothing has been written to the store.
+    pub fn is_empty(&self) -> bool {
+        self.size() == 0
+    }
+
+    /// Returns true if the byte at `offset` has been written (isn't a hole).
+    pub fn has_data(&self, offset: u64) -> bool {
+        self.base.spans_in(offset, offset + 1).next().is_some()
+    }
+
+    /// Returns the first written offset at or after `offset` (which is
+    /// `offset` itself if that has been written), or None if there's only
+    /// holes from there to the end.
+    pub fn next_data_after(&self, offset: u64) -> Option<u64> {
+        self.base.spans_in(offset, self.size()).next().map(|(off, _, _)| off)
+    }
+
+    /// Returns the first hole at or after `offset` (which is `offset`
+    /// itself if that is a hole), or None if everything from there to the
+    /// end has been written.
+    pub fn next_hole_after(&self, offset: u64) -> Option<u64> {
+        self.first_hole(offset, self.size()).map(|(off, _)| off)
+    }
+
+    /// Tells the OS we're about to read `len` bytes at `offset`, so it can
+    /// start reading the parts of the file they're in now.
+    ///
+    /// This is only a hint (`posix_fadvise(POSIX_FADV_WILLNEED)`): it does
+    /// nothing where that isn't supported.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the OS rejects the hint.
+    pub fn prefetch(&self, offset: u64, len: u64) -> Result<(), Error> {
+        for (file_off, file_len) in self.base.file_ranges(offset, offset.saturating_add(len)) {
+            sys::fadvise(self.base.file.get_ref(), file_off, file_len, sys::Advice::WillNeed)?;
+        }
+        Ok(())
+    }
+
+    /// Tells the OS we won't read `len` bytes at `offset` again soon, so
+    /// it can drop the parts of the file they're in from its cache (say,
+    /// after reading the whole store once).
+    ///
+    /// Like [`Store::prefetch`], this is only a hint
+    /// (`posix_fadvise(POSIX_FADV_DONTNEED)`), and does nothing where that
+    /// isn't supported.  Recent writes which haven't reached the disk yet
+    /// stay cached.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the OS rejects the hint.
+    pub fn advise_dontneed(&self, offset: u64, len: u64) -> Result<(), Error> {
+        for (file_off, file_len) in self.base.file_ranges(offset, offset.saturating_add(len)) {
+            sys::fadvise(self.base.file.get_ref(), file_off, file_len, sys::Advice::DontNeed)?;
+        }
+        Ok(())
+    }
+
+    /// Returns where in the file the byte at logical offset `logical` is
+    /// stored, or None if it's a hole (or past the end).
+    pub fn physical_offset(&self, logical: u64) -> Option<u64> {
+        self.base.spans_in(logical, logical.saturating_add(1)).next().map(|(_, _, file_off)| file_off)
+    }
+
+    /// Returns every hole in `[start, end)` as `(offset, len)`, in order.
+    ///
+    /// `end` is clamped to [`Store::size`]: past that isn't a hole, it's
+    /// just the end.
+    pub fn list_holes(&self, start: u64, end: u64) -> Vec<(u64, u64)> {
+        let end = end.min(self.size());
+        let mut holes = Vec::new();
+        let mut pos = start;
+
+        for (off, len, _) in self.base.spans_in(start, end) {
+            if off > pos {
+                holes.push((pos, off - pos));
+            }
+            pos = off + len;
+        }
+        if pos < end {
+            holes.push((pos, end - pos));
+        }
+        holes
+    }
+
+    /// Returns the generation counter of the store.
+    ///
+    /// This is incremented (and synced to disk) each time the store is
+    /// opened writable, so you can tell if someone else may have changed
+    /// it since you last looked.  Files created by syncless 0.1 don't have
+    /// one, and always return 0 (until compaction rewrites them).
+    pub fn generation(&self) -> u64 {
+        self.base.generation.unwrap_or(0)
+    }
+
+    /// Returns true if this open created a new store, rather than opening
+    /// an existing one.
+    ///
+    /// An existing but empty file counts as new, as it gets a fresh header
+    /// just like one we created.  This is always false for readonly opens.
+    pub fn was_created(&self) -> bool {
+        self.base.created
+    }
+
+    /// Returns what holes (and reads past the end) read as: zero, unless
+    /// the store was created with [`StoreOptions::hole_fill`].
+    pub fn hole_fill(&self) -> u8 {
+        self.base.fields.hole_fill
+    }
+
+    /// Returns the store's id: 16 random bytes chosen when it was created.
+    ///
+    /// This tells stores apart (a copy of the file has the same id, which
+    /// tells you it's a copy).  Files from syncless 0.1 (major version 0)
+    /// have an id of all zeros.
+    pub fn id(&self) -> [u8; 16] {
+        self.base.fields.id
+    }
+
+    /// Returns when the store was created (to the second), as recorded in
+    /// its header, or None for files from syncless 0.1 (major version 0).
+    ///
+    /// Unlike the file's mtime, copying the file doesn't change this.
+    pub fn created_at(&self) -> Option<SystemTime> {
+        match self.base.fields.created_at {
+            0 => None,
+            secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    /// Returns the metadata saved under `tag` when the store was created
+    /// (see [`StoreOptions::metadata`]), if any.
+    pub fn metadata(&self, tag: u8) -> Option<&[u8]> {
+        self.base.fields.ext_value(tag)
+    }
+
+    /// Returns the file format version from the header, as
+    /// `major.format.minor`, for display.
+    pub fn file_version(&self) -> String {
+        self.base.ver.to_string()
+    }
+
+    /// Returns how many bytes of the file are in use: the header and
+    /// every record (the file itself may be longer, if a write was torn).
+    pub fn file_len(&self) -> u64 {
+        self.base.file_size
+    }
+
+    /// Turns this into a [`StoreReader`], to read the contents from the
+    /// start like a file.
+    pub fn into_reader(self) -> StoreReader<M> {
+        StoreReader { store: self, pos: 0, buf: vec![0; READER_BUF_SIZE].into_boxed_slice(), filled: 0, consumed: 0 }
+    }
+
+    /// Returns the highest id passed to [`Store::checkpoint`] in this
+    /// store's records, or None if there are no checkpoints.
+    ///
+    /// With [`StoreOptions::max_replay_records`], this only covers the
+    /// records replayed so far (see [`Store::finish_replay`]).
+    pub fn last_checkpoint(&self) -> Option<u64> {
+        self.base.last_checkpoint
+    }
+
+    /// Returns false if [`StoreOptions::max_replay_records`] stopped
+    /// replay on open, and nothing has needed the rest yet.
+    pub fn fully_loaded(&self) -> bool {
+        !self.base.replay_limited
+    }
+
+    /// Replays any records [`StoreOptions::max_replay_records`] left, so
+    /// the store is [`Store::fully_loaded`].
+    ///
+    /// Methods which need the rest (including [`Store::into_shared`]) do
+    /// this themselves.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error on underlying I/O error.
+    pub fn finish_replay(&mut self) -> Result<(), Error> {
+        self.base.replay_rest()
+    }
+
+    /// Returns true if every record has been checked, so
+    /// [`Store::into_readonly`] (and reads) won't need to check any more.
+    pub fn is_fully_validated(&self) -> bool {
+        self.base.spans.values().all(|span| span.validated)
+    }
+
+    /// Returns how many records replay accepted when the store was
+    /// opened, and why it stopped: a file which ends cleanly, rather than
+    /// part way through a record, was (probably) not being written when
+    /// the last writer stopped.
+    ///
+    /// With [`open_resync`], the count includes records found after
+    /// damage, and the reason is for the last stop.  With
+    /// [`StoreOptions::max_replay_records`], replaying the rest later
+    /// updates this.
+    pub fn replay_stats(&self) -> ReplayStats {
+        self.base.replay_stats
+    }
+
+    /// Returns counts of the work done since the store was opened.
+    pub fn metrics(&self) -> Metrics {
+        self.base.metrics
+    }
+
+    /// Calls `f` with `(logical offset, length, file data offset)` for each
+    /// span of written data, in order, stopping at the first error.
+    ///
+    /// This describes where the current data lives in the file, without
+    /// reading it (or allocating).
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error `f` returns.
+    pub fn for_each_span<F>(&self, mut f: F) -> Result<(), Error>
+    where F: FnMut(u64, u64, u64) -> Result<(), Error> {
+        for (&off, span) in &self.base.spans {
+            f(off, span.len, span.file_data_offset)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a human-readable description of the file and where each
+    /// span of data lives in it, for debugging.
+    ///
+    /// The format is not stable: don't parse it.
+    pub fn describe_layout(&self) -> String {
+        use std::fmt::Write;
+
+        let mut out = String::new();
+        let _ = writeln!(out, "{}: version {}, generation {}, {} bytes of file, {} bytes of data in {} spans",
+                         self.base.path.display(), self.base.ver, self.generation(),
+                         self.base.file_size, self.size(), self.base.spans.len());
+        for (&off, span) in &self.base.spans {
+            let _ = writeln!(out, "  logical [{}..{}) -> file {}, validated={}",
+                             off, off + span.len, span.file_data_offset, span.validated);
+        }
+        out
+    }
+
+    /// Returns how many spans of data there are of each size, bucketed by
+    /// powers of two: `(bucket, count)` means `count` spans were at least
+    /// `bucket` bytes long, but less than twice that.
+    ///
+    /// Only non-empty buckets are returned, smallest first.  A span is a
+    /// record, or the part of one which hasn't been overwritten.
+    pub fn size_histogram(&self) -> Vec<(u64, u64)> {
+        let mut counts = [0u64; 64];
+        for span in self.base.spans.values() {
+            counts[span.len.ilog2() as usize] += 1;
+        }
+        counts.iter()
+            .enumerate()
+            .filter(|&(_, &count)| count != 0)
+            .map(|(bit, &count)| (1 << bit, count))
+            .collect()
+    }
+
+    /// Returns a rough estimate of the memory used by the in-memory index.
+    ///
+    /// This grows with the number of separate pieces of data in the store
+    /// (so with scattered overwrites), and with the number of records
+    /// (even ones entirely overwritten), until compaction squashes them.
+    pub fn index_memory_bytes(&self) -> usize {
+        // B-tree nodes are typically around two-thirds full.
+        let entry = size_of::<(u64, Span)>();
+        let record = size_of::<(u64, u64)>();
+        let trees = (self.base.spans.len() * entry + self.base.records.len() * record) * 3 / 2;
+        trees
+            + self.base.record_starts.len() * size_of::<u64>()
+            + self.base.unvalidated.len() * size_of::<(u64, u64)>()
+    }
+
+    /// Get offset of prior record (or 0)
+    fn prev_offset(&self, offset: u64) -> u64 {
+        self.base.spans
+            .range((Included(0), Excluded(offset)))
+            .next_back()
+            .map(|(&off, _)| off)
+            .unwrap_or(0)
+    }
+
+    /// Find the first uncovered region in [start, end), if any.
+    fn first_hole(&self, start: u64, end: u64) -> Option<(u64, u64)> {
+        let mut pos = start;
+
+        for (off, len, _) in self.base.spans_in(start, end) {
+            if off > pos {
+                return Some((pos, off - pos));
+            }
+            pos = off + len;
+        }
+
+        if pos < end {
+            Some((pos, end - pos))
+        } else {
+            None
+        }
+    }
+
+    /// Validate any spans starting in this range not already validated.
+    fn validate_spans(&mut self, start: u64, end: u64) -> Result<(), Error> {
+        // Readonly stores checked everything on open (unless told not to).
+        if !self.writable && !self.base.opts.skip_checksum {
+            return Ok(());
+        }
+
+        // Usually (from a write) there's at most one record to check:
+        // do that without the allocations below.
+        let mut unvalidated = self.base.spans
+            .range((Included(start), Excluded(end)))
+            .filter(|(_, span)| !span.validated);
+        match (unvalidated.next(), unvalidated.next()) {
+            (None, _) => return Ok(()),
+            (Some((&off, span)), None) => {
+                let (file_data_offset, length) = self.base.record_of(span);
+                validate_record_with_retry(&mut self.base.file, self.base.format, &self.base.opts.retry_policy,
+                                           file_data_offset, length)?;
+                self.base.metrics.records_validated += 1;
+                self.base.spans.get_mut(&off).unwrap().validated = true;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let to_validate: Vec<(u64, u64, u64)> = self.base.spans
+            .range((Included(start), Excluded(end)))
+            .filter_map(|(&off, span)| {
+                if span.validated {
+                    None
+                } else {
+                    let (file_data_offset, length) = self.base.record_of(span);
+                    Some((off, file_data_offset, length))
+                }
+            })
+            .collect();
+
+        // Validate them all (several spans may share a record).
+        let mut records: Vec<(u64, u64)> = to_validate.iter().map(|&(_, off, len)| (off, len)).collect();
+        records.sort_unstable();
+        records.dedup();
+        let records_checked = records.len() as u64;
+
+        // Check them in parallel first: anything which fails (or can't be
+        // read) gets retried one at a time below, as usual.
+        #[cfg(feature = "rayon")]
+        if records.len() > 1 {
+            use rayon::prelude::*;
+
+            let file = self.base.file.get_ref();
+            let format = self.base.format;
+            records = records
+                .into_par_iter()
+                .filter(|&(off, len)| {
+                    !record::to_usize(len)
+                        .and_then(|len| record::validate_at(file, format, off, len))
+                        .unwrap_or(false)
+                })
+                .collect();
+            // Positioned reads may move the cursor (e.g. on Windows).
+            self.base.file.forget_position();
+        }
+
+        for &(file_data_offset, length) in &records {
+            validate_record_with_retry(&mut self.base.file, self.base.format, &self.base.opts.retry_policy,
+                                       file_data_offset, length)?;
+        }
+
+        // Set them all valid.
+        self.base.metrics.records_validated += records_checked;
+        for &(off, _, _) in &to_validate {
+            let span = self.base.spans.get_mut(&off).unwrap();
+            span.validated = true;
+        }
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes starting at `offset`.
+    ///
+    /// The read is performed against the reconstructed logical view of the
+    /// store.  If there's a hole, or past EOF, it will read as all zeros
+    /// (or the [`Store::hole_fill`] byte).
+    ///
+    /// # Errors
+    ///
+    /// Return zeros past the logical size of the store (see size()).
+    /// Returns [`Error::OutOfRange`] if the read would end past the
+    /// largest offset, and an error on underlying I/O error.
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.read_with(offset, buf, None)
+    }
+
+    /// Reads `buf.len()` bytes starting at `offset`, like [`Store::read`],
+    /// but gives up if `cancel` is set.
+    ///
+    /// The flag is checked before reading each part of the file, so a
+    /// read from slow (say, network) storage which covers many records
+    /// can be abandoned part way, though a single read already waiting on
+    /// the storage can't be.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Cancelled`] if `cancel` was set (the contents of
+    /// `buf` are then unspecified), otherwise as for [`Store::read`].
+    pub fn read_cancellable(&mut self, offset: u64, buf: &mut [u8], cancel: &AtomicBool)
+                            -> Result<(), Error> {
+        self.read_with(offset, buf, Some(cancel))
+    }
+
+    fn read_with(&mut self, offset: u64, buf: &mut [u8], cancel: Option<&AtomicBool>) -> Result<(), Error> {
+        if cancel.is_some_and(|cancel| cancel.load(Ordering::Relaxed)) {
+            return Err(Error::Cancelled);
+        }
+        let end = offset.checked_add(buf.len() as u64).ok_or(Error::OutOfRange)?;
+        let mut runs = std::mem::take(&mut self.base.read_runs);
+        let res = self.validated_runs(offset, end, &mut runs)
+            .and_then(|()| self.read_runs(offset, buf, &runs, cancel));
+        self.base.keep_read_buffers(runs);
+        res
+    }
+
+    /// Fill `buf` (from logical `offset`) by reading `runs`.
+    fn read_runs(&mut self, offset: u64, buf: &mut [u8], runs: &[(u64, u64, u64)], cancel: Option<&AtomicBool>)
+                 -> Result<(), Error> {
+        let check = || match cancel {
+            Some(cancel) if cancel.load(Ordering::Relaxed) => Err(Error::Cancelled),
+            _ => Ok(()),
+        };
+        let fill = self.base.fields.hole_fill;
+        let gap = self.base.opts.read_coalesce_gap;
+
+        // Holes are zeros: only zero what we don't read.
+        let mut pos = 0;
+        let mut i = 0;
+        while i < runs.len() {
+            // Which of the following runs are close enough in the file?
+            let mut n = 1;
+            while let Some(next) = runs.get(i + n) {
+                let prev_end = runs[i + n - 1].2 + runs[i + n - 1].1;
+                if next.2 < prev_end || next.2 - prev_end > gap {
+                    break;
+                }
+                n += 1;
+            }
+
+            check()?;
+            // FIXME: mmap
+            let (off, len, file_off) = runs[i];
+            let start = (off - offset) as usize;
+            buf[pos..start].fill(fill);
+            if n == 1 {
+                self.base.file.seek(SeekFrom::Start(file_off))?;
+                self.base.file.read_exact(&mut buf[start..start + len as usize])?;
+                pos = start + len as usize;
+            } else {
+                let (_, last_len, last_file_off) = runs[i + n - 1];
+                let bridged = &mut self.base.read_scratch;
+                bridged.resize(record::to_usize(last_file_off + last_len - file_off)?, 0);
+                self.base.file.seek(SeekFrom::Start(file_off))?;
+                self.base.file.read_exact(bridged)?;
+
+                // Only keep the runs: what's between them isn't ours.
+                for &(off, len, run_file_off) in &runs[i..i + n] {
+                    let start = (off - offset) as usize;
+                    let from = (run_file_off - file_off) as usize;
+                    buf[pos..start].fill(fill);
+                    buf[start..start + len as usize].copy_from_slice(&bridged[from..from + len as usize]);
+                    pos = start + len as usize;
+                }
+            }
+            i += n;
+        }
+        buf[pos..].fill(fill);
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes starting at `offset`, like [`Store::read`],
+    /// but into uninitialized memory: returns `buf`, now initialized.
+    ///
+    /// This saves zeroing a fresh buffer only to overwrite it.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::read`].
+    pub fn read_uninit<'a>(&mut self,
+                           offset: u64,
+                           buf: &'a mut [MaybeUninit<u8>]) -> Result<&'a mut [u8], Error> {
+        let end = offset.checked_add(buf.len() as u64).ok_or(Error::OutOfRange)?;
+        let mut runs = std::mem::take(&mut self.base.read_runs);
+        let res = self.validated_runs(offset, end, &mut runs).and_then(|()| {
+            let fill = MaybeUninit::new(self.base.fields.hole_fill);
+            let mut pos = 0;
+            for &(off, len, file_off) in &runs {
+                let start = (off - offset) as usize;
+                buf[pos..start].fill(fill);
+                sys::read_exact_at_uninit(&mut self.base.file, file_off, &mut buf[start..start + len as usize])?;
+                pos = start + len as usize;
+            }
+            buf[pos..].fill(fill);
+            Ok(())
+        });
+        self.base.keep_read_buffers(runs);
+        res?;
+
+        // SAFETY: every byte was either read or filled above.
+        Ok(unsafe { &mut *(buf as *mut [MaybeUninit<u8>] as *mut [u8]) })
+    }
+
+    /// Validates [start, end), and sets `runs` to the pieces of it which
+    /// aren't holes: spans which are also adjacent in the file are merged,
+    /// so they can be read at once.  (logical offset, length, file offset).
+    fn validated_runs(&mut self, start: u64, end: u64, runs: &mut Vec<(u64, u64, u64)>) -> Result<(), Error> {
+        self.base.replay_rest()?;
+        let prev = self.prev_offset(start);
+        self.validate_spans(prev, end)?;
+        self.base.metrics.reads += 1;
+
+        runs.clear();
+        for (off, len, file_off) in self.base.spans_in(start, end) {
+            if let Some(last) = runs.last_mut()
+                && last.0 + last.1 == off
+                && last.2 + last.1 == file_off {
+                last.1 += len;
+            } else {
+                runs.push((off, len, file_off));
+            }
+        }
+
+        if self.base.opts.paranoid {
+            self.recheck_records(runs)?;
+        }
+        Ok(())
+    }
+
+    /// Paranoid mode: check every record these runs read from.
+    fn recheck_records(&mut self, runs: &[(u64, u64, u64)]) -> Result<(), Error> {
+        let mut to_check: Vec<(u64, u64)> = Vec::new();
+        for &(_, len, file_off) in runs {
+            // The record containing the start, and any after it.
+            let first = self.base.records
+                .range(..=file_off)
+                .next_back()
+                .map(|(&off, _)| off)
+                .unwrap_or(file_off);
+            to_check.extend(self.base.records.range(first..file_off + len).map(|(&off, &len)| (off, len)));
+        }
+        to_check.sort_unstable();
+        to_check.dedup();
+
+        for (file_data_offset, length) in to_check {
+            validate_record_with_retry(&mut self.base.file, self.base.format, &self.base.opts.retry_policy,
+                                       file_data_offset, length)?;
+        }
+        Ok(())
+    }
+
+    /// Returns a checksum of the logical contents of the store (holes
+    /// read as zeros, or the [`Store::hole_fill`] byte).
+    ///
+    /// Two stores with the same contents give the same hash, however
+    /// they were written.  This reads the whole store, but a piece at a
+    /// time.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error on underlying I/O error.
+    pub fn content_hash(&mut self) -> Result<u64, Error> {
+        const CHUNK: usize = 64 * 1024;
+        self.base.replay_rest()?;
+        let size = self.size();
+        self.validate_spans(0, size)?;
+
+        let pieces: Vec<(u64, u64, u64)> = self.base.spans_in(0, size).collect();
+        let holes = vec![self.base.fields.hole_fill; CHUNK];
+        let mut buf = vec![0u8; CHUNK];
+        let mut d = checksum::Digest::new();
+        let mut pos = 0;
+        for (off, len, file_off) in pieces {
+            while pos < off {
+                let n = min(off - pos, CHUNK as u64) as usize;
+                d.write(&holes[..n]);
+                pos += n as u64;
+            }
+
+            self.base.file.seek(SeekFrom::Start(file_off))?;
+            while pos < off + len {
+                let n = min(off + len - pos, CHUNK as u64) as usize;
+                self.base.file.read_exact(&mut buf[..n])?;
+                d.write(&buf[..n]);
+                pos += n as u64;
+            }
+        }
+        Ok(d.sum64())
+    }
+
+    /// Returns a checksum of the file itself, up to the end of the last
+    /// record we read or wrote, to check two copies of a file are the
+    /// same (say, after copying it elsewhere).
+    ///
+    /// Unlike [`Store::content_hash`], this depends on how the store was
+    /// written, not just what it holds: stores with the same contents
+    /// usually have different fingerprints (as does the same file after
+    /// a writable open, which updates the header).  But it's cheaper, as
+    /// it just reads the file straight through.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error on underlying I/O error.
+    pub fn file_fingerprint(&mut self) -> Result<u64, Error> {
+        const CHUNK: usize = 64 * 1024;
+        self.base.replay_rest()?;
+        let mut buf = vec![0u8; CHUNK];
+        let mut d = checksum::Digest::new();
+        let mut pos = 0;
+
+        self.base.file.seek(SeekFrom::Start(0))?;
+        while pos < self.base.file_size {
+            let n = min(self.base.file_size - pos, CHUNK as u64) as usize;
+            self.base.file.read_exact(&mut buf[..n])?;
+            d.write(&buf[..n]);
+            pos += n as u64;
+        }
+        Ok(d.sum64())
+    }
+
+    /// Returns where each record starts in the file, in order: the
+    /// physical log behind [`Store::describe_layout`], for tools which
+    /// want to walk it record by record.
+    ///
+    /// These are the records replay found when the store was opened, plus
+    /// those written since (a compaction leaves one for each run of data
+    /// between holes).  With
+    /// [`open_resync`], damaged records skipped over aren't included.
+    ///
+    /// # Errors
+    ///
+    /// Currently never fails: this is kept as we go.
+    pub fn record_offsets(&mut self) -> Result<Vec<u64>, Error> {
+        self.base.replay_rest()?;
+        Ok(self.base.record_starts.clone())
+    }
+
+    /// Returns the contents of the store (and its header metadata), to
+    /// serialize: see [`Store::restore_serde`] to load it back.
+    ///
+    /// Adjacent spans are merged, so there's one entry for each run of
+    /// data between holes.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::read`].
+    #[cfg(feature = "serde")]
+    pub fn snapshot_serde(&mut self) -> Result<StoreSnapshot, Error> {
+        self.base.replay_rest()?;
+        let runs = self.base.runs();
+        let mut spans = Vec::with_capacity(runs.len());
+        for (off, len) in runs {
+            let mut data = vec![0; record::to_usize(len)?];
+            self.read(off, &mut data)?;
+            spans.push((off, len, data));
+        }
+        Ok(StoreSnapshot {
+            hole_fill: self.hole_fill(),
+            metadata: self.base.fields.ext.iter()
+                .filter(|(tag, _)| *tag >= header::FIRST_APP_TAG)
+                .cloned()
+                .collect(),
+            spans,
+        })
+    }
+
+    /// Writes the data (but not the holes) to `dst`, and returns where
+    /// each piece belongs, for backups of sparse stores.
+    ///
+    /// There's one region for each run of data between holes, written to
+    /// `dst` one after another in order, so the output is only as big as
+    /// the data.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::read`], and any error writing to `dst`.
+    pub fn export_sparse(&mut self, mut dst: impl Write) -> Result<SparseManifest, Error> {
+        const CHUNK: u64 = 64 * 1024;
+        self.base.replay_rest()?;
+        let regions = self.base.runs();
+        let mut buf = vec![0u8; min(CHUNK, self.size()) as usize];
+
+        for &(off, len) in &regions {
+            let mut done = 0;
+            while done < len {
+                let n = min(len - done, CHUNK) as usize;
+                self.read(off + done, &mut buf[..n])?;
+                dst.write_all(&buf[..n])?;
+                done += n as u64;
+            }
+        }
+        dst.flush()?;
+        Ok(SparseManifest { regions })
+    }
+
+    /// Checks every record in the file (even those since overwritten),
+    /// and that nothing follows the last one, returning the file offset
+    /// of the first problem, or None if there aren't any.
+    ///
+    /// Replay stops at the first bad record, so on a store that was
+    /// opened normally this only finds records which went bad since
+    /// they were read, or junk after the end (a crash while writing
+    /// leaves a partial record there, so that isn't always corruption).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error on underlying I/O error.
+    pub fn fsck(&mut self) -> Result<Option<u64>, Error> {
+        self.base.replay_rest()?;
+        let mut pos = self.base.header_len;
+        self.base.file.seek(SeekFrom::Start(pos))?;
+        while pos < self.base.file_size {
+            let start = pos;
+            if record::read_next_record(&mut self.base.file, self.base.format, &mut pos, true)?.is_err() {
+                return Ok(Some(start));
+            }
+        }
+        if self.base.file.get_ref().metadata()?.len() > self.base.file_size {
+            return Ok(Some(self.base.file_size));
+        }
+        Ok(None)
+    }
+
+    /// Returns the `(offset, len)` ranges where the logical contents of
+    /// this store and `other` differ, in order (holes read as they do in
+    /// [`Store::read`]).
+    ///
+    /// If one store is longer, everything past the end of the shorter one
+    /// is reported as different.  This reads both stores a piece at a
+    /// time, skipping regions which are holes in both.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error on underlying I/O error.
+    pub fn diff<M2>(&mut self, other: &mut Store<M2>) -> Result<Vec<(u64, u64)>, Error> {
+        const CHUNK: u64 = 64 * 1024;
+        self.base.replay_rest()?;
+        other.base.replay_rest()?;
+        let common = min(self.size(), other.size());
+        let mut a = vec![0u8; CHUNK as usize];
+        let mut b = vec![0u8; CHUNK as usize];
+        let mut diffs: Vec<(u64, u64)> = Vec::new();
+        let mut add = |off: u64, len: u64| {
+            if let Some(last) = diffs.last_mut()
+                && last.0 + last.1 == off {
+                last.1 += len;
+            } else {
+                diffs.push((off, len));
+            }
+        };
+
+        let mut pos = 0;
+        while pos < common {
+            // Holes in both are the same (unless they're filled differently).
+            if self.hole_fill() == other.hole_fill() {
+                pos = match (self.next_data_after(pos), other.next_data_after(pos)) {
+                    (None, None) => break,
+                    (x, y) => min(x.unwrap_or(u64::MAX), y.unwrap_or(u64::MAX)),
+                };
+                if pos >= common {
+                    break;
+                }
+            }
+
+            let n = min(common - pos, CHUNK) as usize;
+            self.read(pos, &mut a[..n])?;
+            other.read(pos, &mut b[..n])?;
+            let mut i = 0;
+            while i < n {
+                if a[i] == b[i] {
+                    i += 1;
+                    continue;
+                }
+                let start = i;
+                while i < n && a[i] != b[i] {
+                    i += 1;
+                }
+                add(pos + start as u64, (i - start) as u64);
+            }
+            pos += n as u64;
+        }
+
+        let end = max(self.size(), other.size());
+        if common < end {
+            add(common, end - common);
+        }
+        Ok(diffs)
+    }
+
+    /// Returns true if this store and `other` have the same logical
+    /// contents (holes read as they do in [`Store::read`]).
+    ///
+    /// Like [`Store::diff`], this reads both a piece at a time, but stops
+    /// at the first difference.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error on underlying I/O error.
+    pub fn contents_eq<M2>(&mut self, other: &mut Store<M2>) -> Result<bool, Error> {
+        const CHUNK: u64 = 64 * 1024;
+        self.base.replay_rest()?;
+        other.base.replay_rest()?;
+        let size = self.size();
+        if size != other.size() {
+            return Ok(false);
+        }
+        let mut a = vec![0u8; CHUNK as usize];
+        let mut b = vec![0u8; CHUNK as usize];
+
+        let mut pos = 0;
+        while pos < size {
+            // Holes in both are the same (unless they're filled differently).
+            if self.hole_fill() == other.hole_fill() {
+                pos = match (self.next_data_after(pos), other.next_data_after(pos)) {
+                    (None, None) => break,
+                    (x, y) => min(x.unwrap_or(u64::MAX), y.unwrap_or(u64::MAX)),
+                };
+                if pos >= size {
+                    break;
+                }
+            }
+
+            let n = min(size - pos, CHUNK) as usize;
+            self.read(pos, &mut a[..n])?;
+            other.read(pos, &mut b[..n])?;
+            if a[..n] != b[..n] {
+                return Ok(false);
+            }
+            pos += n as u64;
+        }
+        Ok(true)
+    }
+
+    /// Consumes the store, returning the underlying file.
+    ///
+    /// This does not validate recent writes: call into_readonly() first
+    /// if you want that.  The file position is unspecified.
+    pub fn into_inner(self) -> File {
+        self.into_parts().0
+    }
+
+    /// Consumes the store, returning the underlying file and the length
+    /// of the valid log within it (anything after that is junk, such as
+    /// a partially-written record).
+    ///
+    /// As with into_inner(), this does not validate recent writes.
+    pub fn into_parts(self) -> (File, u64) {
+        let base = self.into_base();
+        (base.file.into_inner(), base.file_size)
+    }
+
+    /// Take the StoreBase out, without running our Drop.
+    fn into_base(self) -> StoreBase {
+        let this = std::mem::ManuallyDrop::new(self);
+        // SAFETY: this is never used again (or dropped), and the other
+        // fields don't need dropping.
+        unsafe { std::ptr::read(&this.base) }
+    }
+
+    /// Reads `buf.len()` bytes starting at `offset`, like read(), but
+    /// insists that every byte has actually been written.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Hole`] describing the first unwritten region if
+    /// any byte in the range is a hole (or past the logical size), and an
+    /// error on underlying I/O error.
+    pub fn read_strict(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.base.replay_rest()?;
+        let end = offset.checked_add(buf.len() as u64).ok_or(Error::OutOfRange)?;
+        if let Some((offset, len)) = self.first_hole(offset, end) {
+            return Err(Error::Hole { offset, len });
+        }
+        self.read(offset, buf)
+    }
+
+    /// Reads `buf.len()` bytes starting at `offset`, like [`Store::read`],
+    /// and says which of them were actually written (the rest are holes,
+    /// or past the logical size).
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::read`].
+    pub fn read_mapped(&mut self, offset: u64, buf: &mut [u8]) -> Result<ReadCoverage, Error> {
+        self.read(offset, buf)?;
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for (off, len, _) in self.base.spans_in(offset, offset + buf.len() as u64) {
+            match ranges.last_mut() {
+                Some((start, run)) if *start + *run == off => *run += len,
+                _ => ranges.push((off, len)),
+            }
+        }
+        Ok(ReadCoverage { ranges })
+    }
+}
+
+#[cfg(unix)]
+impl<M> std::os::unix::io::AsRawFd for Store<M> {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.base.file.get_ref().as_raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl<M> std::os::unix::io::AsFd for Store<M> {
+    fn as_fd(&self) -> std::os::unix::io::BorrowedFd<'_> {
+        self.base.file.get_ref().as_fd()
+    }
+}
+
+fn compact(base: &mut StoreBase) -> Result<StoreBase, Error> {
+    debug_log!("{}: compacting {} bytes of file for {} bytes of data",
+               base.path.display(), base.file_size, base.size());
+
+    // Suck up all the data, a record for each run (holes stay holes).
+    let mut runs = Vec::new();
+    for (off, len) in base.runs() {
+        let mut data = vec![0; record::to_usize(len)?];
+        let pieces: Vec<_> = base.spans_in(off, off + len).collect();
+        for (piece_off, piece_len, file_off) in pieces {
+            let start = (piece_off - off) as usize;
+            base.file.seek(SeekFrom::Start(file_off))?;
+            base.file.read_exact(&mut data[start..start + piece_len as usize])?;
+        }
+        runs.push((off, data));
+    }
+
+    let fields = base.fields.clone();
+    let mut writes: Vec<(u64, &[u8])> = runs.iter().map(|(off, data)| (*off, data.as_slice())).collect();
+    // Keep the latest checkpoint (an empty record).
+    if let Some(id) = base.last_checkpoint {
+        writes.push((id, &[]));
+    }
+    rewrite(base, &fields, &writes)
+}
+
+/// Atomically replaces the file with one holding `fields` and a record for
+/// each of `writes`, as (logical offset, data).
+fn rewrite(base: &mut StoreBase, fields: &header::HeaderFields, writes: &[(u64, &[u8])])
+           -> Result<StoreBase, Error> {
+    let path = base.path.clone();
+    let tmp = path.with_extension("compact");
+
+    let mut file = if base.anonymous {
+        // Nobody else can see it, so we just swap to a new one.
+        sys::anonymous_file()?
+    } else {
+        // Fresh file: if we crashed before, overwrite.
+        let mut oo = std::fs::OpenOptions::new();
+        oo.write(true);
+        oo.create(true);
+        oo.truncate(true);
+
+        let file = oo.open(&tmp)?;
+        // Keep the permissions (e.g. from open_with_permissions).
+        file.set_permissions(base.file.get_ref().metadata()?.permissions())?;
+        file
+    };
+    // This upgrades 0.1 files, but keeps sampled checksums.
+    let format = if base.format == RecordFormat::Legacy { RecordFormat::CURRENT } else { base.format };
+    let mut file_len = header::write_header(&mut file, base.generation.unwrap_or(0), format, fields)?;
+
+    // Write it out, make sure it hit disk.
+    for &(offset, data) in writes {
+        record::write_record(&mut file, format, offset, data, &mut file_len)?;
+    }
+    let file = if base.anonymous {
+        file.seek(SeekFrom::Start(0))?;
+        file
+    } else {
+        file.sync_data()?;
+
+        // atomic replace
+        std::fs::rename(&tmp, &path)?;
+
+        // It's possible that the atomic replace is not actually atomic,
+        // but this is the best we can do.
+        let parent = path.parent().unwrap();
+        let dir = File::open(parent)?;
+        dir.sync_all()?;
+
+        // reopen
+        let mut oo = std::fs::OpenOptions::new();
+        oo.read(true);
+        oo.write(true);
+        oo.open(&path)?
+    };
+
+    // into a fresh StoreBase (not a new open, so generation is unchanged)
+    let mut newbase = StoreBase::new(path, file, base.opts.clone());
+    newbase.anonymous = base.anonymous;
+    read_newfile(&mut newbase, header::HeaderVer::is_write_compatible, None)?;
+    newbase.metrics = base.metrics;
+    newbase.replay_stats = base.replay_stats;
+    newbase.created = base.created;
+    newbase.compactions = base.compactions + 1;
+    newbase.history = base.history + 1;
+    newbase.compacted_records = newbase.record_starts.len();
+    newbase.replication = base.replication.take();
+    Ok(newbase)
+}    
+
+/// A point to roll a writable store back to: see [`Store::savepoint`].
+pub struct Savepoint {
+    file_size: u64,
+    spans: BTreeMap<u64, Span>,
+    records: BTreeMap<u64, u64>,
+    history: u64,
+    last_checkpoint: Option<u64>,
+}
+
+impl Store<Writable> {
+    /// Writes `buf.len()` bytes starting at `offset`.
+    ///
+    /// You can write anywhere, but if you create holes they will be
+    /// zero-filled.  Writes are ordered and become atomically visible
+    /// on success.  No durability guarantees (unless opened with a
+    /// [`SyncPolicy`] which says otherwise): the effects of this
+    /// write may be lost on crash or power failure.  However, the
+    /// effects of this write will never be observed without also
+    /// observing the effects of all previous successful writes.
+    ///
+    /// This will rewrite the file (using fsync and rename) if it gets
+    /// more than 100x bigger than the contents.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::OutOfRange`] if the data would end past
+    /// `u64::MAX`, [`Error::Sealed`] after [`Store::seal`], and an error
+    /// on underlying I/O problems (probably out of disk space).
+    pub fn write(&mut self, offset: u64, buf: &[u8]) -> Result<(), Error> {
+        self.write_records(offset, buf, None)
+    }
+
+    /// Writes `buf` at `offset` as [`Store::write`] does, unless exactly
+    /// those bytes are already there, and returns whether it wrote.
+    ///
+    /// This saves growing the file for writers which often save the same
+    /// thing again.  It costs a read of the existing bytes first (into a
+    /// buffer as big as `buf`, and checking their records if they haven't
+    /// been yet), which is wasted if they differ.  A hole counts as
+    /// different, even where `buf` holds the same bytes it reads as.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::read`] and [`Store::write`].
+    pub fn write_if_changed(&mut self, offset: u64, buf: &[u8]) -> Result<bool, Error> {
+        let mut existing = vec![0; buf.len()];
+        let coverage = self.read_mapped(offset, &mut existing)?;
+        if existing == buf && coverage.ranges.iter().map(|&(_, len)| len).sum::<u64>() == buf.len() as u64 {
+            return Ok(false);
+        }
+        self.write(offset, buf)?;
+        Ok(true)
+    }
+
+    /// As [`Store::write`], but returns where the data of each record it
+    /// appended starts in the file (see [`Store::record_offsets`] for
+    /// where the records themselves start).
+    ///
+    /// There's one record, unless this is a file from syncless 0.1, which
+    /// splits up large writes.  If the write made the file big enough to
+    /// compact, those records are gone by the time this returns, and it
+    /// returns where the data now starts, inside the record written for
+    /// its run of data.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::write`].
+    pub fn write_tracked(&mut self, offset: u64, buf: &[u8]) -> Result<Vec<u64>, Error> {
+        let compactions = self.base.compactions;
+        let mut offsets = Vec::with_capacity(1);
+        self.write_records(offset, buf, Some(&mut offsets))?;
+        if self.base.compactions != compactions {
+            // It's part of a record for its whole run now.
+            offsets = self.base.spans_in(offset, offset + buf.len() as u64)
+                .take(1)
+                .map(|(_, _, file_off)| file_off)
+                .collect();
+        }
+        Ok(offsets)
+    }
+
+    /// Write, noting the data offset of each record in `offsets`.
+    fn write_records(&mut self, mut offset: u64, mut buf: &[u8], mut offsets: Option<&mut Vec<u64>>)
+                     -> Result<(), Error> {
+        self.check_unsealed()?;
+        let end = offset.checked_add(buf.len() as u64).ok_or(Error::OutOfRange)?;
+        // Validate anything we're going to overwrite.
+        self.validate_spans(self.prev_offset(offset), end)?;
+
+        // Reads and validation move the cursor: appends go at the end
+        // (this is free if we're already there).
+        self.base.file.seek(SeekFrom::Start(self.base.file_size))?;
+
+        while !buf.is_empty() {
+            let chunk = &buf[..min(buf.len(), self.base.format.max_record_len())];
+            let start = self.base.file_size;
+
+            let data_off = record::write_record(&mut self.base.file, self.base.format, offset, chunk, &mut self.base.file_size)?;
+            self.base.record_starts.push(start);
+            self.base.add_record(offset, chunk.len() as u64, data_off, false);
+            if let Some(offsets) = offsets.as_deref_mut() {
+                offsets.push(data_off);
+            }
+            self.base.metrics.records_written += 1;
+            self.base.metrics.bytes_written += chunk.len() as u64;
+            if let Some(sink) = &self.base.replication {
+                let event = WriteEvent { logical_offset: offset, data: chunk.to_vec() };
+                // Receiver gone?  Stop replicating.
+                if sink.send(event).is_err() {
+                    self.base.replication = None;
+                }
+            }
+            if self.base.opts.max_unvalidated_spans.is_some() {
+                self.base.unvalidated.push_back((offset, chunk.len() as u64));
+            }
+            buf = &buf[chunk.len()..];
+            offset += chunk.len() as u64;
+        }
+
+        // Catch up on checking, if we're too far behind.
+        if let Some(max) = self.base.opts.max_unvalidated_spans {
+            while self.base.unvalidated.len() > max {
+                // What's left of that record starts in this range.
+                let (start, len) = self.base.unvalidated.pop_front().unwrap();
+                self.validate_spans(start, start + len)?;
+            }
+        }
+
+        // Compact when we're over 100x larger than we should be (unless we're tiny anyway)
+        if self.base.file_size > 1_000_000 && self.base.file_size * 100 > self.size()
+            && !self.base.in_transaction {
+            self.validate_spans(0, self.size())?;
+            self.base = compact(&mut self.base)?;
+        }
+
+        self.base.maybe_sync()
+    }
+
+    /// Appends a checkpoint marker: after a reopen (or crash), if this
+    /// checkpoint made it to disk, [`Store::last_checkpoint`] returns `id`
+    /// (unless a higher one did too).
+    ///
+    /// This is an empty record, with `id` where the offset would be, so
+    /// it changes no data.  Like a write, it is ordered but not durable, and it
+    /// isn't replicated.  Compaction keeps the latest checkpoint.  A file
+    /// from syncless 0.1 is compacted first, to upgrade it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error on underlying I/O problems (probably out of disk space).
+    pub fn checkpoint(&mut self, id: u64) -> Result<(), Error> {
+        self.check_unsealed()?;
+        if self.base.format == RecordFormat::Legacy {
+            self.validate_spans(0, self.size())?;
+            self.base = compact(&mut self.base)?;
+        }
+        self.base.file.seek(SeekFrom::Start(self.base.file_size))?;
+        let start = self.base.file_size;
+        record::write_record(&mut self.base.file, self.base.format, id, &[], &mut self.base.file_size)?;
+        self.base.record_starts.push(start);
+        self.base.add_record(id, 0, 0, false);
+        self.base.metrics.records_written += 1;
+        self.base.maybe_sync()
+    }
+
+    /// Seals the store: nothing more can be written to it, and opening it
+    /// for writing fails with [`Error::Sealed`], though it can still be
+    /// opened readonly.
+    ///
+    /// This is a flag in the file's header, so (unlike permissions) it
+    /// goes wherever the file does.  It's synced before this returns, and
+    /// it's permanent.  A file from syncless 0.1, with its shorter header,
+    /// is compacted first, to make room for it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::CorruptRecord`] if compacting found a damaged
+    /// record, and an error on underlying I/O problems.
+    pub fn seal(&mut self) -> Result<(), Error> {
+        if self.base.fields.sealed {
+            return Ok(());
+        }
+        if self.base.header_len != header::HEADER_SIZE as u64 {
+            self.validate_spans(0, self.size())?;
+            self.base = compact(&mut self.base)?;
+        }
+
+        // Same size, so rewrite it in place: only the version and flags
+        // change, so a torn write can't hurt.
+        let mut fields = self.base.fields.clone();
+        fields.sealed = true;
+        self.base.file.seek(SeekFrom::Start(0))?;
+        header::write_header(&mut self.base.file, self.base.generation.unwrap_or(0), self.base.format, &fields)?;
+        self.base.file.sync_data()?;
+        self.base.fields = fields;
+        self.base.ver = header::HeaderVer::for_format(self.base.format);
+        Ok(())
+    }
+
+    fn check_unsealed(&self) -> Result<(), Error> {
+        if self.base.fields.sealed {
+            return Err(Error::Sealed);
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` at the current end of the store, returning the offset
+    /// it was written at.
+    ///
+    /// This is `write(self.size(), buf)`, for log-like uses (e.g. history)
+    /// where you only ever add to the end.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::write`].
+    pub fn append(&mut self, buf: &[u8]) -> Result<u64, Error> {
+        let offset = self.size();
+        self.write(offset, buf)?;
+        Ok(offset)
+    }
+
+    /// Preallocates `additional` bytes of file space past the current end.
+    ///
+    /// This can reduce fragmentation on filesystems which allocate as
+    /// they go, when you know a lot of writes are coming.  The logical
+    /// size is unchanged.  Where the OS or filesystem doesn't support
+    /// this, it does nothing.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error on underlying I/O problems (probably out of disk space).
+    pub fn reserve(&mut self, additional: u64) -> Result<(), Error> {
+        sys::preallocate(self.base.file.get_ref(), self.base.file_size, additional)?;
+        Ok(())
+    }
+
+    /// Rewrites the file to hold just the current contents, now, rather
+    /// than waiting for a write to do it, and returns how many bytes
+    /// smaller the file got.
+    ///
+    /// Like that automatic compaction, this writes a record for each run
+    /// of data between holes: if that wouldn't make the file smaller, this
+    /// leaves it alone and returns 0.  Savepoints taken before a rewrite
+    /// can't be rolled back to.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::CorruptRecord`] if a record is damaged, and an
+    /// error on underlying I/O problems (probably out of disk space).
+    pub fn shrink_to_fit(&mut self) -> Result<u64, Error> {
+        let size = self.size();
+        let new_len: u64 = self.base.runs().iter()
+            .map(|&(_, len)| match len {
+                l if l < crate::MAX_RECORD_SIZE as u64 => l + crate::RECORD_OVERHEAD as u64,
+                l => l + crate::LONG_RECORD_OVERHEAD as u64,
+            })
+            .sum::<u64>() + header::HEADER_SIZE as u64;
+        // Plus the latest checkpoint, which compaction keeps.
+        let new_len = new_len + self.base.last_checkpoint.map_or(0, |_| crate::RECORD_OVERHEAD as u64);
+        let old_len = self.base.file_size;
+        if new_len >= old_len {
+            return Ok(0);
+        }
+
+        self.validate_spans(0, size)?;
+        self.base = compact(&mut self.base)?;
+        Ok(old_len - self.base.file_size)
+    }
+
+    /// Checks the records holding data in `[start, end)`.
+    ///
+    /// Records written since this store was opened are normally only
+    /// checked the first time they are read (or overwritten), since some
+    /// filesystems return zeroes for fresh data until it is synced (see
+    /// [`RetryPolicy`]).  This lets you pay that cost (possibly an
+    /// fdatasync) up front instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::CorruptRecord`] if a record is damaged, and an
+    /// error on underlying I/O error.
+    pub fn validate_range(&mut self, start: u64, end: u64) -> Result<(), Error> {
+        self.validate_spans(self.prev_offset(start), end)
+    }
+
+    /// Checks every record, as [`Store::validate_range`] over the whole store.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::validate_range`].
+    pub fn validate_all(&mut self) -> Result<(), Error> {
+        self.validate_spans(0, self.size())
+    }
+
+    /// Sends a [`WriteEvent`] to `sink` for every record written from now
+    /// on, in order, so a follower can replay them.
+    ///
+    /// This is best-effort: if the receiver is dropped, we quietly stop
+    /// sending.  Events are sent once the record is appended (not synced,
+    /// or validated), and [`Store::rollback`] isn't replicated.
+    pub fn set_replication_sink(&mut self, sink: Sender<WriteEvent>) {
+        self.base.replication = Some(sink);
+    }
+
+    /// Applies a [`WriteEvent`] from another store's replication sink.
+    ///
+    /// Apply events in the order they were sent, and this store's contents
+    /// will follow the other's (as of the last event applied).
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::write`].
+    pub fn apply_event(&mut self, event: &WriteEvent) -> Result<(), Error> {
+        self.write(event.logical_offset, &event.data)
+    }
+
+    /// Remembers the current state, so [`Store::rollback`] can discard
+    /// later writes.
+    ///
+    /// This copies the in-memory index, so it's not free for stores with
+    /// many scattered writes.
+    pub fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            file_size: self.base.file_size,
+            spans: self.base.spans.clone(),
+            records: self.base.records.clone(),
+            history: self.base.history,
+            last_checkpoint: self.base.last_checkpoint,
+        }
+    }
+
+    /// Discards every write since `sp` was taken (on this store), by
+    /// truncating the file.
+    ///
+    /// The truncation is synced before this returns, so the discarded
+    /// writes can't reappear after a crash.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::SavepointLost`] if the file has been compacted
+    /// since the savepoint (this can happen on any write), or there has
+    /// been another rollback or [`Store::undo_last`] since, as the records
+    /// it refers to may be gone.  Returns an error on underlying I/O error.
+    pub fn rollback(&mut self, sp: Savepoint) -> Result<(), Error> {
+        self.check_unsealed()?;
+        if sp.history != self.base.history {
+            return Err(Error::SavepointLost);
+        }
+        self.base.file.get_ref().set_len(sp.file_size)?;
+        self.base.file.sync_data()?;
+        self.base.file_size = sp.file_size;
+        self.base.record_starts.retain(|&start| start < sp.file_size);
+        self.base.spans = sp.spans;
+        self.base.records = sp.records;
+        self.base.last_checkpoint = sp.last_checkpoint;
+        self.base.history += 1;
+        Ok(())
+    }
+
+    /// Discards the last `n` records written to the file, as if those
+    /// writes had never happened: the file is truncated (and synced, as
+    /// for [`Store::rollback`]), then replayed.
+    ///
+    /// Each write is one record (except in files from syncless 0.1, which
+    /// split large writes up).  Compacting replaces all the records with
+    /// one for each run of data: if that happened since this store was
+    /// opened, you can only undo writes since then.  (If it happened
+    /// before, undoing those records undoes everything before them.)
+    /// A [`Store::checkpoint`] is a record too.  Savepoints taken before
+    /// this can't be rolled back to afterwards, and like rollback, this
+    /// isn't replicated.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::SavepointLost`] (and undoes nothing) if there
+    /// aren't `n` records which can be undone.  Returns
+    /// [`Error::CorruptRecord`] if one of the records left is damaged,
+    /// and an error on underlying I/O error.
+    pub fn undo_last(&mut self, n: usize) -> Result<(), Error> {
+        self.check_unsealed()?;
+        if n == 0 {
+            return Ok(());
+        }
+        let starts = &self.base.record_starts;
+        // The first records after a compaction are the compacted ones.
+        let undoable = starts.len().saturating_sub(self.base.compacted_records);
+        if n > undoable {
+            return Err(Error::SavepointLost);
+        }
+        let new_size = starts[starts.len() - n];
+
+        // We'll replay the rest, so check them first.
+        self.validate_all()?;
+        self.base.file.get_ref().set_len(new_size)?;
+        self.base.file.sync_data()?;
+        self.base.file_size = new_size;
+        self.base.history += 1;
+        self.base.replay()
+    }
+
+    /// Writes the data from [`Store::export_sparse`], read from `src`, to
+    /// where `manifest` says it belongs.
+    ///
+    /// Into an empty store, this reproduces the exported contents, holes
+    /// and all; anything else already in the store is only overwritten
+    /// where the regions cover it.  Each region is written in pieces of
+    /// up to 1MiB, so a crash part way can leave some of it written.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::write`], and any error reading from `src` (one of
+    /// kind `UnexpectedEof` if it's shorter than the regions).
+    pub fn import_sparse(&mut self, mut src: impl Read, manifest: &SparseManifest) -> Result<(), Error> {
+        const CHUNK: u64 = 1024 * 1024;
+        let mut buf = Vec::new();
+
+        for &(off, len) in &manifest.regions {
+            let mut done = 0;
+            while done < len {
+                let n = min(len - done, CHUNK) as usize;
+                buf.resize(n, 0);
+                src.read_exact(&mut buf)?;
+                self.write(off + done, &buf)?;
+                done += n as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the contents of the store (and its hole fill byte and
+    /// metadata) with `snap`, from [`Store::snapshot_serde`].
+    ///
+    /// Like compaction, this atomically replaces the file, so savepoints
+    /// taken before can't be rolled back to.  It isn't replicated.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::OutOfRange`] (and changes nothing) if `snap` isn't
+    /// valid: a length which doesn't match its data, spans out of order or
+    /// overlapping, or metadata [`StoreOptions::metadata`] wouldn't allow.
+    /// Returns an error on underlying I/O problems.
+    #[cfg(feature = "serde")]
+    pub fn restore_serde(&mut self, snap: &StoreSnapshot) -> Result<(), Error> {
+        self.check_unsealed()?;
+        let mut fields = self.base.fields.clone();
+        fields.hole_fill = snap.hole_fill;
+        fields.ext.retain(|(tag, _)| *tag < header::FIRST_APP_TAG);
+        for (tag, value) in &snap.metadata {
+            if *tag < header::FIRST_APP_TAG || fields.ext.iter().any(|(t, _)| t == tag) {
+                return Err(Error::OutOfRange);
+            }
+            fields.ext.push((*tag, value.clone()));
+        }
+        if !header::ext_fits(&fields.ext) {
+            return Err(Error::OutOfRange);
+        }
+
+        let mut writes = Vec::with_capacity(snap.spans.len());
+        let mut end = 0;
+        for (off, len, data) in &snap.spans {
+            if *len != data.len() as u64 || *off < end {
+                return Err(Error::OutOfRange);
+            }
+            end = off.checked_add(*len).ok_or(Error::OutOfRange)?;
+            if !data.is_empty() {
+                writes.push((*off, data.as_slice()));
+            }
+        }
+        self.base = rewrite(&mut self.base, &fields, &writes)?;
+        Ok(())
+    }
+
+    /// Starts a [`Transaction`]: writes through it are all discarded
+    /// unless it is committed.
+    pub fn transaction(&mut self) -> Transaction<'_> {
+        let savepoint = self.savepoint();
+        self.base.in_transaction = true;
+        Transaction { store: self, savepoint: Some(savepoint) }
+    }
+
+    /// Convert this writable store into a readonly one.
+    pub fn into_readonly(mut self) -> Result<Store<ReadOnly>, Error> {
+        // Before we make it readonly, make sure all spans are validated!
+        self.validate_all()?;
+
+        let mut base = self.into_base();
+        base.file.set_shared();
+        Ok(Store {
+            base,
+            writable: false,
+            _mode: PhantomData,
+        })
+    }
+
+    /// Closes the store, returning any error.
+    ///
+    /// This validates every record written (as [`Store::validate_all`])
+    /// and, unless the [`SyncPolicy`] is `Never`, syncs the file so the
+    /// last writes are as durable as the policy promised.  Simply
+    /// dropping the store can't report errors (and, in debug builds with
+    /// the `log` feature, logs a warning if records written weren't
+    /// checked).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::CorruptRecord`] if a record is damaged, and an
+    /// error on underlying I/O error.
+    pub fn close(mut self) -> Result<(), Error> {
+        self.validate_all()?;
+        let mut base = self.into_base();
+        base.file.flush()?;
+        if !matches!(base.opts.sync_policy, SyncPolicy::Never) {
+            base.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// A group of writes which are discarded (with [`Store::rollback`]) if
+/// it's dropped without [`Transaction::commit`], say on an error or a
+/// panic.  See [`Store::transaction`].
+///
+/// This is about what this process sees: like any writes, a crash can
+/// lose the later ones, so after a crash you may see some of a
+/// transaction's writes without the rest.  The file isn't compacted
+/// while a transaction is open (that would make it impossible to roll
+/// back), so keep them short.
+pub struct Transaction<'a> {
+    store: &'a mut Store<Writable>,
+    /// None once committed or aborted.
+    savepoint: Option<Savepoint>,
+}
+
+impl Transaction<'_> {
+    /// Writes `buf` at `offset`, as [`Store::write`].
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::write`].
+    pub fn write(&mut self, offset: u64, buf: &[u8]) -> Result<(), Error> {
+        self.store.write(offset, buf)
+    }
+
+    /// Reads `buf.len()` bytes at `offset`, as [`Store::read`]: this sees
+    /// the transaction's writes.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::read`].
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.store.read(offset, buf)
+    }
+
+    /// Keeps the writes.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::CorruptRecord`] if a record written by the
+    /// transaction is damaged, and an error on underlying I/O error.
+    /// The writes are rolled back in that case.
+    pub fn commit(mut self) -> Result<(), Error> {
+        let end = self.store.size();
+        self.store.validate_range(0, end)?;
+        self.savepoint = None;
+        Ok(())
+    }
+
+    /// Discards the writes, as dropping the transaction does, but
+    /// reporting any error.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::rollback`].
+    pub fn abort(mut self) -> Result<(), Error> {
+        let sp = self.savepoint.take().unwrap();
+        self.store.rollback(sp)
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if let Some(sp) = self.savepoint.take() {
+            // Nowhere to report an error, but the next write will find
+            // it (e.g. if the file is now read-only).
+            let _ = self.store.rollback(sp);
+        }
+        self.store.base.in_transaction = false;
+    }
+}
+
+impl Store<ReadOnly> {
+    /// Converts this into a [`SharedReadStore`], which many threads can
+    /// read at once.
+    ///
+    /// A [`SharedReadStore`] can't replay or check records any more, so
+    /// this first replays whatever [`StoreOptions::max_replay_records`]
+    /// left, and checks any records [`StoreOptions::skip_checksum_on_open`]
+    /// skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::CorruptRecord`] if a record skipped on open is
+    /// damaged, and an error on underlying I/O error.
+    pub fn into_shared(mut self) -> Result<SharedReadStore, Error> {
+        self.base.replay_rest()?;
+        self.validate_spans(0, self.size())?;
+        Ok(SharedReadStore { base: Arc::new(self.into_base()) })
+    }
+
+    /// Returns another handle to this store, without opening and
+    /// replaying the file again.
+    ///
+    /// The two are independent, except that they share the file (and its
+    /// position), so don't use them from different threads at once: see
+    /// [`Store::into_shared`] for that.  There's no writable version, as
+    /// two writers appending to one file would clobber each other.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file handle can't be duplicated.
+    pub fn try_clone(&self) -> Result<Store<ReadOnly>, Error> {
+        let old = &self.base;
+        let mut base = StoreBase::new(old.path.clone(), old.file.get_ref().try_clone()?, old.opts.clone());
+        base.file.set_shared();
+        base.spans = old.spans.clone();
+        base.records = old.records.clone();
+        base.file_size = old.file_size;
+        base.header_len = old.header_len;
+        base.record_starts = old.record_starts.clone();
+        base.format = old.format;
+        base.ver = old.ver;
+        base.fields = old.fields.clone();
+        base.resynced = old.resynced;
+        base.replay_limited = old.replay_limited;
+        base.generation = old.generation;
+        base.created = old.created;
+        base.anonymous = old.anonymous;
+        base.compactions = old.compactions;
+        base.history = old.history;
+        base.compacted_records = old.compacted_records;
+        base.last_checkpoint = old.last_checkpoint;
+        base.metrics = old.metrics;
+        base.replay_stats = old.replay_stats;
+        Ok(Store { base, writable: false, _mode: PhantomData })
+    }
+}
+
+/// A readonly store which can be read from many threads at once (clone it,
+/// or share a reference), without locking.
+///
+/// Readonly stores never change, so this only needs positioned reads.
+/// Unlike [`Store::read`], these reads aren't counted in [`Store::metrics`]
+/// and don't recheck records even if the store was opened paranoid
+/// (records skipped by [`StoreOptions::skip_checksum_on_open`] were
+/// checked by [`Store::into_shared`]).
+#[derive(Clone)]
+pub struct SharedReadStore {
+    base: Arc<StoreBase>,
+}
+
+impl SharedReadStore {
+    /// Returns the logical size of the store in bytes.
+    pub fn size(&self) -> u64 {
+        self.base.size()
+    }
+
+    /// Returns the logical size of the store in bytes (same as size()).
+    pub fn len(&self) -> u64 {
+        self.size()
+    }
+
+    /// Returns true if nothing has been written to the store.
+    pub fn is_empty(&self) -> bool {
+        self.size() == 0
+    }
+
+    /// Reads `buf.len()` bytes starting at `offset`, like [`Store::read`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::OutOfRange`] if the read would end past the
+    /// largest offset, and an error on underlying I/O error (or, on
+    /// platforms without positioned reads, one of kind `Unsupported`).
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let end = offset.checked_add(buf.len() as u64).ok_or(Error::OutOfRange)?;
+        let mut pos = 0;
+        for (off, len, file_off) in self.base.spans_in(offset, end) {
+            let start = (off - offset) as usize;
+            buf[pos..start].fill(self.base.fields.hole_fill);
+            sys::read_exact_at(self.base.file.get_ref(), file_off, &mut buf[start..start + len as usize])?;
+            pos = start + len as usize;
+        }
+        buf[pos..].fill(self.base.fields.hole_fill);
+        Ok(())
+    }
+}
+
+const READER_BUF_SIZE: usize = 64 * 1024;
+
+/// Read buffers larger than these (from reads of many scattered spans)
+/// are freed afterwards rather than kept for the next read.
+const MAX_KEPT_READ_RUNS: usize = 1024;
+const MAX_KEPT_READ_SCRATCH: usize = 1024 * 1024;
+
+/// Reads a store like a file: see [`Store::into_reader`].
+///
+/// This implements [`Read`], [`Seek`] and [`BufRead`] (with its
+/// own buffer, so there's no need to wrap it in a `BufReader`).  Holes
+/// read as they do for [`Store::read`], and it ends at [`Store::size`].
+/// Errors other than I/O errors come back as I/O errors of kind
+/// `InvalidData`.
+pub struct StoreReader<M> {
+    store: Store<M>,
+    /// Logical offset of buf[0].
+    pos: u64,
+    buf: Box<[u8]>,
+    /// How much of buf holds data, and how much of that has been read.
+    filled: usize,
+    consumed: usize,
+}
+
+fn to_io_error(err: Error) -> std::io::Error {
+    match err {
+        Error::Io(err) => err,
+        err => std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", err)),
+    }
+}
+
+impl<M> StoreReader<M> {
+    /// Returns the logical offset the next read will start at.
+    pub fn position(&self) -> u64 {
+        self.pos + self.consumed as u64
+    }
+
+    /// Returns the store (whatever has been read so far).
+    pub fn into_inner(self) -> Store<M> {
+        self.store
+    }
+
+    /// The number of bytes left in the store after `offset`.
+    fn remaining(&mut self, offset: u64) -> std::io::Result<u64> {
+        self.store.finish_replay().map_err(to_io_error)?;
+        Ok(self.store.size().saturating_sub(offset))
+    }
+}
+
+impl<M> Read for StoreReader<M> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        // Don't bother copying big reads through the buffer.
+        if self.consumed == self.filled && out.len() >= self.buf.len() {
+            let offset = self.position();
+            let n = min(out.len() as u64, self.remaining(offset)?) as usize;
+            self.store.read(offset, &mut out[..n]).map_err(to_io_error)?;
+            self.pos = offset + n as u64;
+            self.filled = 0;
+            self.consumed = 0;
+            return Ok(n);
+        }
+        let data = self.fill_buf()?;
+        let n = min(data.len(), out.len());
+        out[..n].copy_from_slice(&data[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<M> BufRead for StoreReader<M> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.consumed == self.filled {
+            self.pos += self.filled as u64;
+            self.filled = 0;
+            self.consumed = 0;
+            let n = min(self.buf.len() as u64, self.remaining(self.pos)?) as usize;
+            self.store.read(self.pos, &mut self.buf[..n]).map_err(to_io_error)?;
+            self.filled = n;
+        }
+        Ok(&self.buf[self.consumed..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed = min(self.consumed + amt, self.filled);
+    }
+}
+
+impl<M> Seek for StoreReader<M> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::Current(delta) => (self.position(), delta),
+            SeekFrom::End(delta) => {
+                self.store.finish_replay().map_err(to_io_error)?;
+                (self.store.size(), delta)
+            }
+        };
+        let Some(offset) = base.checked_add_signed(delta) else {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before start"));
+        };
+        self.pos = offset;
+        self.filled = 0;
+        self.consumed = 0;
+        Ok(offset)
+    }
+}
+
+/// A writable store behind a mutex, so threads can share one writer.
+///
+/// Every call takes the lock, so all access is serialized, but writes are
+/// still atomic and ordered exactly as for [`Store`].  Share it with an
+/// `Arc`.  If a thread panics holding the lock, others carry on: the
+/// store's own state is never left half-updated by a write.
+pub struct SyncStore {
+    store: Mutex<Store<Writable>>,
+}
+
+impl SyncStore {
+    /// Wraps `store`.
+    pub fn new(store: Store<Writable>) -> SyncStore {
+        SyncStore { store: Mutex::new(store) }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Store<Writable>> {
+        self.store.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the logical size of the store in bytes.
+    pub fn size(&self) -> u64 {
+        self.lock().size()
+    }
+
+    /// As [`Store::read`].
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::read`].
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        self.lock().read(offset, buf)
+    }
+
+    /// As [`Store::write`].
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::write`].
+    pub fn write(&self, offset: u64, buf: &[u8]) -> Result<(), Error> {
+        self.lock().write(offset, buf)
+    }
+
+    /// As [`Store::append`].
+    ///
+    /// # Errors
+    ///
+    /// As for [`Store::append`].
+    pub fn append(&self, buf: &[u8]) -> Result<u64, Error> {
+        self.lock().append(buf)
+    }
+
+    /// Syncs the file (fdatasync), whatever the [`SyncPolicy`], so every
+    /// write so far is durable.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error on underlying I/O error.
+    pub fn sync(&self) -> Result<(), Error> {
+        self.lock().base.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Returns the store.
+    pub fn into_inner(self) -> Store<Writable> {
+        self.store.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<M> Drop for Store<M> {
+    fn drop(&mut self) {
+        // We don't buffer anything, but the file might.
+        let _ = self.base.file.flush();
+        // Fresh records nobody checked: a bad one (see RetryPolicy) would
+        // only show up on the next open.
+        if cfg!(debug_assertions) && self.writable && !self.is_fully_validated() {
+            let unchecked = self.base.spans.values().filter(|span| !span.validated).count();
+            warn_log!("{} dropped without close() or into_readonly(): {} spans written but never checked",
+                      self.base.path.display(), unchecked);
+        }
+    }
+}
+
+#[cfg(test)]
+
+#[test]
+fn empty_store_size_zero() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let store = open(&path, WriteOpenMode::MayExist).unwrap();
+    assert_eq!(store.size(), 0);
+}
+
+#[test]
+fn write_then_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+
+    store.write(0, b"hello").unwrap();
+    assert_eq!(store.size(), 5);
+
+    let mut buf = [0u8; 5];
+    store.read(0, &mut buf).unwrap();
+
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+fn overwrite_middle() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+
+    store.write(0, b"abcdefgh").unwrap();
+    store.write(2, b"XYZ").unwrap();
+
+    let mut buf = [0u8; 8];
+    store.read(0, &mut buf).unwrap();
+
+    assert_eq!(&buf, b"abXYZfgh");
+}
+
+#[test]
+fn holes_are_zero() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(10, b"hi").unwrap();
+
+    let mut buf = [0u8; 12];
+    store.read(0, &mut buf).unwrap();
+
+    assert_eq!(&buf[..10], &[0u8; 10]);
+    assert_eq!(&buf[10..12], b"hi");
+}
+
+#[test]
+fn replay_reconstructs_state() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    {
+        let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+        store.write(0, b"abc").unwrap();
+        store.write(5, b"xyz").unwrap();
+    }
+
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+
+    let mut buf = [0u8; 8];
+    store.read(0, &mut buf).unwrap();
+
+    assert_eq!(&buf, b"abc\0\0xyz");
+}
+
+#[test]
+fn read_strict_holes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    store.write(5, b"xyz").unwrap();
+
+    let mut buf = [0u8; 2];
+    store.read_strict(1, &mut buf).unwrap();
+    assert_eq!(&buf, b"bc");
+    store.read_strict(6, &mut buf).unwrap();
+    assert_eq!(&buf, b"yz");
+
+    let mut buf = [0u8; 8];
+    assert!(matches!(store.read_strict(0, &mut buf), Err(Error::Hole { offset: 3, len: 2 })));
+    assert!(matches!(store.read_strict(4, &mut buf), Err(Error::Hole { offset: 4, len: 1 })));
+    assert!(matches!(store.read_strict(5, &mut buf), Err(Error::Hole { offset: 8, len: 5 })));
+}
+
+#[test]
+fn write_after_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    store.write(5, b"xyz").unwrap();
+
+    // Validates everything, then leaves cursor in the first record.
+    let mut buf = [0u8; 8];
+    store.read(0, &mut buf).unwrap();
+    store.read(0, &mut buf[..2]).unwrap();
+    store.write(10, b"q").unwrap();
+    drop(store);
+
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    let mut buf = [0u8; 11];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abc\0\0xyz\0\0q");
+}
+
+#[test]
+fn reserve_keeps_size() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    let file_len = std::fs::metadata(&path).unwrap().len();
+
+    store.reserve(1 << 20).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), file_len);
+    assert_eq!(store.size(), 3);
+
+    store.write(3, b"def").unwrap();
+    drop(store);
+
+    let mut store = open_readonly(&path).unwrap();
+    let mut buf = [0u8; 6];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abcdef");
+}
+
+#[test]
+fn sync_policies() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    for policy in [SyncPolicy::Never,
+                   SyncPolicy::EveryWrite,
+                   SyncPolicy::EveryN(0),
+                   SyncPolicy::EveryN(2),
+                   SyncPolicy::Interval(std::time::Duration::from_millis(1))] {
+        let mut store = open_with_sync_policy(&path, WriteOpenMode::MayExist, policy).unwrap();
+        for i in 0..3u8 {
+            store.write(i as u64, &[i]).unwrap();
+        }
+        if let SyncPolicy::EveryN(2) = policy {
+            assert_eq!(store.base.writes_since_sync, 1);
+        }
+        drop(store);
+
+        let mut store = open_readonly(&path).unwrap();
+        let mut buf = [9u8; 3];
+        store.read(0, &mut buf).unwrap();
+        assert_eq!(&buf, &[0, 1, 2]);
+    }
+}
+
+#[test]
+fn generation_bumps_on_writable_open() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    assert_eq!(store.generation(), 1);
+    store.write(0, b"abc").unwrap();
+    drop(store);
+
+    assert_eq!(open_readonly(&path).unwrap().generation(), 1);
+    assert_eq!(open_readonly(&path).unwrap().generation(), 1);
+
+    let store = open(&path, WriteOpenMode::MustExist).unwrap();
+    assert_eq!(store.generation(), 2);
+    drop(store);
+
+    let mut store = open_readonly(&path).unwrap();
+    assert_eq!(store.generation(), 2);
+    let mut buf = [0u8; 3];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abc");
+}
+
+#[test]
+fn legacy_header_has_no_generation() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    // A 0.1 file: 12 byte header, then a record of "abc" at offset 0.
+    let legacy_hdr = b"Syncless\0\0\0\0";
+    let mut file_len = legacy_hdr.len() as u64;
+    {
+        let mut f = File::create(&path).unwrap();
+        std::io::Write::write_all(&mut f, legacy_hdr).unwrap();
+        record::write_record(&mut f, RecordFormat::Legacy, 0, b"abc", &mut file_len).unwrap();
+    }
+    let bytes = std::fs::read(&path).unwrap();
+
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    assert_eq!(store.generation(), 0);
+    let mut buf = [0u8; 3];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abc");
+    drop(store);
+
+    // Nothing was written.
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+}
+
+#[test]
+fn read_coalesces_file_adjacent_spans() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(0, b"0123456789").unwrap();
+    store.write(20, b"abcdefghij").unwrap();
+
+    let mut expected = [0u8; 30];
+    store.read(0, &mut expected).unwrap();
+
+    // Chop each record into pieces, which are adjacent in the file.
+    for (off, piece) in [(0, 3), (3, 4), (20, 1), (21, 8)] {
+        let span = store.base.spans.get_mut(&off).unwrap();
+        let rest = Span { len: span.len - piece,
+                          file_data_offset: span.file_data_offset + piece,
+                          validated: span.validated };
+        span.len = piece;
+        store.base.spans.insert(off + piece, rest);
+    }
+    assert_eq!(store.base.spans.len(), 6);
+
+    for start in 0..30 {
+        for end in start..=30 {
+            let mut buf = vec![0xFFu8; end - start];
+            store.read(start as u64, &mut buf).unwrap();
+            assert_eq!(buf, &expected[start..end]);
+        }
+    }
+}
+
+#[test]
+fn data_queries() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    assert!(!store.has_data(0));
+    assert_eq!(store.next_data_after(0), None);
+
+    store.write(2, b"ab").unwrap();
+    store.write(10, b"xyz").unwrap();
+
+    let covered: Vec<u64> = (0..14).filter(|&off| store.has_data(off)).collect();
+    assert_eq!(covered, [2, 3, 10, 11, 12]);
+
+    assert_eq!(store.next_data_after(0), Some(2));
+    assert_eq!(store.next_data_after(3), Some(3));
+    assert_eq!(store.next_data_after(4), Some(10));
+    assert_eq!(store.next_data_after(12), Some(12));
+    assert_eq!(store.next_data_after(13), None);
+}
+
+#[test]
+fn hole_queries() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    assert_eq!(store.next_hole_after(0), None);
+
+    store.write(2, b"ab").unwrap();
+    store.write(4, b"cd").unwrap();
+    store.write(10, b"xyz").unwrap();
+
+    assert_eq!(store.next_hole_after(0), Some(0));
+    assert_eq!(store.next_hole_after(2), Some(6));
+    assert_eq!(store.next_hole_after(5), Some(6));
+    assert_eq!(store.next_hole_after(9), Some(9));
+    assert_eq!(store.next_hole_after(10), None);
+    assert_eq!(store.next_hole_after(100), None);
+}
+
+#[test]
+fn content_hash_ignores_history() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let mut a = open(dir.path().join("a"), WriteOpenMode::MustNotExist).unwrap();
+    a.write(0, b"hello world").unwrap();
+    a.write(0, b"j").unwrap();
+    a.write(20, b"!").unwrap();
+
+    let mut b = open(dir.path().join("b"), WriteOpenMode::MustNotExist).unwrap();
+    b.write(20, b"!").unwrap();
+    b.write(6, b"world").unwrap();
+    b.write(0, b"jello ").unwrap();
+
+    assert_eq!(a.content_hash().unwrap(), b.content_hash().unwrap());
+
+    // Same as hashing the contents directly.
+    let mut contents = vec![0u8; 21];
+    a.read(0, &mut contents).unwrap();
+    let mut d = checksum::Digest::new();
+    d.write(&contents);
+    assert_eq!(a.content_hash().unwrap(), d.sum64());
+
+    b.write(11, b"\0").unwrap();
+    assert_eq!(a.content_hash().unwrap(), b.content_hash().unwrap());
+    b.write(11, b"?").unwrap();
+    assert_ne!(a.content_hash().unwrap(), b.content_hash().unwrap());
+}
+
+#[test]
+fn into_parts_returns_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    let (file, file_size) = store.into_parts();
+    assert_eq!(file.metadata().unwrap().len(), file_size);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), file_size);
+
+    let file = open_readonly(&path).unwrap().into_inner();
+    assert_eq!(file.metadata().unwrap().len(), file_size);
+}
+
+#[cfg(unix)]
+#[test]
+fn as_fd_is_the_file() {
+    use std::os::unix::io::{AsFd, AsRawFd};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(0, b"abc").unwrap();
+
+    let dup = File::from(store.as_fd().try_clone_to_owned().unwrap());
+    assert_eq!(dup.metadata().unwrap().len(), std::fs::metadata(&path).unwrap().len());
+    assert_eq!(store.as_raw_fd(), store.base.file.get_ref().as_raw_fd());
+}
+
+#[test]
+fn validate_all_catches_damage() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    store.write(10, b"xyz").unwrap();
+    assert!(!store.is_fully_validated());
+    store.validate_range(0, 3).unwrap();
+    assert!(!store.is_fully_validated());
+
+    // Damage the second record's data behind the store's back.
+    let data_off = store.base.spans[&10].file_data_offset;
+    let mut f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.seek(SeekFrom::Start(data_off)).unwrap();
+    f.write_all(b"\0").unwrap();
+
+    store.validate_range(0, 3).unwrap();
+    assert!(matches!(store.validate_range(11, 12),
+                     Err(Error::CorruptRecord { file_offset, expected_len: 3 }) if file_offset == data_off));
+    assert!(matches!(store.validate_all(), Err(Error::CorruptRecord { .. })));
+}
+
+#[test]
+fn fully_validated() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    assert!(store.is_fully_validated());
+    store.write(0, b"abc").unwrap();
+    assert!(!store.is_fully_validated());
+    store.validate_all().unwrap();
+    assert!(store.is_fully_validated());
+    drop(store);
+
+    // Replay checks everything it reads.
+    let store = open(&path, WriteOpenMode::MustExist).unwrap();
+    assert!(store.is_fully_validated());
+}
+
+#[test]
+fn retry_policy_still_catches_damage() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    for retries in [0, 3] {
+        std::fs::remove_file(&path).ok();
+        let policy = RetryPolicy { retries, sync: RetrySync::All };
+        let mut store = open_with_retry_policy(&path, WriteOpenMode::MustNotExist, policy).unwrap();
+        store.write(0, b"abc").unwrap();
+
+        let data_off = store.base.spans[&0].file_data_offset;
+        let mut f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        f.seek(SeekFrom::Start(data_off)).unwrap();
+        f.write_all(b"\0").unwrap();
+
+        assert!(matches!(store.validate_all(), Err(Error::CorruptRecord { .. })));
+    }
+}
+
+#[test]
+fn retry_recovers_from_phantom_zeroes() {
+    use crate::storage::FaultyStorage;
+
+    let mut storage = FaultyStorage::new();
+    let mut file_size = 0;
+    let data_off = record::write_record(&mut storage, RecordFormat::CURRENT, 0, b"abc", &mut file_size).unwrap();
+
+    // Without a retry, the fresh record looks corrupt.
+    let policy = RetryPolicy { retries: 0, ..RetryPolicy::default() };
+    assert!(matches!(validate_record_with_retry(&mut storage, RecordFormat::CURRENT, &policy, data_off, 3),
+                     Err(Error::CorruptRecord { .. })));
+    assert_eq!(storage.syncs, 0);
+
+    // The default syncs once, and then it reads back fine.
+    validate_record_with_retry(&mut storage, RecordFormat::CURRENT, &RetryPolicy::default(), data_off, 3).unwrap();
+    assert_eq!(storage.syncs, 1);
+}
+
+#[test]
+fn large_write_is_one_record() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+    let data: Vec<u8> = (0..record::MAX_RECORD_SIZE + 100).map(|i| i as u8).collect();
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(1, &data).unwrap();
+    assert_eq!(store.base.spans.len(), 1);
+    drop(store);
+
+    let mut store = open_readonly(&path).unwrap();
+    assert_eq!(store.base.spans.len(), 1);
+    let mut buf = vec![0u8; data.len()];
+    store.read(1, &mut buf).unwrap();
+    assert!(buf == data);
+}
+
+#[test]
+fn mixed_record_encodings() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    // Long records are normally only used for big writes, but any record
+    // may use either encoding: hand-craft a small one.
+    fn long_record(logical_offset: u64, data: &[u8]) -> Vec<u8> {
+        let mut rec = vec![0xF5, 0x9C, 0x5A, 0x18];
+        rec.extend_from_slice(&logical_offset.to_le_bytes());
+        rec.extend_from_slice(&(data.len() as u64).to_le_bytes());
+        rec.extend_from_slice(data);
+        let mut d = checksum::Digest::new();
+        d.write(&rec);
+        rec.extend_from_slice(&d.sum64().to_le_bytes());
+        rec
+    }
+
+    let mut f = File::create(&path).unwrap();
+    let mut file_len = header::write_header(&mut f, 1, RecordFormat::CURRENT, &header::HeaderFields::default()).unwrap();
+    record::write_record(&mut f, RecordFormat::CURRENT, 0, b"aaaaaa", &mut file_len).unwrap();
+    let rec = long_record(2, b"LL");
+    f.write_all(&rec).unwrap();
+    file_len += rec.len() as u64;
+    record::write_record(&mut f, RecordFormat::CURRENT, 3, b"s", &mut file_len).unwrap();
+    f.write_all(&long_record(8, b"end")).unwrap();
+    drop(f);
+
+    let mut store = open_readonly(&path).unwrap();
+    let mut buf = [0u8; 11];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"aaLsaa\0\0end");
+
+    // Checking records after replay has to get their headers right too.
+    let mut buf = [0u8; 11];
+    open_readonly_paranoid(&path).unwrap().read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"aaLsaa\0\0end");
+    let opts = StoreOptions::new().skip_checksum_on_open(true);
+    let mut buf = [0u8; 11];
+    opts.clone().open_readonly(&path).unwrap().read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"aaLsaa\0\0end");
+    let mut store = opts.open(&path).unwrap();
+    let mut buf = [0u8; 11];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"aaLsaa\0\0end");
+    store.close().unwrap();
+}
+
+#[test]
+fn append_returns_offset() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    assert_eq!(store.append(b"abc").unwrap(), 0);
+    assert_eq!(store.append(b"de").unwrap(), 3);
+    store.write(10, b"x").unwrap();
+    assert_eq!(store.append(b"f").unwrap(), 11);
+
+    let mut buf = [0u8; 12];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abcde\0\0\0\0\0xf");
+}
+
+#[test]
+fn sequential_appends_dont_seek() {
+    use crate::storage::CountingStorage;
+
+    let mut storage = Tracked::new(CountingStorage::new());
+    let mut file_size = header::write_header(&mut storage, 1, RecordFormat::CURRENT, &header::HeaderFields::default()).unwrap();
+    assert_eq!(storage.get_ref().seeks, 0);
+
+    // As Store::write does.
+    for i in 0..3 {
+        storage.seek(SeekFrom::Start(file_size)).unwrap();
+        record::write_record(&mut storage, RecordFormat::CURRENT, i, b"x", &mut file_size).unwrap();
+    }
+    assert_eq!(storage.get_ref().seeks, 1);
+
+    // Reading moves the cursor, so now we do need to seek.
+    storage.seek(SeekFrom::Start(0)).unwrap();
+    storage.read_exact(&mut [0u8; 4]).unwrap();
+    storage.seek(SeekFrom::Start(file_size)).unwrap();
+    assert_eq!(storage.get_ref().seeks, 3);
+}
+
+#[test]
+fn record_is_one_write() {
+    use crate::storage::CountingStorage;
+
+    let mut storage = CountingStorage::new();
+    let mut file_size = 0;
+    record::write_record(&mut storage, RecordFormat::CURRENT, 0, b"abc", &mut file_size).unwrap();
+    assert_eq!(storage.writes, 1);
+
+    // Still the same record as separate writes would give.
+    storage.seek(SeekFrom::Start(0)).unwrap();
+    let mut end = 0;
+    let rec = record::read_next_record(&mut storage, RecordFormat::CURRENT, &mut end, true).unwrap().unwrap();
+    assert_eq!((rec.hdr.length, end), (3, file_size));
+}
+
+#[test]
+fn read_uninit_matches_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(2, b"abc").unwrap();
+    store.write(4, b"XY").unwrap();
+    store.write(10, b"z").unwrap();
+
+    let mut expect = [0xFFu8; 14];
+    store.read(1, &mut expect).unwrap();
+
+    let mut buf = [MaybeUninit::<u8>::uninit(); 14];
+    assert_eq!(store.read_uninit(1, &mut buf).unwrap(), &expect);
+}
+
+#[test]
+fn metrics_count_work() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    store.append(b"de").unwrap();
+    let mut buf = [0u8; 5];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(store.metrics(), Metrics { records_written: 2,
+                                          bytes_written: 5,
+                                          records_validated: 2,
+                                          checksum_failures: 0,
+                                          reads: 1 });
+
+    // Damage the last record: replay notices.
+    let data_off = store.base.spans[&3].file_data_offset;
+    drop(store);
+    let mut f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.seek(SeekFrom::Start(data_off)).unwrap();
+    f.write_all(b"X").unwrap();
+
+    let store = open_readonly(&path).unwrap();
+    assert_eq!(store.metrics(), Metrics { checksum_failures: 1, ..Metrics::default() });
+}
+
+#[test]
+fn close_reports_damage() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open_with_sync_policy(&path, WriteOpenMode::MayExist, SyncPolicy::EveryN(10)).unwrap();
+    store.write(0, b"abc").unwrap();
+    store.close().unwrap();
+
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    store.write(3, b"def").unwrap();
+    let data_off = store.base.spans[&3].file_data_offset;
+    let mut f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.seek(SeekFrom::Start(data_off)).unwrap();
+    f.write_all(b"X").unwrap();
+    assert!(matches!(store.close(), Err(Error::CorruptRecord { .. })));
+}
+
+#[test]
+fn paranoid_reads_recheck() {
+    use std::io::Write;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open_paranoid(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(0, b"abcdef").unwrap();
+    store.write(2, b"XY").unwrap();
+    let mut buf = [0u8; 6];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abXYef");
+    drop(store);
+
+    let mut normal = open_readonly(&path).unwrap();
+    let mut paranoid = open_readonly_paranoid(&path).unwrap();
+    normal.read(0, &mut buf).unwrap();
+    paranoid.read(0, &mut buf).unwrap();
+
+    // Damage the overwritten part of the first record: it's still
+    // covered by its checksum, even though we don't read it.
+    let data_off = paranoid.base.spans[&0].file_data_offset;
+    let mut f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.seek(SeekFrom::Start(data_off + 2)).unwrap();
+    f.write_all(b"!").unwrap();
+
+    normal.read(0, &mut buf).unwrap();
+    assert!(matches!(paranoid.read(0, &mut buf), Err(Error::CorruptRecord { .. })));
+    // Doesn't touch the first record.
+    paranoid.read(2, &mut buf[..2]).unwrap();
+}
+
+#[test]
+fn open_creates_dirs() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a").join("b").join("s");
+
+    assert!(matches!(open(&path, WriteOpenMode::MayExist), Err(Error::Io(_))));
+    assert!(matches!(open_creating_dirs(&path, WriteOpenMode::MustExist), Err(Error::Io(_))));
+    assert!(!dir.path().join("a").exists());
+
+    let mut store = open_creating_dirs(&path, WriteOpenMode::MayExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    store.close().unwrap();
+    open_creating_dirs(&path, WriteOpenMode::MustExist).unwrap().close().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn created_with_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    open_with_permissions(&path, WriteOpenMode::MustNotExist, 0o600).unwrap().close().unwrap();
+    let perms = std::fs::metadata(&path).unwrap().permissions();
+    assert_eq!(perms.mode() & 0o777, 0o600);
+
+    // Only on creation.
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+    let mut store = open_with_permissions(&path, WriteOpenMode::MustExist, 0o600).unwrap();
+    let perms = std::fs::metadata(&path).unwrap().permissions();
+    assert_eq!(perms.mode() & 0o777, 0o640);
+
+    // Compaction keeps them.
+    store.base = compact(&mut store.base).unwrap();
+    let perms = std::fs::metadata(&path).unwrap().permissions();
+    assert_eq!(perms.mode() & 0o777, 0o640);
+}
+
+#[test]
+fn store_options_builder() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a").join("s");
+
+    let opts = StoreOptions::new()
+        .write_mode(WriteOpenMode::MustNotExist)
+        .sync_policy(SyncPolicy::EveryWrite)
+        .paranoid(true)
+        .create_dirs(true);
+    let mut store = opts.open(&path).unwrap();
+    assert!(store.base.opts.paranoid);
+    store.write(0, b"abc").unwrap();
+    store.close().unwrap();
+
+    // MustNotExist still applies.
+    assert!(matches!(opts.open(&path), Err(Error::Io(_))));
+
+    let mut store = opts.open_readonly(&path).unwrap();
+    assert!(store.base.opts.paranoid);
+    let mut buf = [0u8; 3];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abc");
+}
+
+#[test]
+fn was_created() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    assert!(store.was_created());
+    store.write(0, b"abc").unwrap();
+    store.close().unwrap();
+
+    let store = open(&path, WriteOpenMode::MayExist).unwrap();
+    assert!(!store.was_created());
+    store.close().unwrap();
+    assert!(!open_readonly(&path).unwrap().was_created());
+
+    // An empty file gets a header, so it's new too.
+    File::create(&path).unwrap();
+    let store = open(&path, WriteOpenMode::MustExist).unwrap();
+    assert!(store.was_created());
+    store.close().unwrap();
+}
+
+#[test]
+fn must_not_exist_leaves_no_partial_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s.syncless");
+    let files = || {
+        let mut names: Vec<String> = std::fs::read_dir(dir.path()).unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    };
+
+    // Files which look like ours are left alone.
+    let other = dir.path().join("s.create");
+    std::fs::write(&other, b"junk").unwrap();
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    assert!(store.was_created());
+    assert_eq!(files(), ["s.create", "s.syncless"]);
+    assert_eq!(std::fs::read(&other).unwrap(), b"junk");
+    store.write(0, b"abc").unwrap();
+    store.close().unwrap();
+
+    assert!(matches!(open(&path, WriteOpenMode::MustNotExist),
+                     Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::AlreadyExists));
+    assert_eq!(files(), ["s.create", "s.syncless"]);
+
+    // Stores with the same stem get their own.
+    let threads: Vec<_> = ["s.a", "s.b", "s.c", "s.d"].into_iter().map(|name| {
+        let path = dir.path().join(name);
+        std::thread::spawn(move || {
+            let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+            store.write(0, name.as_bytes()).unwrap();
+            store.close().unwrap();
+        })
+    }).collect();
+    for t in threads {
+        t.join().unwrap();
+    }
+    for name in ["s.a", "s.b", "s.c", "s.d"] {
+        let mut store = open_readonly(dir.path().join(name)).unwrap();
+        let mut buf = [0u8; 3];
+        store.read(0, &mut buf).unwrap();
+        assert_eq!(&buf, name.as_bytes());
+    }
+
+    let mut store = open_readonly(&path).unwrap();
+    let mut buf = [0u8; 3];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abc");
+}
+
+#[test]
+fn savepoint_rollback() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    let sp = store.savepoint();
+    store.write(1, b"XYZ").unwrap();
+    store.write(10, b"!").unwrap();
+    store.rollback(sp).unwrap();
+
+    let mut buf = [0u8; 3];
+    assert_eq!(store.size(), 3);
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abc");
+
+    // We can carry on writing, and reopening agrees.
+    store.write(3, b"d").unwrap();
+    store.close().unwrap();
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    let mut buf = [0u8; 4];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abcd");
+
+    // Not across compaction, though.
+    let sp = store.savepoint();
+    store.base = compact(&mut store.base).unwrap();
+    assert!(matches!(store.rollback(sp), Err(Error::SavepointLost)));
+    store.close().unwrap();
+}
+
+#[test]
+fn diff_stores() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut a = open(dir.path().join("a"), WriteOpenMode::MustNotExist).unwrap();
+    let mut b = open(dir.path().join("b"), WriteOpenMode::MustNotExist).unwrap();
+    assert_eq!(a.diff(&mut b).unwrap(), vec![]);
+
+    // Same contents, written differently (and a hole vs explicit zeros).
+    a.write(0, b"hello").unwrap();
+    a.write(200_000, b"world").unwrap();
+    b.write(0, b"he").unwrap();
+    b.write(2, b"llo\0\0").unwrap();
+    b.write(200_000, b"world").unwrap();
+    assert_eq!(a.diff(&mut b).unwrap(), vec![]);
+
+    b.write(1, b"EL").unwrap();
+    b.write(4, b"O").unwrap();
+    b.write(200_004, b"D!!").unwrap();
+    assert_eq!(a.diff(&mut b).unwrap(), vec![(1, 2), (4, 1), (200_004, 3)]);
+    assert_eq!(b.diff(&mut a).unwrap(), vec![(1, 2), (4, 1), (200_004, 3)]);
+
+    a.close().unwrap();
+    b.close().unwrap();
+}
+
+#[test]
+fn replication_sink() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"unseen").unwrap();
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    store.set_replication_sink(tx);
+    store.write(3, b"abc").unwrap();
+    store.append(b"de").unwrap();
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(),
+               vec![WriteEvent { logical_offset: 3, data: b"abc".to_vec() },
+                    WriteEvent { logical_offset: 6, data: b"de".to_vec() }]);
+
+    // Dropping the receiver just stops it.
+    drop(rx);
+    store.write(0, b"x").unwrap();
+    assert!(store.base.replication.is_none());
+    store.close().unwrap();
+}
+
+#[test]
+fn sync_store_threads() {
+    let dir = tempfile::tempdir().unwrap();
+    let store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    let shared = Arc::new(SyncStore::new(store));
+
+    let writers: Vec<_> = (0..4u8).map(|t| {
+        let shared = shared.clone();
+        std::thread::spawn(move || {
+            for _ in 0..100 {
+                shared.append(&[t; 10]).unwrap();
+            }
+        })
+    }).collect();
+    for w in writers {
+        w.join().unwrap();
+    }
+    shared.sync().unwrap();
+    assert_eq!(shared.size(), 4000);
+
+    // Each append landed whole.
+    let mut buf = [0u8; 10];
+    for off in (0..4000).step_by(10) {
+        shared.read(off, &mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == buf[0]));
+    }
+    Arc::try_unwrap(shared).ok().unwrap().into_inner().close().unwrap();
+}
+
+#[test]
+fn index_memory_grows() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    assert_eq!(store.index_memory_bytes(), 0);
+
+    store.write(0, b"a").unwrap();
+    let one = store.index_memory_bytes();
+    assert!(one > 0);
+    for i in 1..100 {
+        store.write(i * 2, b"b").unwrap();
+    }
+    assert_eq!(store.index_memory_bytes(), one * 100);
+    store.close().unwrap();
+}
+
+#[test]
+fn for_each_span_walks_layout() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abcdef").unwrap();
+    store.write(2, b"XY").unwrap();
+    store.write(10, b"z").unwrap();
+
+    let mut seen = Vec::new();
+    store.for_each_span(|off, len, file_off| {
+        seen.push((off, len, file_off));
+        Ok(())
+    }).unwrap();
+    assert_eq!(seen.iter().map(|&(off, len, _)| (off, len)).collect::<Vec<_>>(),
+               vec![(0, 2), (2, 2), (4, 2), (10, 1)]);
+    // The first and third pieces are from the same record.
+    assert_eq!(seen[2].2, seen[0].2 + 4);
+
+    // Errors stop the walk.
+    let mut calls = 0;
+    let res = store.for_each_span(|_, _, _| {
+        calls += 1;
+        Err(Error::NotSyncless)
+    });
+    assert!(matches!(res, Err(Error::NotSyncless)));
+    assert_eq!(calls, 1);
+    store.close().unwrap();
+}
+
+#[test]
+fn describe_layout_lists_spans() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abcdef").unwrap();
+    store.write(2, b"XY").unwrap();
+
+    let layout = store.describe_layout();
+    let lines: Vec<&str> = layout.lines().collect();
+    assert_eq!(lines.len(), 4);
+    let version = format!("version {}, generation 1", header::HeaderVer::CURRENT);
+    assert!(lines[0].contains(&version), "{}", lines[0]);
+    assert!(lines[0].ends_with("6 bytes of data in 3 spans"), "{}", lines[0]);
+    assert!(lines[1].starts_with("  logical [0..2) -> file "), "{}", lines[1]);
+    assert!(lines[2].ends_with("validated=false"), "{}", lines[2]);
+    store.close().unwrap();
+}
+
+#[test]
+fn size_histogram_buckets() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    assert_eq!(store.size_histogram(), vec![]);
+
+    store.write(0, &[1; 1]).unwrap();
+    store.write(10, &[1; 3]).unwrap();
+    store.write(20, &[1; 2]).unwrap();
+    store.write(30, &[1; 1000]).unwrap();
+    assert_eq!(store.size_histogram(), vec![(1, 1), (2, 2), (512, 1)]);
+    store.close().unwrap();
+}
+
+#[test]
+fn overhead_constants() {
+    let dir = tempfile::tempdir().unwrap();
+
+    // One store each, since a big file gets compacted.
+    let mut store = open(dir.path().join("short"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    store.validate_all().unwrap();
+    let (_, file_len) = store.into_parts();
+    assert_eq!(file_len as usize, crate::HEADER_SIZE + crate::RECORD_OVERHEAD + 3);
+
+    let mut store = open(dir.path().join("long"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, &vec![1u8; crate::MAX_RECORD_SIZE]).unwrap();
+    store.validate_all().unwrap();
+    let (_, file_len) = store.into_parts();
+    assert_eq!(file_len as usize,
+               crate::HEADER_SIZE + crate::LONG_RECORD_OVERHEAD + crate::MAX_RECORD_SIZE);
+}
+
+#[test]
+fn huge_lengths_are_out_of_range() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abc").unwrap();
+
+    // Adding the header and checksum would overflow.
+    assert!(matches!(record::validate(&mut store.base.file, RecordFormat::CURRENT, 100, usize::MAX - 4),
+                     Err(Error::OutOfRange)));
+    if usize::BITS < 64 {
+        assert!(matches!(record::to_usize(u64::MAX), Err(Error::OutOfRange)));
+        assert!(matches!(validate_record_with_retry(&mut store.base.file, RecordFormat::CURRENT,
+                                                    &RetryPolicy::default(), 100, u64::MAX),
+                         Err(Error::OutOfRange)));
+    }
+    assert_eq!(record::to_usize(3).unwrap(), 3);
+    store.close().unwrap();
+}
+
+#[test]
+fn failed_record_seeks_back_to_start() {
+    use crate::storage::CountingStorage;
+
+    let mut storage = CountingStorage::new();
+    storage.write_all(b"junk!").unwrap();
+    let start = 5;
+    let mut file_size = start;
+    record::write_record(&mut storage, RecordFormat::CURRENT, 0, b"abc", &mut file_size).unwrap();
+    let whole = storage.bytes_mut().clone();
+
+    // Header intact, but nothing after it: the last read returns 0.
+    storage.bytes_mut().truncate(start as usize + 15);
+    storage.seek(SeekFrom::Start(start)).unwrap();
+    let mut end = start;
+    assert!(matches!(record::read_next_record(&mut storage, RecordFormat::CURRENT, &mut end, true).unwrap(),
+                     Err(LogEnd::Truncated)));
+    assert_eq!((end, storage.stream_position().unwrap()), (start, start));
+
+    // A long record claiming an enormous length.
+    *storage.bytes_mut() = whole;
+    storage.bytes_mut()[start as usize + 3] += 1;
+    storage.bytes_mut()[start as usize + 12..start as usize + 20].copy_from_slice(&u64::MAX.to_le_bytes());
+    storage.seek(SeekFrom::Start(start)).unwrap();
+    assert!(matches!(record::read_next_record(&mut storage, RecordFormat::CURRENT, &mut end, true).unwrap(),
+                     Err(LogEnd::TooLong)));
+    assert_eq!((end, storage.stream_position().unwrap()), (start, start));
+}
+
+#[test]
+fn torn_write_is_discarded() {
+    use crate::storage::TornStorage;
+
+    // Replay from the start, applying records in order.
+    fn replay(storage: &mut TornStorage) -> Vec<u8> {
+        let mut contents = Vec::new();
+        let mut end = 0;
+        storage.seek(SeekFrom::Start(0)).unwrap();
+        while let Ok(rec) = record::read_next_record(storage, RecordFormat::CURRENT, &mut end, true).unwrap() {
+            let off = rec.hdr.logical_offset as usize;
+            let len = rec.hdr.length as usize;
+            let mut data = vec![0u8; len];
+            storage.seek(SeekFrom::Start(rec.file_data_offset)).unwrap();
+            storage.read_exact(&mut data).unwrap();
+            storage.seek(SeekFrom::Start(end)).unwrap();
+            if contents.len() < off + len {
+                contents.resize(off + len, 0);
+            }
+            contents[off..off + len].copy_from_slice(&data);
+        }
+        contents
+    }
+
+    let record_len = crate::RECORD_OVERHEAD + 3;
+    for cut in 0..record_len {
+        let mut storage = TornStorage::new();
+        let mut file_size = 0;
+        record::write_record(&mut storage, RecordFormat::CURRENT, 0, b"hello", &mut file_size).unwrap();
+        record::write_record(&mut storage, RecordFormat::CURRENT, 1, b"XY", &mut file_size).unwrap();
+        let before = file_size;
+
+        // The write fails part way through: nothing changes.
+        storage.budget = Some(cut);
+        assert!(record::write_record(&mut storage, RecordFormat::CURRENT, 2, b"abc", &mut file_size).is_err());
+        assert_eq!(file_size, before);
+        assert_eq!(replay(&mut storage), b"hXYlo", "cut at {}", cut);
+
+        // Like Store::write, the next write goes over the torn one.
+        storage.budget = None;
+        storage.seek(SeekFrom::Start(file_size)).unwrap();
+        record::write_record(&mut storage, RecordFormat::CURRENT, 0, b"Z", &mut file_size).unwrap();
+        assert_eq!(replay(&mut storage), b"ZXYlo", "cut at {}", cut);
+    }
+}
+
+#[test]
+fn anonymous_store() {
+    let mut store = open_anonymous().unwrap();
+    assert!(store.was_created());
+    store.write(0, b"hello").unwrap();
+    store.write(1, b"EL").unwrap();
+
+    // Compaction can't rename, but still works.
+    store.base = compact(&mut store.base).unwrap();
+    assert!(store.base.anonymous);
+    store.write(5, b"!").unwrap();
+
+    let mut buf = [0u8; 6];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"hELlo!");
+    store.close().unwrap();
+}
+
+#[test]
+fn hole_fill_pattern() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = StoreOptions::new().hole_fill(0xFF).open(&path).unwrap();
+    store.write(2, b"ab").unwrap();
+    store.write(6, b"c").unwrap();
+    let mut buf = [0u8; 9];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"\xFF\xFFab\xFF\xFFc\xFF\xFF");
+    store.close().unwrap();
+
+    // It's in the header, so the option doesn't matter now.
+    let mut store = StoreOptions::new().hole_fill(0x55).open_readonly(&path).unwrap();
+    assert_eq!(store.hole_fill(), 0xFF);
+    let mut buf = [0u8; 9];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"\xFF\xFFab\xFF\xFFc\xFF\xFF");
+
+    // Compaction keeps it, and its holes (now data).
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    store.base = compact(&mut store.base).unwrap();
+    assert_eq!(store.hole_fill(), 0xFF);
+    let mut buf = [0u8; 9];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"\xFF\xFFab\xFF\xFFc\xFF\xFF");
+    store.close().unwrap();
+
+    // Default is zero.
+    let store = open(dir.path().join("t"), WriteOpenMode::MustNotExist).unwrap();
+    assert_eq!(store.hole_fill(), 0);
+    store.close().unwrap();
+}
+
+#[test]
+fn list_holes_in_range() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    assert_eq!(store.list_holes(0, 100), vec![]);
+
+    store.write(2, b"ab").unwrap();
+    store.write(6, b"cd").unwrap();
+    store.write(10, b"e").unwrap();
+    assert_eq!(store.list_holes(0, 100), vec![(0, 2), (4, 2), (8, 2)]);
+    assert_eq!(store.list_holes(3, 9), vec![(4, 2), (8, 1)]);
+    assert_eq!(store.list_holes(5, 5), vec![]);
+    assert_eq!(store.list_holes(20, 30), vec![]);
+    store.close().unwrap();
+}
+
+#[test]
+fn physical_offsets() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abcdef").unwrap();
+    store.write(2, b"XY").unwrap();
+    store.write(10, b"z").unwrap();
+    store.close().unwrap();
+
+    let store = open_readonly(&path).unwrap();
+    let raw = std::fs::read(&path).unwrap();
+    for (logical, &expect) in b"abXYef\0\0\0\0z".iter().enumerate() {
+        match store.physical_offset(logical as u64) {
+            Some(off) => assert_eq!(raw[off as usize], expect),
+            None => assert!((6..10).contains(&logical)),
+        }
+    }
+    assert_eq!(store.physical_offset(11), None);
+    assert_eq!(store.physical_offset(u64::MAX), None);
+}
+
+#[test]
+fn store_ids() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = open(dir.path().join("a"), WriteOpenMode::MustNotExist).unwrap();
+    let b = open(dir.path().join("b"), WriteOpenMode::MustNotExist).unwrap();
+    let mut c = open_anonymous().unwrap();
+    assert_ne!(a.id(), [0; 16]);
+    assert_ne!(a.id(), b.id());
+    assert_ne!(a.id(), c.id());
+
+    // It sticks, through reopening and compaction.
+    let id = a.id();
+    a.close().unwrap();
+    b.close().unwrap();
+    let mut a = open(dir.path().join("a"), WriteOpenMode::MustExist).unwrap();
+    assert_eq!(a.id(), id);
+    a.base = compact(&mut a.base).unwrap();
+    assert_eq!(a.id(), id);
+    a.close().unwrap();
+    assert_eq!(open_readonly(dir.path().join("a")).unwrap().id(), id);
+
+    let id = c.id();
+    c.base = compact(&mut c.base).unwrap();
+    assert_eq!(c.id(), id);
+    c.close().unwrap();
+}
+
+#[test]
+fn creation_time() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+    let before = SystemTime::now() - Duration::from_secs(1);
+    let store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    let created = store.created_at().unwrap();
+    assert!(created >= before && created <= SystemTime::now());
+    store.close().unwrap();
+
+    assert_eq!(open_readonly(&path).unwrap().created_at(), Some(created));
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    store.base = compact(&mut store.base).unwrap();
+    assert_eq!(store.created_at(), Some(created));
+    store.close().unwrap();
+}
+
+#[test]
+fn header_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+    let opts = StoreOptions::new()
+        .metadata(0x80, b"myapp")
+        .metadata(0x81, b"v1")
+        .metadata(0x81, b"v2")
+        .metadata(0xFF, &[]);
+    let store = opts.open(&path).unwrap();
+    assert_eq!(store.metadata(0x80), Some(&b"myapp"[..]));
+    store.close().unwrap();
+
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    assert_eq!(store.metadata(0x80), Some(&b"myapp"[..]));
+    assert_eq!(store.metadata(0x81), Some(&b"v2"[..]));
+    assert_eq!(store.metadata(0xFF), Some(&b""[..]));
+    assert_eq!(store.metadata(0x82), None);
+    store.base = compact(&mut store.base).unwrap();
+    assert_eq!(store.metadata(0x81), Some(&b"v2"[..]));
+    store.close().unwrap();
+
+    // Unknown tags are kept, nonsense is ignored.
+    let mut raw = std::fs::read(&path).unwrap();
+    raw[45..52].copy_from_slice(&[0x05, 1, b'x', 0x90, 200, b'y', b'z']);
+    std::fs::write(&path, &raw).unwrap();
+    let store = open_readonly(&path).unwrap();
+    assert_eq!(store.base.fields.ext, vec![(0x05, b"x".to_vec())]);
+    assert_eq!(store.metadata(0x90), None);
+}
+
+#[test]
+fn header_metadata_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+    let opts = StoreOptions::new().metadata(0x80, &[0; 100]).metadata(0x81, &[0; 30]);
+    assert!(matches!(opts.open(&path), Err(Error::OutOfRange)));
+    assert!(matches!(opts.open_anonymous(), Err(Error::OutOfRange)));
+    assert!(!path.exists());
+
+    // Replacing a value frees its room.
+    opts.metadata(0x81, &[0; 20]).open(&path).unwrap().close().unwrap();
+}
+
+#[test]
+fn header_metadata_reserved_tag() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+    let opts = StoreOptions::new().metadata(0x7F, b"mine");
+    assert!(matches!(opts.open(&path), Err(Error::OutOfRange)));
+    assert!(!path.exists());
+}
+
+#[test]
+fn fixed_size_header() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    assert_eq!(store.physical_offset(0), Some(256 + 15));
+    store.close().unwrap();
+
+    // A later minor version's fields don't bother us.
+    let mut raw = std::fs::read(&path).unwrap();
+    raw[10] = 7;
+    raw[200] = 7;
+    std::fs::write(&path, &raw).unwrap();
+    let mut store = open_readonly(&path).unwrap();
+    let mut buf = [0u8; 3];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abc");
+}
+
+#[test]
+fn skip_checksum_on_open() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"0123456789").unwrap();
+    store.write(3, b"abc").unwrap();
+    store.write(20, b"tail").unwrap();
+    store.close().unwrap();
+
+    let mut store = StoreOptions::new().skip_checksum_on_open(true).open_readonly(&path).unwrap();
+    assert!(!store.is_fully_validated());
+    assert_eq!(store.size(), 24);
+    let mut buf = [0u8; 10];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"012abc6789");
+    assert_eq!(store.metrics().records_validated, 2);
+    drop(store);
+
+    // Damage the last record: a normal open drops it, we only notice on read.
+    let mut raw = std::fs::read(&path).unwrap();
+    let len = raw.len();
+    raw[len - 9] ^= 1;
+    std::fs::write(&path, &raw).unwrap();
+    assert_eq!(open_readonly(&path).unwrap().size(), 10);
+
+    let mut store = StoreOptions::new()
+        .write_mode(WriteOpenMode::MustExist)
+        .skip_checksum_on_open(true)
+        .open(&path)
+        .unwrap();
+    assert_eq!(store.size(), 24);
+    store.read(0, &mut buf).unwrap();
+    assert!(matches!(store.read(20, &mut buf[..4]), Err(Error::CorruptRecord { .. })));
+    assert!(matches!(store.write(21, b"x"), Err(Error::CorruptRecord { .. })));
+
+    // A torn record is still spotted.
+    std::fs::write(&path, &raw[..len - 3]).unwrap();
+    let store = StoreOptions::new().skip_checksum_on_open(true).open_readonly(&path).unwrap();
+    assert_eq!(store.size(), 10);
+}
+
+#[test]
+fn read_coalesce_gap() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = StoreOptions::new().hole_fill(b'.').open(&path).unwrap();
+    store.write(0, b"0123456789").unwrap();
+    store.write(12, b"abcdef").unwrap();
+    store.write(4, b"XY").unwrap();
+    store.write(20, b"tail").unwrap();
+    store.write(14, b"Z").unwrap();
+    store.close().unwrap();
+
+    let mut plain = open_readonly(&path).unwrap();
+    let mut expected = [0u8; 30];
+    plain.read(0, &mut expected).unwrap();
+    assert_eq!(&expected, b"0123XY6789..abZdef..tail......");
+
+    for gap in [1, 23, 24, 100, u64::MAX] {
+        let mut store = StoreOptions::new().read_coalesce_gap(gap).open_readonly(&path).unwrap();
+        for start in 0..30 {
+            for end in start..=30 {
+                let mut buf = [0u8; 30];
+                store.read(start as u64, &mut buf[start..end]).unwrap();
+                assert_eq!(buf[start..end], expected[start..end], "gap {} [{}..{})", gap, start, end);
+            }
+        }
+    }
+}
+
+#[test]
+fn shrink_to_fit() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    assert_eq!(store.shrink_to_fit().unwrap(), 0);
+    for i in 0..10u8 {
+        store.write(0, &[i; 100]).unwrap();
+    }
+    let sp = store.savepoint();
+    let old_len = std::fs::metadata(&path).unwrap().len();
+    let reclaimed = store.shrink_to_fit().unwrap();
+    assert_eq!(reclaimed, 9 * (100 + crate::RECORD_OVERHEAD as u64));
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), old_len - reclaimed);
+    assert_eq!(store.shrink_to_fit().unwrap(), 0);
+    assert!(matches!(store.rollback(sp), Err(Error::SavepointLost)));
+
+    let mut buf = [0u8; 100];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(buf, [9; 100]);
+    store.close().unwrap();
+
+    // Holes stay holes: only the overwritten record goes.
+    let mut store = open(dir.path().join("sparse"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"a").unwrap();
+    store.write(0, b"b").unwrap();
+    store.write(100_000, b"c").unwrap();
+    assert_eq!(store.shrink_to_fit().unwrap(), 1 + crate::RECORD_OVERHEAD as u64);
+    assert_eq!(store.shrink_to_fit().unwrap(), 0);
+    assert_eq!(store.record_offsets().unwrap().len(), 2);
+    let mut buf = [1u8; 2];
+    store.read(99_999, &mut buf).unwrap();
+    assert_eq!(buf, [0, b'c']);
+}
+
+#[test]
+fn readonly_try_clone() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"0123456789").unwrap();
+    store.write(20, b"abcdefghij").unwrap();
+    store.write(5, b"XY").unwrap();
+    let original = store.into_readonly().unwrap();
+
+    // Interleave reads, so each moves the cursor under the other.
+    let mut stores = [original.try_clone().unwrap(), original];
+    let mut buf = [0u8; 5];
+    for (i, off, expect) in [(0, 20, b"abcde"), (1, 0, b"01234"), (0, 25, b"fghij"),
+                             (1, 20, b"abcde"), (0, 0, b"01234"), (1, 25, b"fghij")] {
+        stores[i].read(off, &mut buf).unwrap();
+        assert_eq!(&buf, expect);
+    }
+    let [clone, original] = stores;
+    drop(original);
+    assert_eq!(clone.size(), 30);
+    assert_eq!(clone.generation(), 1);
+    assert_eq!(clone.metrics().reads, 3);
+}
+
+#[test]
+fn file_fingerprint() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+    let copy = dir.path().join("copy");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, &[7; 100_000]).unwrap();
+    store.write(10, b"abc").unwrap();
+    let fingerprint = store.file_fingerprint().unwrap();
+    store.close().unwrap();
+
+    // Same bytes, same fingerprint: even with a torn write on the end.
+    std::fs::copy(&path, &copy).unwrap();
+    let mut raw = std::fs::OpenOptions::new().append(true).open(&copy).unwrap();
+    std::io::Write::write_all(&mut raw, &[0xF5, 0x9C]).unwrap();
+    let mut other = open_readonly(&copy).unwrap();
+    assert_eq!(other.file_fingerprint().unwrap(), fingerprint);
+
+    // The same contents, written differently.
+    let mut other = open(dir.path().join("other"), WriteOpenMode::MustNotExist).unwrap();
+    other.write(0, &[7; 100_000]).unwrap();
+    other.write(10, b"abc").unwrap();
+    assert_ne!(other.file_fingerprint().unwrap(), fingerprint);
+
+    let mut store = open_readonly(&path).unwrap();
+    assert_eq!(store.file_fingerprint().unwrap(), fingerprint);
+    let mut buf = [0u8; 3];
+    store.read(10, &mut buf).unwrap();
+    assert_eq!(&buf, b"abc");
+}
+
+#[test]
+fn prefetch() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, &[1; 10_000]).unwrap();
+    store.write(20_000, &[2; 100]).unwrap();
+    store.write(5_000, &[3; 10]).unwrap();
+
+    // The first record is split around the last: one range covers it all.
+    let first = store.physical_offset(0).unwrap();
+    let last = store.physical_offset(5_000).unwrap();
+    assert_eq!(store.base.file_ranges(0, 10_000), vec![(first, last + 10 - first)]);
+    assert_eq!(store.base.file_ranges(10_000, 20_000), vec![]);
+    assert_eq!(store.base.file_ranges(20_050, 30_000).len(), 1);
+
+    store.prefetch(0, u64::MAX).unwrap();
+    store.prefetch(30_000, 10).unwrap();
+    let mut buf = [0u8; 10];
+    store.read(4_995, &mut buf).unwrap();
+    assert_eq!(buf, [1, 1, 1, 1, 1, 3, 3, 3, 3, 3]);
+
+    // Dropping it from the cache doesn't lose anything.
+    store.base.file.sync_data().unwrap();
+    store.advise_dontneed(0, u64::MAX).unwrap();
+    store.read(4_995, &mut buf).unwrap();
+    assert_eq!(buf, [1, 1, 1, 1, 1, 3, 3, 3, 3, 3]);
+}
+
+#[test]
+fn transactions() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"before").unwrap();
+
+    let mut tx = store.transaction();
+    tx.write(0, b"BE").unwrap();
+    tx.write(6, b" and after").unwrap();
+    let mut buf = [0u8; 16];
+    tx.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"BEfore and after");
+    tx.commit().unwrap();
+
+    // Dropped (or aborted) ones vanish.
+    let len = std::fs::metadata(&path).unwrap().len();
+    let mut tx = store.transaction();
+    tx.write(0, b"XXXXXXXXXXXXXXXXXXXX").unwrap();
+    drop(tx);
+    let mut tx = store.transaction();
+    tx.write(100, b"Y").unwrap();
+    tx.abort().unwrap();
+    assert_eq!(store.size(), 16);
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"BEfore and after");
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+
+    // Even if we panic, or write enough that we'd normally compact.
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        let mut tx = store.transaction();
+        for _ in 0..3 {
+            tx.write(0, &[0xAA; 500_000]).unwrap();
+        }
+        panic!("oops");
+    }));
+    assert!(result.is_err());
+    assert_eq!(store.size(), 16);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    store.close().unwrap();
+
+    let mut store = open_readonly(&path).unwrap();
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"BEfore and after");
+}
+
+#[test]
+fn undo_last() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"one").unwrap();
+    store.write(3, b"two").unwrap();
+    store.write(0, b"ONE").unwrap();
+    store.write(6, b"three").unwrap();
+    let sp = store.savepoint();
+    store.undo_last(0).unwrap();
+    store.undo_last(2).unwrap();
+
+    assert_eq!(store.size(), 6);
+    let mut buf = [0u8; 6];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"onetwo");
+    assert!(matches!(store.rollback(sp), Err(Error::SavepointLost)));
+    assert!(matches!(store.undo_last(3), Err(Error::SavepointLost)));
+    store.write(6, b"!").unwrap();
+    store.close().unwrap();
+
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    let mut buf = [0u8; 7];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"onetwo!");
+    store.undo_last(3).unwrap();
+    assert_eq!(store.size(), 0);
+
+    // Only what we wrote since compacting.
+    store.write(0, b"x").unwrap();
+    store.base = compact(&mut store.base).unwrap();
+    store.write(1, b"y").unwrap();
+    assert!(matches!(store.undo_last(2), Err(Error::SavepointLost)));
+    store.undo_last(1).unwrap();
+    assert_eq!(store.size(), 1);
+}
+
+#[test]
+fn record_offsets() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+    let header = header::HEADER_SIZE as u64;
+    let rec = |len: u64| record::RECORD_OVERHEAD as u64 + len;
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    assert_eq!(store.record_offsets().unwrap(), []);
+    store.write(0, b"one").unwrap();
+    store.write(3, b"four").unwrap();
+    let sp = store.savepoint();
+    store.write(0, b"x").unwrap();
+    assert_eq!(store.record_offsets().unwrap(), [header, header + rec(3), header + rec(3) + rec(4)]);
+    store.rollback(sp).unwrap();
+    store.close().unwrap();
+
+    // Replay finds the same ones.
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    assert_eq!(store.record_offsets().unwrap(), [header, header + rec(3)]);
+    store.undo_last(1).unwrap();
+    assert_eq!(store.record_offsets().unwrap(), [header]);
+    store.base = compact(&mut store.base).unwrap();
+    store.write(3, b"!").unwrap();
+    assert_eq!(store.record_offsets().unwrap(), [header, header + rec(3)]);
+}
+
+#[test]
+fn fsck() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    let data_off = store.base.spans[&0].file_data_offset;
+    store.write(0, b"def").unwrap();
+    assert_eq!(store.fsck().unwrap(), None);
+
+    // Damage the overwritten record: reads don't care, fsck does.
+    let mut f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.seek(SeekFrom::Start(data_off)).unwrap();
+    f.write_all(b"X").unwrap();
+    let mut buf = [0u8; 3];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(store.fsck().unwrap(), Some(header::HEADER_SIZE as u64));
+}
+
+#[test]
+fn write_tracked() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+
+    let offsets = store.write_tracked(0, b"hello").unwrap();
+    assert_eq!(offsets, [store.physical_offset(0).unwrap()]);
+    let offsets = store.write_tracked(2, b"LL").unwrap();
+    assert_eq!(offsets, [store.physical_offset(2).unwrap()]);
+    assert_eq!(store.write_tracked(9, b"").unwrap(), []);
+
+    // Big enough to compact.
+    let big = vec![1u8; 1_000_001];
+    let offsets = store.write_tracked(0, &big).unwrap();
+    assert_eq!(store.record_offsets().unwrap().len(), 1);
+    assert_eq!(offsets, [store.physical_offset(0).unwrap()]);
+}
+
+#[cfg(unix)]
+#[test]
+fn readonly_never_writes() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    store.write(3, b"def").unwrap();
+    let data_off = store.base.spans[&3].file_data_offset;
+    store.close().unwrap();
+    let mut f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.seek(SeekFrom::Start(data_off)).unwrap();
+    f.write_all(b"X").unwrap();
+    drop(f);
+
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o444)).unwrap();
+    let contents = std::fs::read(&path).unwrap();
+    let modified = std::fs::metadata(&path).unwrap().modified().unwrap();
+
+    // Skip checksums, so reads find the damage (and would retry).
+    let policy = RetryPolicy { retries: 3, sync: RetrySync::All };
+    let mut store = StoreOptions::new()
+        .skip_checksum_on_open(true)
+        .retry_policy(policy)
+        .open_readonly(&path)
+        .unwrap();
+    assert_eq!(store.base.opts.retry_policy.retries, 0);
+    let mut buf = [0u8; 3];
+    store.read(0, &mut buf).unwrap();
+    assert!(matches!(store.read(3, &mut buf), Err(Error::CorruptRecord { .. })));
+    drop(store);
+
+    assert_eq!(std::fs::read(&path).unwrap(), contents);
+    assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), modified);
+}
 
-        let to_validate: Vec<(u64, u64, u64)> = self.base.spans
-            .range((Included(start), Excluded(end)))
-            .filter_map(|(&off, span)| {
-                if span.validated {
-                    None
-                } else {
-                    Some((off, span.file_data_offset, span.len))
-                }
-            })
-            .collect();
+#[test]
+fn max_replay_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
 
-        // Validate them all.
-        for &(_, file_data_offset, length) in &to_validate {
-            validate_record_with_retry(&mut self.base.file, file_data_offset, length)?;
-        }
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    for i in 0..5u8 {
+        store.write(i as u64, &[b'a' + i]).unwrap();
+    }
+    store.write(0, b"A").unwrap();
+    store.close().unwrap();
 
-        // Set them all valid.
-        for &(off, _, _) in &to_validate {
-            let span = self.base.spans.get_mut(&off).unwrap();
-            span.validated = true;
-        }
-        Ok(())
+    let opts = StoreOptions::new().max_replay_records(Some(2));
+    let mut store = opts.open_readonly(&path).unwrap();
+    assert!(!store.fully_loaded());
+    assert_eq!(store.size(), 2);
+    let mut clone = store.try_clone().unwrap();
+
+    let mut buf = [0u8; 5];
+    store.read(0, &mut buf).unwrap();
+    assert!(store.fully_loaded());
+    assert_eq!(&buf, b"Abcde");
+    assert_eq!(store.record_offsets().unwrap().len(), 6);
+
+    assert!(!clone.fully_loaded());
+    clone.finish_replay().unwrap();
+    assert!(clone.fully_loaded());
+    assert_eq!(clone.size(), 5);
+
+    // Nothing left over, and nothing at all.
+    let store = StoreOptions::new().max_replay_records(Some(6)).open_readonly(&path).unwrap();
+    assert!(store.fully_loaded());
+    let mut store = StoreOptions::new().max_replay_records(Some(0)).open_readonly(&path).unwrap();
+    assert_eq!(store.size(), 0);
+    assert_eq!(store.content_hash().unwrap(), open_readonly(&path).unwrap().content_hash().unwrap());
+
+    // Writable opens replay everything.
+    let store = opts.open(&path).unwrap();
+    assert!(store.fully_loaded());
+    assert_eq!(store.size(), 5);
+}
+
+#[test]
+fn lazy_replay() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"hello").unwrap();
+    store.write(4, b"!").unwrap();
+    store.close().unwrap();
+
+    let mut store = StoreOptions::new().lazy_replay(true).open_readonly(&path).unwrap();
+    assert!(!store.fully_loaded());
+    assert_eq!(store.metrics(), Metrics::default());
+    let mut buf = [0u8; 1];
+    store.read(4, &mut buf).unwrap();
+    assert_eq!(&buf, b"!");
+    assert_eq!(store.size(), 5);
+
+    let store = StoreOptions::new().lazy_replay(true).lazy_replay(false).open_readonly(&path).unwrap();
+    assert!(store.fully_loaded());
+
+    // Not lazy doesn't mean unlimited.
+    let store = StoreOptions::new().max_replay_records(Some(1)).lazy_replay(false).open_readonly(&path).unwrap();
+    assert!(!store.fully_loaded());
+    assert_eq!(store.size(), 5);
+}
+
+#[test]
+fn contents_eq() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut a = open(dir.path().join("a"), WriteOpenMode::MustNotExist).unwrap();
+    let mut b = open(dir.path().join("b"), WriteOpenMode::MustNotExist).unwrap();
+    assert!(a.contents_eq(&mut b).unwrap());
+
+    // Same bytes, different writes (and a hole which is written as zeros).
+    a.write(0, b"hello").unwrap();
+    a.write(100_000, b"world").unwrap();
+    b.write(0, b"HELLO").unwrap();
+    b.write(1, b"ello").unwrap();
+    b.write(100_000, b"world").unwrap();
+    assert!(!a.contents_eq(&mut b).unwrap());
+    b.write(0, b"h").unwrap();
+    b.write(70_000, &[0; 10]).unwrap();
+    assert!(a.contents_eq(&mut b).unwrap());
+    assert!(b.contents_eq(&mut a).unwrap());
+
+    b.write(100_005, b"!").unwrap();
+    assert!(!a.contents_eq(&mut b).unwrap());
+    a.write(100_005, b"?").unwrap();
+    assert!(!a.contents_eq(&mut b).unwrap());
+
+    let mut c = StoreOptions::new().hole_fill(1).open(dir.path().join("c")).unwrap();
+    c.write(0, b"hello").unwrap();
+    c.write(100_000, b"world?").unwrap();
+    assert!(!a.contents_eq(&mut c).unwrap());
+}
+
+#[test]
+fn sampled_checksums() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+    let big: Vec<u8> = (0..20_000u32).map(|i| i as u8).collect();
+
+    let mut store = StoreOptions::new().sampled_checksums(true).open(&path).unwrap();
+    assert_eq!(store.base.format, RecordFormat::Sampled);
+    store.write(0, &big).unwrap();
+    let big_off = store.base.spans[&0].file_data_offset;
+    store.write(30_000, b"small").unwrap();
+    let small_off = store.base.spans[&30_000].file_data_offset;
+    let store = store.into_readonly().unwrap();
+    assert!(store.is_fully_validated());
+    drop(store);
+    assert_eq!(std::fs::read(&path).unwrap()[9], 1);
+
+    // Damage the middle of the big record: nobody notices.
+    let mut f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.seek(SeekFrom::Start(big_off + 10_000)).unwrap();
+    f.write_all(b"X").unwrap();
+    let mut store = open_readonly(&path).unwrap();
+    assert_eq!(store.size(), 30_005);
+    let mut buf = [0u8; 2];
+    store.read(9_999, &mut buf).unwrap();
+    assert_eq!(buf, [big[9_999], b'X']);
+
+    // But the ends are checked, as are small records.
+    f.seek(SeekFrom::Start(small_off)).unwrap();
+    f.write_all(b"X").unwrap();
+    assert_eq!(open_readonly(&path).unwrap().size(), 20_000);
+    f.seek(SeekFrom::Start(big_off + 19_999)).unwrap();
+    f.write_all(b"X").unwrap();
+    assert_eq!(open_readonly(&path).unwrap().size(), 0);
+
+    // Existing stores keep their format, even through compaction.
+    let mut store = StoreOptions::new().sampled_checksums(true).open(dir.path().join("t")).unwrap();
+    store.write(0, &big).unwrap();
+    store.base = compact(&mut store.base).unwrap();
+    assert_eq!(store.base.format, RecordFormat::Sampled);
+    store.close().unwrap();
+    let store = open(dir.path().join("t"), WriteOpenMode::MustExist).unwrap();
+    assert_eq!(store.base.format, RecordFormat::Sampled);
+    assert_eq!(store.size(), 20_000);
+}
+
+#[test]
+fn checkpoint() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    assert_eq!(store.last_checkpoint(), None);
+    store.write(0, b"hello").unwrap();
+    store.checkpoint(2).unwrap();
+    store.write(0, b"world").unwrap();
+    store.checkpoint(1).unwrap();
+    assert_eq!(store.last_checkpoint(), Some(2));
+    assert_eq!(store.size(), 5);
+    assert_eq!(store.record_offsets().unwrap().len(), 4);
+    store.close().unwrap();
+
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    assert_eq!(store.last_checkpoint(), Some(2));
+    assert_eq!(store.size(), 5);
+
+    let sp = store.savepoint();
+    store.checkpoint(7).unwrap();
+    assert_eq!(store.last_checkpoint(), Some(7));
+    store.rollback(sp).unwrap();
+    assert_eq!(store.last_checkpoint(), Some(2));
+    store.undo_last(3).unwrap();
+    assert_eq!(store.last_checkpoint(), None);
+
+    // Compaction keeps (just) the latest.
+    store.checkpoint(3).unwrap();
+    store.checkpoint(5).unwrap();
+    assert_eq!(store.shrink_to_fit().unwrap(), crate::RECORD_OVERHEAD as u64);
+    assert_eq!(store.last_checkpoint(), Some(5));
+    assert_eq!(store.shrink_to_fit().unwrap(), 0);
+    store.close().unwrap();
+    let mut store = open_readonly(&path).unwrap();
+    assert_eq!(store.last_checkpoint(), Some(5));
+    assert_eq!(store.record_offsets().unwrap().len(), 2);
+    let mut buf = [0u8; 5];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+
+    // 0.1 files can have empty records which aren't checkpoints.
+    let path = dir.path().join("legacy");
+    let legacy_hdr = b"Syncless\0\0\0\0";
+    let mut file_len = legacy_hdr.len() as u64;
+    {
+        let mut f = File::create(&path).unwrap();
+        std::io::Write::write_all(&mut f, legacy_hdr).unwrap();
+        record::write_record(&mut f, RecordFormat::Legacy, 0, &[], &mut file_len).unwrap();
     }
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    assert_eq!(store.last_checkpoint(), None);
+    store.checkpoint(0).unwrap();
+    assert_eq!(store.base.format, RecordFormat::CURRENT);
+    store.close().unwrap();
+    assert_eq!(open_readonly(&path).unwrap().last_checkpoint(), Some(0));
+}
 
-    /// Reads `buf.len()` bytes starting at `offset`.
-    ///
-    /// The read is performed against the reconstructed logical view of the
-    /// store.  If there's a hole, or past EOF, it will read as all zeros.
-    ///
-    /// # Errors
-    ///
-    /// Return zeros past the logical size of the store (see size()), and an
-    /// error on underlying I/O error.
-    pub fn read(&mut self, mut offset: u64, mut buf: &mut [u8]) -> Result<(), Error> {
-        // Holes are zeros, so simply zero it out to start.
-        buf.fill(0);
+#[test]
+fn store_reader() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
 
-        let prev = self.prev_offset(offset);
-        self.validate_range(prev, offset + buf.len() as u64)?;
+    let mut store = StoreOptions::new().hole_fill(b'-').open(&path).unwrap();
+    store.write(0, b"first line\nsecond").unwrap();
+    store.write(20, b" line\n").unwrap();
+    let big: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    store.write(100, &big).unwrap();
 
-        // End of previous span may overlap.
-        if let Some(span) = self.base.spans.get(&prev) {
-            if prev + span.len > offset {
-                // FIXME: mmap
-                let bytes_before = offset - prev;
-                let len = min(span.len - bytes_before, buf.len() as u64);
-                self.base.file.seek(SeekFrom::Start(span.file_data_offset + bytes_before))?;
-                self.base.file.read_exact(&mut buf[..len as usize])?;
-                offset += len;
-                buf = &mut buf[len as usize..];
-            }
+    let mut reader = store.into_reader();
+    let mut lines = (&mut reader).lines();
+    assert_eq!(lines.next().unwrap().unwrap(), "first line");
+    assert_eq!(lines.next().unwrap().unwrap(), "second--- line");
+    assert_eq!(reader.position(), 26);
+
+    // Big reads skip the buffer, and stop at the end.
+    reader.seek(SeekFrom::Start(100)).unwrap();
+    let mut buf = vec![0u8; 300_000];
+    let mut got = 0;
+    loop {
+        match reader.read(&mut buf[got..]).unwrap() {
+            0 => break,
+            n => got += n,
         }
+    }
+    assert_eq!(buf[..got], big[..]);
 
-        for (&off, span) in self.base.spans.range((Included(offset), Excluded(offset + buf.len() as u64))) {
-            // Skip over any bytes not covered by span.
-            let bytes_until_span = off - offset;
-            if bytes_until_span != 0 {
-                offset += bytes_until_span;
-                buf = &mut buf[bytes_until_span as usize..];
-            }
+    let mut rest = Vec::new();
+    reader.seek(SeekFrom::End(-3)).unwrap();
+    reader.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, big[big.len() - 3..]);
+    assert_eq!(reader.seek(SeekFrom::Current(-200_100)).unwrap(), 0);
+    assert!(reader.seek(SeekFrom::Current(-1)).is_err());
+    assert_eq!(reader.fill_buf().unwrap()[..5], *b"first");
+    assert_eq!(reader.into_inner().size(), 200_100);
+}
+
+#[test]
+fn export_sparse() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    let mut out = Vec::new();
+    assert_eq!(store.export_sparse(&mut out).unwrap().regions, []);
+    assert!(out.is_empty());
+
+    store.write(10, b"hello").unwrap();
+    store.write(15, b" world").unwrap();
+    store.write(1_000_000, b"!").unwrap();
+    store.write(12, b"LL").unwrap();
+    let big = vec![7u8; 100_000];
+    store.write(2_000_000, &big).unwrap();
 
-            // Read in span.
-            let len = min(span.len, buf.len() as u64);
-            self.base.file.seek(SeekFrom::Start(span.file_data_offset))?;
-            self.base.file.read_exact(&mut buf[..len as usize])?;
-            offset += len;
-            buf = &mut buf[len as usize..];
+    let manifest = store.export_sparse(&mut out).unwrap();
+    assert_eq!(manifest.regions, [(10, 11), (1_000_000, 1), (2_000_000, 100_000)]);
+    assert_eq!(out[..12], *b"heLLo world!");
+    assert_eq!(out[12..], big[..]);
+
+    // And back into a fresh store.
+    let mut copy = open(dir.path().join("t"), WriteOpenMode::MustNotExist).unwrap();
+    copy.import_sparse(&out[..], &manifest).unwrap();
+    assert!(copy.contents_eq(&mut store).unwrap());
+    let mut out2 = Vec::new();
+    assert_eq!(copy.export_sparse(&mut out2).unwrap(), manifest);
+    assert_eq!(out2, out);
+
+    // Not enough data.
+    let mut short = open(dir.path().join("u"), WriteOpenMode::MustNotExist).unwrap();
+    assert!(matches!(short.import_sparse(&out[..100], &manifest),
+                     Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof));
+}
+
+#[test]
+fn random_record_lengths() {
+    use crate::storage::CountingStorage;
+
+    // xorshift64, so this is repeatable without a rand dependency.
+    let mut seed = 0x9E37_79B9_7F4A_7C15u64;
+    let mut rand = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    let mut storage = CountingStorage::new();
+    let mut file_size = 0;
+    record::write_record(&mut storage, RecordFormat::CURRENT, 0, &[1; 1000], &mut file_size).unwrap();
+    let whole = storage.bytes_mut().clone();
+
+    for i in 0..10_000 {
+        *storage.bytes_mut() = whole.clone();
+        let bytes = storage.bytes_mut();
+        // Alternate short lengths, and long ones of any magnitude.
+        let length = if i % 2 == 0 {
+            let length = rand() & 0xFF_FFFF;
+            bytes[12..15].copy_from_slice(&length.to_le_bytes()[..3]);
+            length
+        } else {
+            let length = rand() >> (rand() % 64);
+            bytes[3] += 1;
+            bytes[12..20].copy_from_slice(&length.to_le_bytes());
+            length
+        };
+        let hdr_len = if i % 2 == 0 { 15 } else { 20 };
+
+        storage.seek(SeekFrom::Start(0)).unwrap();
+        let mut end = 0;
+        match record::read_next_record(&mut storage, RecordFormat::CURRENT, &mut end, true).unwrap() {
+            Ok(rec) => assert_eq!(rec.hdr.length, 1000),
+            Err(why) => {
+                assert_eq!((end, storage.stream_position().unwrap()), (0, 0));
+                if length > 64 * 1024 && length.saturating_add(8) > whole.len() as u64 - hdr_len {
+                    assert_eq!(why, LogEnd::TooLong, "length {}", length);
+                }
+            }
         }
-        Ok(())
     }
 }
 
-fn compact(base: &mut StoreBase) -> Result<StoreBase, Error> {
-    let path = base.path.clone();
-    let tmp = path.with_extension("compact");
+#[test]
+fn offset_overflow() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
 
-    // Fresh file: if we crashed before, overwrite.
-    let mut oo = std::fs::OpenOptions::new();
-    oo.write(true);
-    oo.create(true);
-    oo.truncate(true);
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(u64::MAX - 2, b"ab").unwrap();
+    assert!(matches!(store.write(u64::MAX - 1, b"ab"), Err(Error::OutOfRange)));
+    store.close().unwrap();
+
+    // A (checksummed) record which runs off the end ends replay.
+    let mut f = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+    let mut len = f.metadata().unwrap().len();
+    record::write_record(&mut f, RecordFormat::CURRENT, u64::MAX - 1, b"hello", &mut len).unwrap();
+    let mut store = open_readonly(&path).unwrap();
+    assert_eq!(store.size(), u64::MAX);
+    assert_eq!(store.record_offsets().unwrap().len(), 1);
+}
+
+#[test]
+fn read_mapped() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(2, b"ab").unwrap();
+    store.write(4, b"\0\0").unwrap();
+    store.write(10, b"xyz").unwrap();
+
+    let mut buf = [1u8; 16];
+    let coverage = store.read_mapped(1, &mut buf).unwrap();
+    assert_eq!(buf, *b"\0ab\0\0\0\0\0\0xyz\0\0\0\0");
+    assert_eq!(coverage.ranges, [(2, 4), (10, 3)]);
+    assert!(!coverage.contains(1));
+    assert!(coverage.contains(2) && coverage.contains(5));
+    assert!(!coverage.contains(6) && !coverage.contains(9));
+    assert!(coverage.contains(12) && !coverage.contains(13));
+
+    let coverage = store.read_mapped(11, &mut buf[..1]).unwrap();
+    assert_eq!(coverage.ranges, [(11, 1)]);
+    assert_eq!(store.read_mapped(6, &mut buf[..4]).unwrap(), ReadCoverage::default());
+}
+
+#[test]
+fn write_if_changed() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    assert!(store.write_if_changed(0, b"hello").unwrap());
+    let len = store.file_len();
+    assert!(!store.write_if_changed(0, b"hello").unwrap());
+    assert!(!store.write_if_changed(1, b"ell").unwrap());
+    assert!(!store.write_if_changed(3, b"").unwrap());
+    assert_eq!(store.file_len(), len);
 
-    let mut file = oo.open(&tmp)?;
-    let mut file_len = header::write_header(&mut file)?;
+    assert!(store.write_if_changed(3, b"p!").unwrap());
+    // Holes read as zeros, but writing zeros still changes them.
+    assert!(store.write_if_changed(5, b"\0").unwrap());
+    assert!(!store.write_if_changed(0, b"help!\0").unwrap());
+    let mut buf = [0u8; 6];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"help!\0");
+}
+
+#[test]
+fn seal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
 
-    // Suck up all the data.
-    let mut data = vec![0u8; base.size() as usize];
-    for (off, span) in &base.spans {
-        base.file.seek(SeekFrom::Start(span.file_data_offset))?;
-        base.file.read_exact(&mut data[*off as usize..(*off + span.len) as usize])?;
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"final").unwrap();
+    let sp = store.savepoint();
+    store.seal().unwrap();
+    store.seal().unwrap();
+    assert_eq!(store.file_version(), "1.0.0");
+    assert!(matches!(store.write(5, b"!"), Err(Error::Sealed)));
+    assert!(matches!(store.checkpoint(1), Err(Error::Sealed)));
+    assert!(matches!(store.rollback(sp), Err(Error::Sealed)));
+    assert!(matches!(store.undo_last(1), Err(Error::Sealed)));
+    store.close().unwrap();
+
+    for mode in [WriteOpenMode::MustExist, WriteOpenMode::MayExist] {
+        assert!(matches!(open(&path, mode), Err(Error::Sealed)));
     }
+    let mut store = open_readonly(&path).unwrap();
+    let mut buf = [0u8; 5];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"final");
+    assert_eq!(store.generation(), 1);
 
-    // Write it out, make sure it hit disk.
-    record::write_record(&mut file, 0, &data, &mut file_len)?;
-    file.sync_data()?;
+    // An older (0.1) header has no room, so it gets rewritten.
+    let path = dir.path().join("legacy");
+    let legacy_hdr = b"Syncless\0\0\0\0";
+    let mut file_len = legacy_hdr.len() as u64;
+    {
+        let mut f = File::create(&path).unwrap();
+        std::io::Write::write_all(&mut f, legacy_hdr).unwrap();
+        record::write_record(&mut f, RecordFormat::Legacy, 0, b"abc", &mut file_len).unwrap();
+    }
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    store.seal().unwrap();
+    drop(store);
+    assert!(matches!(open(&path, WriteOpenMode::MustExist), Err(Error::Sealed)));
+    assert_eq!(open_readonly(&path).unwrap().size(), 3);
+}
 
-    // atomic replace
-    std::fs::rename(&tmp, &path)?;
+#[test]
+fn replay_stats() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("s");
 
-    // It's possible that the atomic replace is not actually atomic,
-    // but this is the best we can do.
-    let parent = path.parent().unwrap();
-    let dir = File::open(parent)?;
-    dir.sync_all()?;
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    assert_eq!(store.replay_stats(), ReplayStats { accepted: 0, stopped_reason: ReplayStop::EndOfFile });
+    for i in 0..3u8 {
+        store.write(i as u64, &[i]).unwrap();
+    }
+    store.close().unwrap();
+    let stats = open_readonly(&path).unwrap().replay_stats();
+    assert_eq!(stats, ReplayStats { accepted: 3, stopped_reason: ReplayStop::EndOfFile });
 
-    // reopen into a fresh StoreBase
-    open_writable_base(&path, WriteOpenMode::MustExist)
-}    
+    let limited = StoreOptions::new().max_replay_records(Some(2)).open_readonly(&path).unwrap();
+    assert_eq!(limited.replay_stats(), ReplayStats { accepted: 2, stopped_reason: ReplayStop::Limit });
+    let mut limited = limited;
+    limited.finish_replay().unwrap();
+    assert_eq!(limited.replay_stats(), stats);
 
-impl Store<Writable> {
-    /// Writes `buf.len()` bytes starting at `offset`.
-    ///
-    /// You can write anywhere, but if you create holes they will be
-    /// zero-filled.  Writes are ordered and become atomically visible
-    /// on success.  No durability guarantees: the effects of this
-    /// write may be lost on crash or power failure.  However, the
-    /// effects of this write will never be observed without also
-    /// observing the effects of all previous successful writes.
-    ///
-    /// This will rewrite the file (using fsync and rename) if it gets
-    /// more than 100x bigger than the contents.
-    ///
-    /// # Errors
-    ///
-    /// Returns an error on underlying I/O problems (probably out of disk space).
-    pub fn write(&mut self, mut offset: u64, mut buf: &[u8]) -> Result<(), Error> {
-        // Validate anything we're going to overwrite.
-        self.validate_range(self.prev_offset(offset), offset + buf.len() as u64)?;
+    // A torn write: the start of a record.
+    let whole = std::fs::read(&path).unwrap();
+    let last = whole.len() - (crate::RECORD_OVERHEAD + 1);
+    let mut f = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+    f.write_all(&whole[last..last + 7]).unwrap();
+    let stats = open_readonly(&path).unwrap().replay_stats();
+    assert_eq!(stats, ReplayStats { accepted: 3, stopped_reason: ReplayStop::Truncated });
 
-        while !buf.is_empty() {
-            let chunk = &buf[..min(buf.len(), record::MAX_RECORD_SIZE)];
+    // Damage to the last record.
+    let mut damaged = whole.clone();
+    *damaged.last_mut().unwrap() ^= 1;
+    std::fs::write(&path, &damaged).unwrap();
+    let stats = open_readonly(&path).unwrap().replay_stats();
+    assert_eq!(stats, ReplayStats { accepted: 2, stopped_reason: ReplayStop::Corrupt });
 
-            let data_off = record::write_record(&mut self.base.file, offset, chunk, &mut self.base.file_size)?;
-            record::add_record(&mut self.base.spans, offset, chunk.len() as u64, data_off, false);
-            buf = &buf[chunk.len()..];
-            offset += chunk.len() as u64;
-        }
+    // Which a writable open keeps, through compaction.
+    let mut store = open_resync(&path, WriteOpenMode::MustExist).unwrap();
+    assert_eq!(store.replay_stats(), stats);
+    store.shrink_to_fit().unwrap();
+    assert_eq!(store.replay_stats(), stats);
+}
 
-        // Compact when we're over 100x larger than we should be (unless we're tiny anyway)
-        if self.base.file_size > 1_000_000 && self.base.file_size * 100 > self.size() {
-            self.validate_range(0, self.size())?;
-            self.base = compact(&mut self.base)?;
-        }
+#[test]
+fn max_unvalidated_spans() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = StoreOptions::new().max_unvalidated_spans(Some(2)).open(dir.path().join("s")).unwrap();
+    // Backwards, as a write checks the span before it anyway.
+    for i in (0..5u8).rev() {
+        store.write(i as u64 * 10, &[i; 5]).unwrap();
+    }
+    assert_eq!(store.metrics().records_validated, 3);
+    let validated: Vec<bool> = store.base.spans.values().map(|span| span.validated).collect();
+    assert_eq!(validated, [false, false, true, true, true]);
 
-        Ok(())
+    // Already checked by a read (which checks the span before too): no
+    // more work when their turn comes.
+    let mut buf = [0u8; 5];
+    store.read(10, &mut buf).unwrap();
+    assert_eq!(store.metrics().records_validated, 5);
+    store.write(100, b"x").unwrap();
+    store.write(90, b"y").unwrap();
+    assert_eq!(store.metrics().records_validated, 5);
+    store.write(80, b"z").unwrap();
+    assert_eq!(store.metrics().records_validated, 6);
+    assert!(!store.is_fully_validated());
+
+    // The default leaves them all.
+    let mut store = open(dir.path().join("t"), WriteOpenMode::MustNotExist).unwrap();
+    for i in (0..5u8).rev() {
+        store.write(i as u64 * 10, &[i; 5]).unwrap();
     }
+    assert_eq!(store.metrics().records_validated, 0);
+    assert!(store.base.unvalidated.is_empty());
+}
 
-    /// Convert this writable store into a readonly one.
-    pub fn into_readonly(mut self) -> Result<Store<ReadOnly>, Error> {
-        // Before we make it readonly, make sure all spans are validated!
-        self.validate_range(0, self.size())?;
+#[test]
+fn validate_small_and_large_records() {
+    use std::io::Write;
 
-        Ok(Store {
-            base: self.base,
-            writable: false,
-            _mode: PhantomData,
-        })
+    // Either side of the size read back on the stack (with 24 bytes of
+    // header and checksum).
+    for len in [100, 4096 - 24, 4096 - 23, 10_000] {
+        let mut f = tempfile::tempfile().unwrap();
+        let mut file_size = 0;
+        let data_off = record::write_record(&mut f, RecordFormat::CURRENT, 0, &vec![7u8; len], &mut file_size).unwrap();
+        assert!(record::validate(&mut f, RecordFormat::CURRENT, data_off, len).unwrap());
+
+        f.seek(SeekFrom::Start(data_off + len as u64 - 1)).unwrap();
+        f.write_all(b"X").unwrap();
+        assert!(!record::validate(&mut f, RecordFormat::CURRENT, data_off, len).unwrap());
     }
 }
 
-#[cfg(test)]
+#[test]
+fn add_record_appends() {
+    let mut spans = BTreeMap::new();
+
+    // Right at the end, and past it: just inserted.
+    record::add_record(&mut spans, 0, 10, 100, true, false);
+    record::add_record(&mut spans, 10, 10, 200, true, false);
+    record::add_record(&mut spans, 30, 5, 300, true, false);
+    // One byte short of the end still has to split and replace.
+    record::add_record(&mut spans, 34, 2, 400, true, false);
+
+    let got: Vec<(u64, u64, u64)> = spans.iter().map(|(&off, span)| (off, span.len, span.file_data_offset)).collect();
+    assert_eq!(got, [(0, 10, 100), (10, 10, 200), (30, 4, 300), (34, 2, 400)]);
+}
 
 #[test]
-fn empty_store_size_zero() {
+fn repair_tail() {
+    use std::io::Write;
+
     let dir = tempfile::tempdir().unwrap();
     let path = dir.path().join("s");
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"hello").unwrap();
+    store.write(5, b"world").unwrap();
+    store.close().unwrap();
+    let good = std::fs::read(&path).unwrap();
 
-    let store = open(&path, WriteOpenMode::MayExist).unwrap();
-    assert_eq!(store.size(), 0);
+    // A crash part way through a write: the start of a record.
+    let torn = &good[good.len() - (crate::RECORD_OVERHEAD + 5)..][..10];
+    std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(torn).unwrap();
+
+    // Without repair, it stays.
+    let store = open(&path, WriteOpenMode::MustExist).unwrap();
+    assert_eq!(store.replay_stats().stopped_reason, ReplayStop::Truncated);
+    store.close().unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), (good.len() + torn.len()) as u64);
+
+    let mut store = StoreOptions::new().write_mode(WriteOpenMode::MustExist).repair_tail(true).open(&path).unwrap();
+    assert_eq!(store.replay_stats().stopped_reason, ReplayStop::Truncated);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), good.len() as u64);
+    store.write(10, b"!").unwrap();
+    store.close().unwrap();
+
+    let mut store = open_readonly(&path).unwrap();
+    assert_eq!(store.replay_stats(), ReplayStats { accepted: 3, stopped_reason: ReplayStop::EndOfFile });
+    let mut buf = [0u8; 11];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"helloworld!");
 }
 
 #[test]
-fn write_then_read() {
+fn read_cancellable() {
     let dir = tempfile::tempdir().unwrap();
-    let path = dir.path().join("s");
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abc").unwrap();
+    store.write(5, b"def").unwrap();
 
-    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    let cancel = AtomicBool::new(false);
+    let mut buf = [0u8; 8];
+    store.read_cancellable(0, &mut buf, &cancel).unwrap();
+    assert_eq!(&buf, b"abc\0\0def");
 
-    store.write(0, b"hello").unwrap();
-    assert_eq!(store.size(), 5);
+    cancel.store(true, Ordering::Relaxed);
+    assert!(matches!(store.read_cancellable(0, &mut buf, &cancel), Err(Error::Cancelled)));
+    // Checked first, even when it would only read a hole.
+    assert!(matches!(store.read_cancellable(10, &mut buf, &cancel), Err(Error::Cancelled)));
+}
 
-    let mut buf = [0u8; 5];
+#[test]
+fn rollback_after_rollback() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"base").unwrap();
+    let sp1 = store.savepoint();
+    store.write(0, b"AAAA").unwrap();
+    let mut buf = [0u8; 4];
     store.read(0, &mut buf).unwrap();
+    let sp2 = store.savepoint();
 
-    assert_eq!(&buf, b"hello");
+    // That discards the record sp2 refers to, and a new one takes its place.
+    store.rollback(sp1).unwrap();
+    store.write(0, &[b'B'; 30]).unwrap();
+    assert!(matches!(store.rollback(sp2), Err(Error::SavepointLost)));
+
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"BBBB");
+    store.close().unwrap();
 }
 
 #[test]
-fn overwrite_middle() {
+fn rollback_after_undo() {
     let dir = tempfile::tempdir().unwrap();
-    let path = dir.path().join("s");
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"one").unwrap();
+    store.write(3, b"two").unwrap();
+    let sp = store.savepoint();
 
-    let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
+    // Rolling back would now truncate into this record.
+    store.undo_last(1).unwrap();
+    store.write(3, b"a longer one").unwrap();
+    assert!(matches!(store.rollback(sp), Err(Error::SavepointLost)));
 
-    store.write(0, b"abcdefgh").unwrap();
-    store.write(2, b"XYZ").unwrap();
+    let mut buf = [0u8; 15];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"onea longer one");
+    store.close().unwrap();
+}
+
+#[test]
+fn short_reads() {
+    use crate::storage::ShortReadStorage;
+
+    // Bigger than a checksum chunk, read back 1000 bytes at a time.
+    let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+    for format in [RecordFormat::CURRENT, RecordFormat::Sampled] {
+        let mut storage = ShortReadStorage::new(1000);
+        let mut file_size = 0;
+        let data_off = record::write_record(&mut storage, format, 0, &data, &mut file_size).unwrap();
+        assert!(record::validate(&mut storage, format, data_off, data.len()).unwrap());
+
+        let mut end = 0;
+        storage.seek(SeekFrom::Start(0)).unwrap();
+        let rec = record::read_next_record(&mut storage, format, &mut end, true).unwrap().ok().unwrap();
+        assert_eq!(rec.hdr.length, data.len() as u64);
+        assert_eq!(end, file_size);
+
+        // Damage near the end is still caught, chunk by chunk.
+        storage.seek(SeekFrom::Start(file_size - 9)).unwrap();
+        storage.write_all(b"!").unwrap();
+        assert!(!record::validate(&mut storage, format, data_off, data.len()).unwrap());
+        let mut end = 0;
+        storage.seek(SeekFrom::Start(0)).unwrap();
+        assert!(matches!(record::read_next_record(&mut storage, format, &mut end, true).unwrap(),
+                         Err(record::LogEnd::BadChecksum)));
+    }
+}
+
+#[test]
+fn read_past_largest_offset() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abc").unwrap();
 
     let mut buf = [0u8; 8];
-    store.read(0, &mut buf).unwrap();
+    assert!(matches!(store.read(u64::MAX - 4, &mut buf), Err(Error::OutOfRange)));
+    assert!(matches!(store.read_strict(u64::MAX - 4, &mut buf), Err(Error::OutOfRange)));
+    let mut uninit = [MaybeUninit::uninit(); 8];
+    assert!(matches!(store.read_uninit(u64::MAX - 4, &mut uninit), Err(Error::OutOfRange)));
+    store.read(u64::MAX - 8, &mut buf).unwrap();
+    assert_eq!(buf, [0; 8]);
 
-    assert_eq!(&buf, b"abXYZfgh");
+    let shared = store.into_readonly().unwrap().into_shared().unwrap();
+    assert!(matches!(shared.read(u64::MAX - 4, &mut buf), Err(Error::OutOfRange)));
 }
 
 #[test]
-fn holes_are_zero() {
+fn index_memory_counts_overwrites() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut store = open(dir.path().join("s"), WriteOpenMode::MustNotExist).unwrap();
+
+    // Still one span, but every record is remembered.
+    store.write(0, b"a").unwrap();
+    let one = store.index_memory_bytes();
+    for _ in 0..100 {
+        store.write(0, b"b").unwrap();
+    }
+    assert_eq!(store.index_memory_bytes(), one + 100 * size_of::<u64>());
+    store.shrink_to_fit().unwrap();
+    assert_eq!(store.index_memory_bytes(), one);
+    store.close().unwrap();
+}
+
+#[test]
+fn len_and_is_empty() {
     let dir = tempfile::tempdir().unwrap();
     let path = dir.path().join("s");
 
     let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
-    store.write(10, b"hi").unwrap();
+    assert_eq!(store.len(), 0);
+    assert!(store.is_empty());
 
-    let mut buf = [0u8; 12];
-    store.read(0, &mut buf).unwrap();
+    // A hole still counts.
+    store.write(3, b"hello").unwrap();
+    assert_eq!(store.len(), store.size());
+    assert_eq!(store.len(), 8);
+    assert!(!store.is_empty());
+    store.close().unwrap();
 
-    assert_eq!(&buf[..10], &[0u8; 10]);
-    assert_eq!(&buf[10..12], b"hi");
+    let store = open_readonly(&path).unwrap();
+    assert_eq!(store.len(), 8);
+    assert!(!store.is_empty());
 }
 
 #[test]
-fn replay_reconstructs_state() {
+fn read_buffers_are_reused() {
     let dir = tempfile::tempdir().unwrap();
     let path = dir.path().join("s");
 
-    {
-        let mut store = open(&path, WriteOpenMode::MayExist).unwrap();
-        store.write(0, b"abc").unwrap();
-        store.write(5, b"xyz").unwrap();
-    }
-
-    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"abcd").unwrap();
+    store.write(10, b"efgh").unwrap();
+    store.write(2, b"X").unwrap();
+    let big = MAX_KEPT_READ_SCRATCH as u64;
+    store.write(100, &vec![b'y'; big as usize]).unwrap();
+    store.write(big + 110, b"z").unwrap();
+    store.close().unwrap();
 
-    let mut buf = [0u8; 8];
+    // Reading the two records at once needs the scratch buffer.
+    let mut store = StoreOptions::new().read_coalesce_gap(100).open_readonly(&path).unwrap();
+    let mut buf = [0u8; 14];
     store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"abXd\0\0\0\0\0\0efgh");
+    let runs = store.base.read_runs.as_ptr();
+    let scratch = store.base.read_scratch.as_ptr();
+    assert!(store.base.read_scratch.capacity() > 0);
+    for _ in 0..10 {
+        store.read(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"abXd\0\0\0\0\0\0efgh");
+        let mut uninit = [MaybeUninit::uninit(); 14];
+        assert_eq!(store.read_uninit(0, &mut uninit).unwrap(), b"abXd\0\0\0\0\0\0efgh");
+    }
+    assert_eq!(store.base.read_runs.as_ptr(), runs);
+    assert_eq!(store.base.read_scratch.as_ptr(), scratch);
 
-    assert_eq!(&buf, b"abc\0\0xyz");
+    // But we don't hang on to a big one.
+    let mut buf = vec![0u8; big as usize + 11];
+    store.read(100, &mut buf).unwrap();
+    assert_eq!(buf[big as usize + 10], b'z');
+    assert_eq!(store.base.read_scratch.capacity(), 0);
 }
@@ -0,0 +1,99 @@
+//! Independent logical streams in one syncless [`Store`].
+//!
+//! Each stream is named by a `u32` id and has its own offsets, starting
+//! at 0.  There's no separate record format for this: stream `id` lives
+//! at logical offset `id << 32` of an ordinary store, so each stream can
+//! hold up to 4GiB, and the gaps between them are holes (which take no
+//! space, even after compaction).  Writes to different streams are
+//! ordered and atomic with respect to each other, like any other writes.
+//!
+//! A stream exists once something has been written to it.
+use std::path::Path;
+use crate::{open, Error, Store, Writable, WriteOpenMode};
+
+/// How much each stream can hold (4GiB).  The last one (`u32::MAX`)
+/// holds a byte less, since the store's size has to fit in a `u64`.
+pub const STREAM_SIZE: u64 = 1 << 32;
+
+/// A store holding many independently-addressed streams of bytes.
+pub struct StreamStore {
+    store: Store<Writable>,
+}
+
+/// Logical offset of `[offset, offset + len)` in `stream`, if it fits.
+fn store_offset(stream: u32, offset: u64, len: usize) -> Result<u64, Error> {
+    let start = (stream as u64) << 32;
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= STREAM_SIZE && start.checked_add(end).is_some() => Ok(start + offset),
+        _ => Err(Error::OutOfRange),
+    }
+}
+
+impl StreamStore {
+    /// Opens (or creates) a syncless store at `path` to hold streams.
+    ///
+    /// # Errors
+    ///
+    /// As for [`open`].
+    pub fn open<P: AsRef<Path>>(path: P, mode: WriteOpenMode) -> Result<StreamStore, Error> {
+        Ok(StreamStore::from_store(open(path, mode)?))
+    }
+
+    /// Uses an already-open store to hold streams.
+    pub fn from_store(store: Store<Writable>) -> StreamStore {
+        StreamStore { store }
+    }
+
+    /// Writes `buf` at `offset` within `stream`.
+    ///
+    /// As for [`Store::write`], this is atomic and ordered (with writes
+    /// to every stream), but not durable.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::OutOfRange`] if the write would go past
+    /// [`STREAM_SIZE`], and an error on underlying I/O problems (probably
+    /// out of disk space).
+    pub fn write(&mut self, stream: u32, offset: u64, buf: &[u8]) -> Result<(), Error> {
+        let offset = store_offset(stream, offset, buf.len())?;
+        self.store.write(offset, buf)
+    }
+
+    /// Reads `buf.len()` bytes from `offset` within `stream`.
+    ///
+    /// Anything never written reads as a hole does (see
+    /// [`Store::hole_fill`]).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::OutOfRange`] if the read would go past
+    /// [`STREAM_SIZE`], and an error on underlying I/O error.
+    pub fn read(&mut self, stream: u32, offset: u64, buf: &mut [u8]) -> Result<(), Error> {
+        let offset = store_offset(stream, offset, buf.len())?;
+        self.store.read(offset, buf)
+    }
+
+    /// Returns the size of `stream`: the end of the last data written to
+    /// it, or 0 if there's none.
+    pub fn stream_size(&self, stream: u32) -> u64 {
+        let start = (stream as u64) << 32;
+        self.store.base.last_end_in(start, start + (STREAM_SIZE - 1)).map_or(0, |end| end - start)
+    }
+
+    /// Iterates over the ids of streams which have been written to, in
+    /// increasing order.
+    pub fn streams(&self) -> impl Iterator<Item = u32> + '_ {
+        let mut next = Some(0);
+        std::iter::from_fn(move || {
+            let (offset, _, _) = self.store.base.spans_in(next?, u64::MAX).next()?;
+            let stream = (offset >> 32) as u32;
+            next = stream.checked_add(1).map(|s| (s as u64) << 32);
+            Some(stream)
+        })
+    }
+
+    /// Returns the underlying store.
+    pub fn into_store(self) -> Store<Writable> {
+        self.store
+    }
+}
@@ -0,0 +1,228 @@
+//! Platform-specific helpers.  Everything here is an optimization: where
+//! the OS doesn't support it, we quietly do nothing (or the slow thing).
+use std::fs::File;
+use std::mem::MaybeUninit;
+use std::path::{Path, PathBuf};
+use crate::storage::Tracked;
+
+/// Preallocate `len` bytes at `offset` without changing the file size.
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: fd is valid for the lifetime of file.
+    let ret = unsafe {
+        libc::fallocate(file.as_raw_fd(),
+                        libc::FALLOC_FL_KEEP_SIZE,
+                        offset as libc::off_t,
+                        len as libc::off_t)
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = std::io::Error::last_os_error();
+    match err.raw_os_error() {
+        // Filesystem doesn't do it: that's fine.
+        Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(()),
+        _ => Err(err),
+    }
+}
+
+/// Preallocate `len` bytes at `offset` without changing the file size.
+#[cfg(windows)]
+pub(crate) fn preallocate(file: &File, offset: u64, len: u64) -> std::io::Result<()> {
+    use std::os::windows::io::AsRawHandle;
+    use windows_sys::Win32::Storage::FileSystem::{
+        FileAllocationInfo, SetFileInformationByHandle, FILE_ALLOCATION_INFO,
+    };
+
+    let info = FILE_ALLOCATION_INFO { AllocationSize: (offset + len) as i64 };
+    // SAFETY: handle is valid for the lifetime of file, info outlives the call.
+    let ok = unsafe {
+        SetFileInformationByHandle(file.as_raw_handle(),
+                                   FileAllocationInfo,
+                                   &info as *const FILE_ALLOCATION_INFO as *const _,
+                                   std::mem::size_of::<FILE_ALLOCATION_INFO>() as u32)
+    };
+    if ok == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Preallocate `len` bytes at `offset` without changing the file size.
+#[cfg(not(any(target_os = "linux", windows)))]
+pub(crate) fn preallocate(_file: &File, _offset: u64, _len: u64) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// How we're about to use part of a file (see fadvise).
+#[derive(Clone, Copy)]
+pub(crate) enum Advice {
+    /// We'll read it soon.
+    WillNeed,
+    /// We won't read it again soon.
+    DontNeed,
+}
+
+/// Tell the OS how we'll use `len` bytes at `offset`.
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+pub(crate) fn fadvise(file: &File, offset: u64, len: u64, advice: Advice) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let advice = match advice {
+        Advice::WillNeed => libc::POSIX_FADV_WILLNEED,
+        Advice::DontNeed => libc::POSIX_FADV_DONTNEED,
+    };
+    let (Ok(offset), Ok(len)) = (libc::off_t::try_from(offset), libc::off_t::try_from(len)) else {
+        return Ok(());
+    };
+    // SAFETY: fd is valid for the lifetime of file.
+    match unsafe { libc::posix_fadvise(file.as_raw_fd(), offset, len, advice) } {
+        0 | libc::ENOSYS => Ok(()),
+        // This one returns the error, rather than setting errno.
+        err => Err(std::io::Error::from_raw_os_error(err)),
+    }
+}
+
+/// Tell the OS how we'll use `len` bytes at `offset`.
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+pub(crate) fn fadvise(_file: &File, _offset: u64, _len: u64, _advice: Advice) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// A read-write file with no name, which vanishes when closed.
+#[cfg(target_os = "linux")]
+pub(crate) fn anonymous_file() -> std::io::Result<File> {
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: the name is a valid C string.
+    let fd = unsafe { libc::memfd_create(c"syncless".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: fd is a fresh descriptor which nothing else owns.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Opens a new file named `base`, a dot and a random number, then
+/// `suffix`, with `oo` (which should `create_new`): if the name is
+/// taken, we try another.
+pub(crate) fn create_randomly_named(base: &Path, suffix: &str, oo: &std::fs::OpenOptions)
+                                    -> std::io::Result<(PathBuf, File)> {
+    use std::hash::{BuildHasher, RandomState};
+
+    let mut tries = 0;
+    loop {
+        // Each RandomState is seeded differently.
+        let mut name = base.as_os_str().to_os_string();
+        name.push(format!(".{:016x}{}", RandomState::new().hash_one(tries), suffix));
+        let path = PathBuf::from(name);
+        match oo.open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && tries < 10 => tries += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A read-write file with no name, which vanishes when closed.
+///
+/// Without memfd, we create a fresh file in the temporary directory and
+/// remove it straight away (Windows can't, so it deletes it on close).
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn anonymous_file() -> std::io::Result<File> {
+    let mut oo = std::fs::OpenOptions::new();
+    oo.read(true).write(true).create_new(true);
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        use windows_sys::Win32::Storage::FileSystem::FILE_FLAG_DELETE_ON_CLOSE;
+        oo.custom_flags(FILE_FLAG_DELETE_ON_CLOSE);
+    }
+
+    let (_path, file) = create_randomly_named(&std::env::temp_dir().join("syncless"), "", &oo)?;
+    #[cfg(not(windows))]
+    std::fs::remove_file(&_path)?;
+    Ok(file)
+}
+
+/// Fill `buf` from `offset` in the file, without initializing it first.
+#[cfg(unix)]
+pub(crate) fn read_exact_at_uninit(file: &mut Tracked<File>,
+                                   mut offset: u64,
+                                   mut buf: &mut [MaybeUninit<u8>]) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // pread doesn't move the cursor, so Tracked stays correct.
+    let fd = file.get_ref().as_raw_fd();
+    while !buf.is_empty() {
+        let off = libc::off_t::try_from(offset)
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+        // SAFETY: fd is valid for the lifetime of file, and pread writes
+        // at most buf.len() bytes into buf.
+        let ret = unsafe { libc::pread(fd, buf.as_mut_ptr().cast(), buf.len(), off) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        if ret == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        buf = &mut buf[ret as usize..];
+        offset += ret as u64;
+    }
+    Ok(())
+}
+
+/// Fill `buf` from `offset` in the file (here, by zeroing it first).
+#[cfg(not(unix))]
+pub(crate) fn read_exact_at_uninit(file: &mut Tracked<File>,
+                                   offset: u64,
+                                   buf: &mut [MaybeUninit<u8>]) -> std::io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    buf.fill(MaybeUninit::new(0));
+    // SAFETY: we just initialized every byte.
+    let buf = unsafe { &mut *(buf as *mut [MaybeUninit<u8>] as *mut [u8]) };
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+/// Fill `buf` from `offset` in the file, without moving the cursor (so
+/// this can be shared between threads).
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    file.read_exact_at(buf, offset)
+}
+
+/// Fill `buf` from `offset` in the file (this moves the cursor, but
+/// doesn't use it).
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut offset: u64, mut buf: &mut [u8]) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// No positioned reads here, so we can't share the file.
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn read_exact_at(_file: &File, _offset: u64, _buf: &mut [u8]) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,50 @@
+#![cfg(feature = "cli")]
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::process::{Command, Output};
+use tempfile::tempdir;
+
+use syncless::{open, WriteOpenMode};
+
+fn syncless(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_syncless")).args(args).output().unwrap()
+}
+
+#[test]
+fn cli() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+    let p = path.to_str().unwrap();
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"hello").unwrap();
+    store.write(10, b"world").unwrap();
+    store.close().unwrap();
+
+    let out = syncless(&["info", p]);
+    assert!(out.status.success());
+    let info = String::from_utf8(out.stdout).unwrap();
+    assert!(info.contains("data size: 15\n"), "{}", info);
+    assert!(info.contains("spans: 2\n"), "{}", info);
+
+    let out = syncless(&["dump", p]);
+    assert!(out.status.success());
+    assert_eq!(out.stdout, b"hello\0\0\0\0\0world");
+
+    let out = syncless(&["layout", p]);
+    assert!(out.status.success());
+    assert!(String::from_utf8(out.stdout).unwrap().contains("logical [10..15)"));
+
+    let out = syncless(&["verify", p]);
+    assert_eq!(out.status.code(), Some(0));
+
+    // Junk on the end.
+    let len = std::fs::metadata(&path).unwrap().len();
+    OpenOptions::new().append(true).open(&path).unwrap().write_all(b"junk").unwrap();
+    let out = syncless(&["verify", p]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), format!("bad record at file offset {}\n", len));
+
+    assert_eq!(syncless(&["frob", p]).status.code(), Some(2));
+    assert_eq!(syncless(&["info", dir.path().join("missing").to_str().unwrap()]).status.code(), Some(2));
+}
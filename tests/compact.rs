@@ -27,8 +27,8 @@ fn compact() {
     let mut compacted_contents = vec![0u8; store.size() as usize];
     store.read(0, &mut compacted_contents).unwrap();
 
-    for i in 0..off {
-        assert_eq!(compacted_contents[i], b'A');
+    for &b in &compacted_contents[..off] {
+        assert_eq!(b, b'A');
     }
     assert_eq!(&compacted_contents[off..], b"ABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789");
 
@@ -2,7 +2,7 @@ use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
 use tempfile::tempdir;
 
-use syncless::{open_readonly, open, WriteOpenMode};
+use syncless::{open_readonly, open_readonly_resync, open, open_resync, WriteOpenMode, HEADER_SIZE};
 
 const ALL_WRITES: usize = 3;
 
@@ -61,7 +61,7 @@ fn max_record(byte_corrupted: usize) -> usize {
             return i - 1;
         }
     }
-    return boundaries.len() - 1;
+    boundaries.len() - 1
 }
 
 fn is_valid_result(buf: &[u8], records: usize) -> bool {
@@ -71,7 +71,7 @@ fn is_valid_result(buf: &[u8], records: usize) -> bool {
         b"\0AC",
         b"\0DC",
     ];
-    return buf == CONTENTS[records];
+    buf == CONTENTS[records]
 }
 
 #[test]
@@ -84,12 +84,12 @@ fn single_bit_corruption() {
     let mut original = Vec::new();
     File::open(&path).unwrap().read_to_end(&mut original).unwrap();
 
-    // Skip header (12 bytes: magic + version)
-    for i in 12 * 8..original.len() * 8 {
+    // Skip header (magic, version, generation...)
+    for i in HEADER_SIZE * 8..original.len() * 8 {
         let mut corrupted = original.clone();
 
         // Flip a bit deterministically
-        corrupted[i / 8] ^= 1 << i%8;
+        corrupted[i / 8] ^= 1 << (i % 8);
 
         write_bytes(&path, &corrupted);
 
@@ -98,7 +98,7 @@ fn single_bit_corruption() {
             is_valid_result(&result, max_record(i / 8)),
             "bit flip at byte {} bit {} ({}->{}) produced invalid state: {:?}",
             i / 8, i % 8,
-            corrupted[i / 8] ^ (1 << i%8),
+            corrupted[i / 8] ^ (1 << (i % 8)),
             corrupted[i / 8],
             result
         );
@@ -115,34 +115,44 @@ fn zero_each_nonzero_byte() {
     let mut original = Vec::new();
     File::open(&path).unwrap().read_to_end(&mut original).unwrap();
 
-    // Exhaustive map of each non-zero byte is too large (34 bits)
-    // so we only do one of the checksum bytes, so it's only 13 bits.
+    // Exhaustive map of each non-zero byte is too large (46 bits)
+    // so we skip the sync markers and all but one of the checksum
+    // bytes, so it's only 13 bits.
 
     // Layout:
-    // header: 12
-    // record 1: offset(8) len(3) data(2) csum(8)
-    // record 2: offset(8) len(3) data(1) csum(8)
-    // record 3: offset(8) len(3) data(1) csum(8)
-    const HEADER_LEN: usize = 12;
+    // header: HEADER_SIZE
+    // record 1: sync(4) offset(8) len(3) data(2) csum(8)
+    // record 2: sync(4) offset(8) len(3) data(1) csum(8)
+    // record 3: sync(4) offset(8) len(3) data(1) csum(8)
+    const HEADER_LEN: usize = HEADER_SIZE;
+    const SYNC_LEN: usize = 4;
     const OFFSET_LEN: usize = 8;
     const LEN_LEN: usize = 3;
     const CSUM_LEN: usize = 8;
 
-    let csum_offsets = {
-        let r1 = HEADER_LEN + OFFSET_LEN + LEN_LEN + 2;
-        let r2 = r1 + CSUM_LEN + OFFSET_LEN + LEN_LEN + 1;
-        let r3 = r2 + CSUM_LEN + OFFSET_LEN + LEN_LEN + 1;
+    let record_starts = {
+        let r1 = HEADER_LEN;
+        let r2 = r1 + SYNC_LEN + OFFSET_LEN + LEN_LEN + 2 + CSUM_LEN;
+        let r3 = r2 + SYNC_LEN + OFFSET_LEN + LEN_LEN + 1 + CSUM_LEN;
         [r1, r2, r3]
     };
+    let csum_offsets = [
+        record_starts[0] + SYNC_LEN + OFFSET_LEN + LEN_LEN + 2,
+        record_starts[1] + SYNC_LEN + OFFSET_LEN + LEN_LEN + 1,
+        record_starts[2] + SYNC_LEN + OFFSET_LEN + LEN_LEN + 1,
+    ];
 
-    let is_checksum_byte = |i: usize| {
+    let is_skipped_byte = |i: usize| {
         csum_offsets
             .iter()
             .any(|&off| i > off && i < off + CSUM_LEN)
+            || record_starts
+            .iter()
+            .any(|&off| i >= off && i < off + SYNC_LEN)
     };
 
     let nonzero_bytes: Vec<usize> = (HEADER_LEN..original.len())
-        .filter(|&i| !is_checksum_byte(i))
+        .filter(|&i| !is_skipped_byte(i))
         .filter(|&i| original[i] != 0)
         .collect();
 
@@ -185,7 +195,7 @@ fn truncation_at_any_prefix_is_handled() {
     File::open(&path).unwrap().read_to_end(&mut original).unwrap();
 
     // Header must remain intact or open will fail.
-    for len in 12..=original.len() {
+    for len in HEADER_SIZE..=original.len() {
         write_bytes(&path, &original[..len]);
 
         let result = read_contents(&path);
@@ -206,7 +216,7 @@ fn truncation_after_corruption_is_handled() {
     write_base_file(&path, ALL_WRITES);
     let original = std::fs::read(&path).unwrap();
 
-    for len in 13..original.len() {
+    for len in HEADER_SIZE + 1..original.len() {
         let mut corrupted = original[..len].to_vec();
         if let Some(b) = corrupted.last_mut() {
             *b = 0;
@@ -218,3 +228,72 @@ fn truncation_after_corruption_is_handled() {
         assert!(is_valid_result(&result, max_record(len - 1)));
     }
 }
+
+/// With resync, damage to one record only loses that record.
+fn is_valid_resync_result(buf: &[u8], damaged_record: usize) -> bool {
+    const CONTENTS: &[&[u8]] = &[
+        b"\0DC",
+        b"\0DB",
+        b"\0AC",
+    ];
+    buf == CONTENTS[damaged_record]
+}
+
+#[test]
+fn resync_single_bit_corruption() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+
+    write_base_file(&path, ALL_WRITES);
+    let boundaries = measure_boundaries();
+
+    let mut original = Vec::new();
+    File::open(&path).unwrap().read_to_end(&mut original).unwrap();
+
+    for i in HEADER_SIZE * 8..original.len() * 8 {
+        let mut corrupted = original.clone();
+        corrupted[i / 8] ^= 1 << (i % 8);
+        write_bytes(&path, &corrupted);
+
+        let mut store = open_readonly_resync(&path).unwrap();
+        let mut result = vec![0u8; store.size() as usize];
+        store.read(0, &mut result).unwrap();
+
+        let damaged = max_record(i / 8);
+        assert!(
+            is_valid_resync_result(&result, damaged),
+            "bit flip at byte {} bit {} (record {}, boundaries {:?}) lost more than one record: {:?}",
+            i / 8, i % 8, damaged, boundaries, result
+        );
+    }
+}
+
+#[test]
+fn resync_skips_corrupt_record() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+
+    write_base_file(&path, ALL_WRITES);
+    let boundaries = measure_boundaries();
+
+    // Zero the data byte of the second record: header(4) offset(8) len(3).
+    let mut corrupted = std::fs::read(&path).unwrap();
+    corrupted[boundaries[1] + 4 + 8 + 3] = 0;
+    write_bytes(&path, &corrupted);
+
+    // Normal open stops at the damage.
+    assert_eq!(read_contents(&path), b"\0AB");
+
+    // Resync finds the third record.
+    let mut store = open_readonly_resync(&path).unwrap();
+    let mut buf = [0u8; 3];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"\0DB");
+    drop(store);
+
+    // Writable resync rewrites the file, so normal opens see it too.
+    let mut store = open_resync(&path, WriteOpenMode::MustExist).unwrap();
+    store.write(3, b"E").unwrap();
+    drop(store);
+    assert_eq!(read_contents(&path), b"\0DBE");
+}
@@ -0,0 +1,41 @@
+#![cfg(feature = "ffi")]
+use std::ffi::CString;
+use std::ptr;
+use tempfile::tempdir;
+
+use syncless::ffi::*;
+
+#[test]
+fn c_interface() {
+    let dir = tempdir().unwrap();
+    let path = CString::new(dir.path().join("store").to_str().unwrap()).unwrap();
+
+    unsafe {
+        let mut store = ptr::null_mut();
+        assert_eq!(syncless_open_readonly(path.as_ptr(), &mut store), SYNCLESS_ERR_IO);
+        assert!(store.is_null());
+        assert_eq!(syncless_open(path.as_ptr(), 7, &mut store), SYNCLESS_ERR_INVALID_ARGUMENT);
+        assert_eq!(syncless_open(ptr::null(), SYNCLESS_MAY_EXIST, &mut store),
+                   SYNCLESS_ERR_INVALID_ARGUMENT);
+
+        assert_eq!(syncless_open(path.as_ptr(), SYNCLESS_MUST_NOT_EXIST, &mut store), SYNCLESS_OK);
+        assert_eq!(syncless_write(store, 1, b"hello".as_ptr(), 5), SYNCLESS_OK);
+        assert_eq!(syncless_write(store, 0, ptr::null(), 0), SYNCLESS_OK);
+        assert_eq!(syncless_write(store, 0, ptr::null(), 1), SYNCLESS_ERR_INVALID_ARGUMENT);
+        assert_eq!(syncless_size(store), 6);
+        assert_eq!(syncless_close(store), SYNCLESS_OK);
+
+        let mut store = ptr::null_mut();
+        assert_eq!(syncless_open(path.as_ptr(), SYNCLESS_MUST_NOT_EXIST, &mut store), SYNCLESS_ERR_IO);
+        assert_eq!(syncless_open_readonly(path.as_ptr(), &mut store), SYNCLESS_OK);
+        let mut buf = [0xFFu8; 8];
+        assert_eq!(syncless_read(store, 0, buf.as_mut_ptr(), buf.len()), SYNCLESS_OK);
+        assert_eq!(&buf, b"\0hello\0\0");
+        assert_eq!(syncless_read(store, u64::MAX - 4, buf.as_mut_ptr(), buf.len()), SYNCLESS_ERR_OUT_OF_RANGE);
+        assert_eq!(syncless_write(store, 0, b"x".as_ptr(), 1), SYNCLESS_ERR_READONLY);
+        assert_eq!(syncless_close(store), SYNCLESS_OK);
+
+        assert_eq!(syncless_size(ptr::null()), 0);
+        assert_eq!(syncless_close(ptr::null_mut()), SYNCLESS_OK);
+    }
+}
@@ -0,0 +1,117 @@
+#![cfg(feature = "kv")]
+use tempfile::tempdir;
+use syncless::{open, WriteOpenMode};
+use syncless::kv::KvStore;
+
+#[test]
+fn put_get_delete_reopen() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+
+    let mut kv = KvStore::open(&path, WriteOpenMode::MustNotExist).unwrap();
+    kv.put("rust", b"https://rust-lang.org").unwrap();
+    kv.put("ccan", b"https://ccodearchive.net").unwrap();
+    kv.put("rust", b"https://www.rust-lang.org").unwrap();
+    kv.put("empty", b"").unwrap();
+    assert!(kv.delete("ccan").unwrap());
+    assert!(!kv.delete("ccan").unwrap());
+
+    assert_eq!(kv.get("rust").unwrap().unwrap(), b"https://www.rust-lang.org");
+    assert_eq!(kv.get("ccan").unwrap(), None);
+    kv.close().unwrap();
+
+    let mut kv = KvStore::open(&path, WriteOpenMode::MustExist).unwrap();
+    let mut keys: Vec<&str> = kv.keys().collect();
+    keys.sort();
+    assert_eq!(keys, ["empty", "rust"]);
+    assert_eq!(kv.get("rust").unwrap().unwrap(), b"https://www.rust-lang.org");
+    assert_eq!(kv.get("empty").unwrap().unwrap(), b"");
+    assert_eq!(kv.get("ccan").unwrap(), None);
+}
+
+#[test]
+fn torn_tail_entry_is_overwritten() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+
+    let mut kv = KvStore::open(&path, WriteOpenMode::MustNotExist).unwrap();
+    kv.put("a", b"1").unwrap();
+    let end = kv.into_store().size();
+
+    // Header of an entry whose key and value never made it.
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    store.write(end, &[2, 0, 0, 0, 100, 0, 0, 0, b'b']).unwrap();
+    drop(store);
+
+    let mut kv = KvStore::open(&path, WriteOpenMode::MustExist).unwrap();
+    assert_eq!(kv.keys().count(), 1);
+    kv.put("c", b"3").unwrap();
+    drop(kv);
+
+    let mut kv = KvStore::open(&path, WriteOpenMode::MustExist).unwrap();
+    assert_eq!(kv.get("a").unwrap().unwrap(), b"1");
+    assert_eq!(kv.get("c").unwrap().unwrap(), b"3");
+    assert_eq!(kv.get("b").unwrap(), None);
+}
+
+#[test]
+fn shorter_entry_after_torn_entry() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+
+    let mut kv = KvStore::open(&path, WriteOpenMode::MustNotExist).unwrap();
+    kv.put("a", b"1").unwrap();
+    let end = kv.into_store().size();
+
+    // The start of a big entry.  "c" = "3" only covers its first 10
+    // bytes, and the rest look like entries (the second not UTF-8).
+    let mut torn = vec![6, 0, 0, 0, 100, 0, 0, 0, b'b', b'b'];
+    torn.extend_from_slice(&[3, 0, 0, 0, 1, 0, 0, 0, b'z', b'z', b'!']);
+    torn.extend_from_slice(&[3, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff]);
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    store.write(end, &torn).unwrap();
+    drop(store);
+
+    let mut kv = KvStore::open(&path, WriteOpenMode::MustExist).unwrap();
+    kv.put("c", b"3").unwrap();
+    kv.close().unwrap();
+
+    for _ in 0..2 {
+        let mut kv = KvStore::open(&path, WriteOpenMode::MustExist).unwrap();
+        let mut keys: Vec<&str> = kv.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["a", "c"]);
+        assert_eq!(kv.get("c").unwrap().unwrap(), b"3");
+        kv.put("d", b"4").unwrap();
+        kv.delete("d").unwrap();
+        kv.close().unwrap();
+    }
+}
+
+#[test]
+fn zeroes_end_the_log() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+
+    let mut kv = KvStore::open(&path, WriteOpenMode::MustNotExist).unwrap();
+    kv.put("a", b"1").unwrap();
+    let end = kv.into_store().size();
+
+    // A hole, then something which never got a complete entry before it.
+    let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+    store.write(end + 100, &[0xff; 3]).unwrap();
+    drop(store);
+
+    let mut kv = KvStore::open(&path, WriteOpenMode::MustExist).unwrap();
+    assert_eq!(kv.keys().collect::<Vec<_>>(), ["a"]);
+    kv.put("", b"").unwrap();
+    kv.put("b", b"2").unwrap();
+    kv.close().unwrap();
+
+    let mut kv = KvStore::open(&path, WriteOpenMode::MustExist).unwrap();
+    let mut keys: Vec<&str> = kv.keys().collect();
+    keys.sort();
+    assert_eq!(keys, ["", "a", "b"]);
+    assert_eq!(kv.get("").unwrap().unwrap(), b"");
+    assert_eq!(kv.get("b").unwrap().unwrap(), b"2");
+}
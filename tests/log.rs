@@ -0,0 +1,47 @@
+#![cfg(feature = "log")]
+use std::sync::Mutex;
+use tempfile::tempdir;
+
+use syncless::{open, open_readonly, WriteOpenMode};
+
+static MESSAGES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct Capture;
+
+impl log::Log for Capture {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if record.target().starts_with("syncless") {
+            MESSAGES.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[test]
+fn replay_stop_is_logged() {
+    log::set_logger(&Capture).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"hello").unwrap();
+    store.close().unwrap();
+
+    // Chop off the end of the record.
+    let len = std::fs::metadata(&path).unwrap().len();
+    std::fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 1).unwrap();
+
+    let store = open_readonly(&path).unwrap();
+    assert_eq!(store.size(), 0);
+
+    let messages = MESSAGES.lock().unwrap();
+    assert!(messages.iter().any(|m| m.contains("replay stopped") && m.contains("Truncated")),
+            "{:?}", messages);
+}
@@ -0,0 +1,62 @@
+use proptest::prelude::*;
+use tempfile::tempdir;
+use syncless::{open, open_readonly, Store, WriteOpenMode};
+
+/// Offsets mostly land on each other's data, so spans get split, covered
+/// and trimmed; a few are far out, to leave holes.
+fn write_op() -> impl Strategy<Value = (u64, Vec<u8>)> {
+    let offset = prop_oneof![8 => 0..512u64, 1 => 0..100_000u64];
+    (offset, prop::collection::vec(any::<u8>(), 1..300))
+}
+
+fn apply(model: &mut Vec<u8>, offset: u64, data: &[u8]) {
+    let end = offset as usize + data.len();
+    if model.len() < end {
+        model.resize(end, 0);
+    }
+    model[offset as usize..end].copy_from_slice(data);
+}
+
+fn check<M>(store: &mut Store<M>, model: &[u8], reads: &[(u64, u64)]) -> Result<(), TestCaseError> {
+    prop_assert_eq!(store.size(), model.len() as u64);
+    for &(start, len) in reads {
+        // Reads may go past the end, which reads as zeros.
+        let start = start % (model.len() as u64 + 10);
+        let mut buf = vec![0xAA; len as usize];
+        store.read(start, &mut buf).unwrap();
+        let expected: Vec<u8> = (start..start + len)
+            .map(|i| model.get(i as usize).copied().unwrap_or(0))
+            .collect();
+        prop_assert_eq!(buf, expected, "read {} bytes at {}", len, start);
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn reads_match_model(writes in prop::collection::vec(write_op(), 1..40),
+                         reads in prop::collection::vec((any::<u64>(), 0..600u64), 1..20)) {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("store");
+        let mut model = Vec::new();
+
+        let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+        for (offset, data) in &writes {
+            store.write(*offset, data).unwrap();
+            apply(&mut model, *offset, data);
+        }
+        check(&mut store, &model, &reads)?;
+        store.close().unwrap();
+
+        // Replay must rebuild the same spans.
+        let mut store = open_readonly(&path).unwrap();
+        check(&mut store, &model, &reads)?;
+
+        // And so must compaction.
+        let mut store = open(&path, WriteOpenMode::MustExist).unwrap();
+        store.shrink_to_fit().unwrap();
+        check(&mut store, &model, &reads)?;
+    }
+}
@@ -0,0 +1,40 @@
+use std::sync::mpsc::channel;
+use std::thread;
+use tempfile::tempdir;
+
+use syncless::{open, WriteOpenMode};
+
+#[test]
+fn follower_matches_leader() {
+    let dir = tempdir().unwrap();
+    let mut leader = open(dir.path().join("leader"), WriteOpenMode::MustNotExist).unwrap();
+    let mut follower = open(dir.path().join("follower"), WriteOpenMode::MustNotExist).unwrap();
+
+    let (tx, rx) = channel();
+    leader.set_replication_sink(tx);
+
+    let applier = thread::spawn(move || {
+        for event in rx {
+            follower.apply_event(&event).unwrap();
+        }
+        follower
+    });
+
+    // Overlapping writes, so order matters.
+    let mut x: u64 = 1;
+    for i in 0..1000u64 {
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        let off = x % 4096;
+        leader.write(off, &i.to_le_bytes()).unwrap();
+    }
+    leader.append(b"the end").unwrap();
+
+    // Dropping the leader drops the sink, ending the follower's loop.
+    let expected = leader.content_hash().unwrap();
+    leader.close().unwrap();
+    let mut follower = applier.join().unwrap();
+    assert_eq!(follower.content_hash().unwrap(), expected);
+    follower.close().unwrap();
+}
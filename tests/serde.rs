@@ -0,0 +1,60 @@
+#![cfg(feature = "serde")]
+use tempfile::tempdir;
+
+use syncless::{open, Error, StoreOptions, StoreSnapshot, WriteOpenMode};
+
+#[test]
+fn snapshot_round_trip() {
+    let dir = tempdir().unwrap();
+
+    let mut store = StoreOptions::new()
+        .hole_fill(b'.')
+        .metadata(0x80, b"bookmarks")
+        .open(dir.path().join("a"))
+        .unwrap();
+    store.write(0, b"hello").unwrap();
+    store.write(5, b" world").unwrap();
+    store.write(20, b"!").unwrap();
+
+    let snap = store.snapshot_serde().unwrap();
+    assert_eq!(snap.hole_fill, b'.');
+    assert_eq!(snap.metadata, [(0x80, b"bookmarks".to_vec())]);
+    assert_eq!(snap.spans, [(0, 11, b"hello world".to_vec()), (20, 1, b"!".to_vec())]);
+
+    // Through JSON, over a store with other contents.
+    let json = serde_json::to_string(&snap).unwrap();
+    let snap: StoreSnapshot = serde_json::from_str(&json).unwrap();
+    let mut other = open(dir.path().join("b"), WriteOpenMode::MustNotExist).unwrap();
+    other.write(0, b"something much longer than that").unwrap();
+    other.restore_serde(&snap).unwrap();
+    other.close().unwrap();
+
+    let mut other = open(dir.path().join("b"), WriteOpenMode::MustExist).unwrap();
+    assert_eq!(other.size(), 21);
+    assert_eq!(other.metadata(0x80), Some(&b"bookmarks"[..]));
+    let mut buf = [0u8; 21];
+    other.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"hello world.........!");
+    assert_eq!(other.list_holes(0, 21), [(11, 9)]);
+    assert_eq!(other.snapshot_serde().unwrap(), snap);
+}
+
+#[test]
+fn bad_snapshot() {
+    let dir = tempdir().unwrap();
+    let mut store = open(dir.path().join("a"), WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"keep").unwrap();
+
+    let bad = [
+        StoreSnapshot { spans: vec![(0, 2, b"x".to_vec())], ..Default::default() },
+        StoreSnapshot { spans: vec![(0, 2, b"xx".to_vec()), (1, 1, b"y".to_vec())], ..Default::default() },
+        StoreSnapshot { metadata: vec![(1, vec![])], ..Default::default() },
+        StoreSnapshot { metadata: vec![(0x80, vec![0; 200])], ..Default::default() },
+    ];
+    for snap in &bad {
+        assert!(matches!(store.restore_serde(snap), Err(Error::OutOfRange)));
+    }
+    let mut buf = [0u8; 4];
+    store.read(0, &mut buf).unwrap();
+    assert_eq!(&buf, b"keep");
+}
@@ -0,0 +1,84 @@
+use std::thread;
+use tempfile::tempdir;
+
+use syncless::{open, open_readonly, Error, StoreOptions, WriteOpenMode};
+
+#[test]
+fn concurrent_readers() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+
+    // Block i holds bytes equal to i, with a hole in the middle.
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    for i in (0..64u8).filter(|&i| i != 32) {
+        store.write(i as u64 * 1000, &[i; 1000]).unwrap();
+    }
+    store.close().unwrap();
+
+    let shared = open_readonly(&path).unwrap().into_shared().unwrap();
+    assert_eq!(shared.size(), 64_000);
+
+    let readers: Vec<_> = (0..8u64).map(|t| {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1000];
+            for round in 0..200u64 {
+                let i = (t * 7 + round * 13) % 64;
+                shared.read(i * 1000, &mut buf).unwrap();
+                let expect = if i == 32 { 0 } else { i as u8 };
+                assert!(buf.iter().all(|&b| b == expect), "block {}", i);
+            }
+        })
+    }).collect();
+    for r in readers {
+        r.join().unwrap();
+    }
+
+    // Across a block boundary, and past the end.
+    let mut buf = [0xFFu8; 4];
+    shared.read(1998, &mut buf).unwrap();
+    assert_eq!(buf, [1, 1, 2, 2]);
+    shared.read(63_998, &mut buf).unwrap();
+    assert_eq!(buf, [63, 63, 0, 0]);
+}
+
+#[test]
+fn shares_everything() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    for i in 0..10u8 {
+        store.write(i as u64, &[i]).unwrap();
+    }
+    store.close().unwrap();
+
+    // Replay stopped early, but the shared store has it all.
+    let store = StoreOptions::new().max_replay_records(Some(2)).open_readonly(&path).unwrap();
+    assert!(!store.fully_loaded());
+    let shared = store.into_shared().unwrap();
+    assert_eq!(shared.size(), 10);
+    let mut buf = [0xFFu8; 10];
+    shared.read(0, &mut buf).unwrap();
+    assert_eq!(buf, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+}
+
+#[test]
+fn checks_skipped_checksums() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+
+    let mut store = open(&path, WriteOpenMode::MustNotExist).unwrap();
+    store.write(0, b"hello world").unwrap();
+    let file_off = store.physical_offset(7).unwrap();
+    store.close().unwrap();
+
+    let mut f = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    f.seek(SeekFrom::Start(file_off)).unwrap();
+    f.write_all(b"0").unwrap();
+    drop(f);
+
+    let store = StoreOptions::new().skip_checksum_on_open(true).open_readonly(&path).unwrap();
+    assert!(matches!(store.into_shared(), Err(Error::CorruptRecord { .. })));
+}
@@ -0,0 +1,42 @@
+#![cfg(feature = "streams")]
+use tempfile::tempdir;
+use syncless::{Error, WriteOpenMode};
+use syncless::streams::{StreamStore, STREAM_SIZE};
+
+#[test]
+fn independent_streams() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("store");
+
+    let mut ss = StreamStore::open(&path, WriteOpenMode::MustNotExist).unwrap();
+    assert_eq!(ss.streams().count(), 0);
+    ss.write(7, 0, b"seven").unwrap();
+    ss.write(1, 0, b"one").unwrap();
+    ss.write(1, 3, b"-uno").unwrap();
+    ss.write(u32::MAX, STREAM_SIZE - 6, b"last").unwrap();
+    assert_eq!(ss.stream_size(1), 7);
+    assert_eq!(ss.stream_size(2), 0);
+    assert_eq!(ss.stream_size(u32::MAX), STREAM_SIZE - 2);
+
+    assert!(matches!(ss.write(2, STREAM_SIZE - 1, b"xx"), Err(Error::OutOfRange)));
+    assert!(matches!(ss.write(u32::MAX, STREAM_SIZE - 1, b"x"), Err(Error::OutOfRange)));
+    let mut buf = [0u8; 2];
+    assert!(matches!(ss.read(2, u64::MAX, &mut buf), Err(Error::OutOfRange)));
+    drop(ss);
+
+    // Compaction keeps the holes between streams.
+    let mut store = syncless::open(&path, WriteOpenMode::MustExist).unwrap();
+    store.shrink_to_fit().unwrap();
+    assert!(std::fs::metadata(&path).unwrap().len() < 4096);
+
+    let mut ss = StreamStore::from_store(store);
+    assert_eq!(ss.streams().collect::<Vec<_>>(), [1, 7, u32::MAX]);
+    let mut buf = [0u8; 7];
+    ss.read(1, 0, &mut buf).unwrap();
+    assert_eq!(&buf, b"one-uno");
+    ss.read(7, 0, &mut buf[..5]).unwrap();
+    assert_eq!(&buf[..5], b"seven");
+    ss.read(u32::MAX, STREAM_SIZE - 7, &mut buf[..5]).unwrap();
+    assert_eq!(&buf[..5], b"\0last");
+    ss.into_store().close().unwrap();
+}